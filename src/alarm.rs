@@ -0,0 +1,142 @@
+//! Alarms starting a playlist at a given time of the day.
+
+use std::cell::{Cell, RefCell};
+use std::cmp;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Duration as ChronoDuration, Local, NaiveTime, TimeZone};
+use corona::prelude::*;
+use failure::{bail, format_err, Error};
+use futures::unsync::oneshot;
+use log::{debug, error, info};
+use tokio::timer::Delay;
+
+use crate::config;
+use crate::library;
+use crate::player::{self, Cmd};
+
+/// How long to sleep at most before looking at the clock again.
+///
+/// The monotonic timers don't run while the computer is suspended, the wall clock is what
+/// matters for an alarm.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub(crate) struct Alarm {
+    pub(crate) time: NaiveTime,
+    /// Rings every day, not only once.
+    pub(crate) daily: bool,
+    /// What to play, a directory or a file listing the songs (like `--startup-playlist`).
+    pub(crate) playlist: PathBuf,
+}
+
+thread_local! {
+    static ALARMS: RefCell<BTreeMap<u64, Alarm>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_ID: Cell<u64> = const { Cell::new(1) };
+}
+
+fn get(id: u64) -> Option<Alarm> {
+    ALARMS.with(|alarms| alarms.borrow().get(&id).cloned())
+}
+
+/// The first time the alarm rings from now on.
+///
+/// Days where the time doesn't exist (due to the daylight saving time) are skipped.
+fn next_occurrence(time: NaiveTime) -> DateTime<Local> {
+    let now = Local::now();
+    let mut date = now.naive_local().date();
+    loop {
+        if let Some(at) = Local.from_local_datetime(&date.and_time(time)).earliest() {
+            if at > now {
+                return at;
+            }
+        }
+        date = date + ChronoDuration::days(1);
+    }
+}
+
+/// Replaces whatever plays with the alarm's playlist.
+fn ring(id: u64, alarm: &Alarm) {
+    info!("Alarm {} rings", id);
+    let result = catch! {
+        let sources = library::list(&alarm.playlist)?;
+        let songs = library::scan(&sources, config::get().playlist_order).songs;
+        if songs.is_empty() {
+            bail!("no valid songs");
+        }
+        let (sender, receiver) = oneshot::channel();
+        player::send(Cmd::PlayNow {
+            songs,
+            sources,
+            sender,
+        });
+        receiver
+            .coro_wait()
+            .unwrap_or_else(|_| Err(format_err!("player didn't answer")))?;
+    };
+    if let Err(e) = result {
+        error!("Alarm {} failed to play {}: {}", id, alarm.playlist.display(), e);
+    }
+}
+
+/// Waits for the alarm to ring, as long as it's not cancelled.
+fn run(id: u64) {
+    let mut at = match get(id) {
+        Some(alarm) => next_occurrence(alarm.time),
+        None => return,
+    };
+    loop {
+        // Negative once the time comes
+        match at.signed_duration_since(Local::now()).to_std() {
+            Ok(remaining) if remaining > Duration::from_secs(0) => {
+                let sleep = cmp::min(remaining, CHECK_INTERVAL);
+                let _ = Delay::new(Instant::now() + sleep).coro_wait();
+            }
+            _ => {
+                let alarm = match get(id) {
+                    Some(alarm) => alarm,
+                    None => return,
+                };
+                if !alarm.daily {
+                    ALARMS.with(|alarms| alarms.borrow_mut().remove(&id));
+                }
+                ring(id, &alarm);
+                if !alarm.daily {
+                    return;
+                }
+                at = next_occurrence(alarm.time);
+            }
+        }
+        if get(id).is_none() {
+            debug!("Alarm {} cancelled", id);
+            return;
+        }
+    }
+}
+
+/// Sets up a new alarm and returns its id.
+pub(crate) fn add(alarm: Alarm) -> u64 {
+    let id = NEXT_ID.with(|next| next.replace(next.get() + 1));
+    info!("Alarm {} set to {} for {}", id, alarm.time, alarm.playlist.display());
+    ALARMS.with(|alarms| alarms.borrow_mut().insert(id, alarm));
+    corona::spawn(move || run(id));
+    id
+}
+
+/// Removes the alarm, returns if there was any.
+pub(crate) fn cancel(id: u64) -> bool {
+    ALARMS.with(|alarms| alarms.borrow_mut().remove(&id).is_some())
+}
+
+/// The alarms waiting to ring, sorted by their ids.
+pub(crate) fn list() -> Vec<(u64, Alarm)> {
+    ALARMS.with(|alarms| {
+        alarms
+            .borrow()
+            .iter()
+            .map(|(id, alarm)| (*id, alarm.clone()))
+            .collect()
+    })
+}
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{debug, error, warn};
+
+use crate::state;
+
+/// Only songs at least this long get their position remembered.
+pub(crate) const MIN_DURATION: Duration = Duration::from_secs(20 * 60);
+
+/// Remembered positions in long files (audiobooks and such).
+///
+/// Stored in a state file, one `<seconds> <path>` per line.
+pub(crate) struct Bookmarks {
+    file: PathBuf,
+    positions: HashMap<PathBuf, f64>,
+}
+
+impl Bookmarks {
+    pub(crate) fn load() -> Self {
+        let file = state::path("bookmarks");
+        let data = state::read(&file).unwrap_or_else(|e| {
+            error!("Failed to read bookmarks from {}: {}", file.display(), e);
+            Vec::new()
+        });
+        let mut positions = data
+            .split(|c| *c == b'\n')
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, |c| *c == b' ');
+                let pos = std::str::from_utf8(parts.next()?).ok()?.parse().ok()?;
+                let path = state::remap(OsStr::from_bytes(parts.next()?).into());
+                Some((path, pos))
+            }).collect::<HashMap<_, _>>();
+        // The files might have been deleted or moved since (without a --path-remap)
+        let total = positions.len();
+        positions.retain(|path, _| path.is_file());
+        if positions.len() < total {
+            warn!("Dropped {} bookmarks of files not found", total - positions.len());
+        }
+        debug!("Loaded {} bookmarks", positions.len());
+        Bookmarks { file, positions }
+    }
+
+    fn save(&self) {
+        let mut data = Vec::new();
+        for (path, pos) in &self.positions {
+            let path = path.as_os_str().as_bytes();
+            if path.contains(&b'\n') {
+                warn!("Can't store bookmark for {}", String::from_utf8_lossy(path));
+                continue;
+            }
+            data.extend_from_slice(format!("{} ", pos).as_bytes());
+            data.extend_from_slice(path);
+            data.push(b'\n');
+        }
+        if let Err(e) = state::write(&self.file, &data) {
+            error!("Failed to store bookmarks to {}: {}", self.file.display(), e);
+        }
+    }
+
+    pub(crate) fn get(&self, path: &Path) -> Option<f64> {
+        self.positions.get(path).cloned()
+    }
+
+    pub(crate) fn set(&mut self, path: PathBuf, pos: f64) {
+        self.positions.insert(path, pos);
+        self.save();
+    }
+
+    pub(crate) fn clear(&mut self, path: &Path) -> bool {
+        let removed = self.positions.remove(path).is_some();
+        if removed {
+            self.save();
+        }
+        removed
+    }
+}
@@ -0,0 +1,1016 @@
+//! The commands clients send over the control socket.
+//!
+//! Each command is a handler function registered in the [`COMMANDS`] table under its name.
+
+use std::ascii;
+use std::cmp;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{BufRead, BufReader, Error as IoError, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::str::{self, FromStr};
+use std::time::Duration;
+
+use chrono::NaiveTime;
+use corona::prelude::*;
+use failure::{bail, format_err, Error};
+use futures::unsync::oneshot::{self, Sender};
+use log::info;
+use nix::sys::signal::Signal;
+
+use crate::alarm::{self, Alarm};
+use crate::config::{self, PlaylistOrder};
+use crate::fdpass::Received;
+use crate::library::{self, song_path, Scan};
+use crate::metadata::TagFilter;
+use crate::player::{ChapterCmd, Cmd, FilterCmd, LoadMode, Mode, RemoveCmd, Repeat, VolumeCmd};
+use crate::response::{Format, Reply, Response, Writer};
+
+/// Optional (cargo) features compiled in, so clients can adapt to what's available.
+///
+/// Every optional feature gets a `#[cfg(feature = "...")]` entry here.
+const FEATURES: &[&str] = &[];
+
+/// The most songs a single plan may queue.
+const MAX_PLAN: usize = 1000;
+
+/// Everything a command handler gets to work with.
+pub(crate) struct Context<'a> {
+    /// The whole command line, without the line ending.
+    line: &'a [u8],
+    /// The words after the command name.
+    args: Vec<&'a [u8]>,
+    /// The lines following the command, for commands taking a block of them.
+    lines: &'a mut dyn Iterator<Item = Result<Vec<u8>, IoError>>,
+    /// Delivers commands to the player.
+    player: &'a dyn Fn(Cmd),
+    /// Format of the responses on this connection.
+    format: Format,
+    /// The original commands don't answer on this connection.
+    quiet: bool,
+    client: Client<'a>,
+}
+
+/// Where the commands come from.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Client<'a> {
+    /// Number of the connection, the same as in the logs.
+    pub(crate) num: usize,
+    /// How the client talks to us (`unix` for the socket, `tcp`, or `fifo` for the command pipe).
+    pub(crate) transport: &'static str,
+    /// File descriptors sent by the client (only possible on the socket).
+    pub(crate) received: Option<&'a Received>,
+}
+
+impl<'a> Context<'a> {
+    fn arg(&self, idx: usize) -> Option<&'a [u8]> {
+        self.args.get(idx).cloned()
+    }
+
+    /// Sends a command to the player without waiting for it.
+    fn fire(&self, cmd: Cmd) -> Reply {
+        (self.player)(cmd);
+        Ok(Response::new())
+    }
+
+    /// Sends a command to the player and waits for its answer.
+    fn query<F>(&self, cmd: F) -> Reply
+    where
+        F: FnOnce(Sender<Reply>) -> Cmd,
+    {
+        let (sender, receiver) = oneshot::channel();
+        (self.player)(cmd(sender));
+        receiver
+            .coro_wait()
+            .unwrap_or_else(|_| Err(format_err!("player didn't answer")))
+    }
+}
+
+/// What happens after a command.
+pub(crate) enum Outcome {
+    /// Answer and go on with the next command.
+    Reply(Reply),
+    /// Answer even on a `quiet` connection (the query form has nothing to be quiet about).
+    Answer(Reply),
+    /// Close the connection.
+    Close,
+}
+
+type Handler = fn(&mut Context) -> Result<Outcome, Error>;
+
+pub(crate) struct Command {
+    name: &'static str,
+    handler: Handler,
+    /// Commands from before the protocol had responses, which don't answer after `quiet`.
+    quiet: bool,
+}
+
+const fn command(name: &'static str, handler: Handler) -> Command {
+    Command { name, handler, quiet: false }
+}
+
+const fn quiet(name: &'static str, handler: Handler) -> Command {
+    Command { name, handler, quiet: true }
+}
+
+pub(crate) const COMMANDS: &[Command] = &[
+    command("json", json),
+    command("quiet", go_quiet),
+    command("version", version),
+    command("capabilities", capabilities),
+    command("conn-info", conn_info),
+    command("ping", ping),
+    quiet("mode", mode),
+    quiet("load", load),
+    command("load-fd", load_fd),
+    command("play-now", play_now),
+    command("alarm", alarm),
+    command("reload", reload),
+    command("quit", quit),
+    command("terminate", terminate),
+    command("chapter", chapter),
+    command("chapters", chapters),
+    command("bookmark", bookmark),
+    command("speed", speed),
+    command("seek", seek),
+    command("seek-percent", seek_percent),
+    command("remaining", remaining),
+    command("time", time),
+    command("mute", mute),
+    command("duck", duck),
+    command("volume", volume),
+    command("unmute", unmute),
+    command("find", find),
+    command("filter", filter),
+    command("info", info),
+    command("artwork", artwork),
+    command("peek", peek),
+    command("current", current),
+    command("status", status),
+    command("total-duration", total_duration),
+    command("reindex", reindex),
+    command("forbidden", forbidden),
+    command("signal", signal),
+    command("position", position),
+    command("export", export),
+    command("save", save),
+    command("loadlist", load_list),
+    command("history", history),
+    command("history-clear", history_clear),
+    command("history-trim", history_trim),
+    command("queue-shuffle", queue_shuffle),
+    command("queue-detail", queue_detail),
+    command("list", list),
+    command("goto", goto),
+    command("remove", remove),
+    command("plan", plan),
+    quiet("play", play),
+    command("pause", pause),
+    command("resume", resume),
+    command("toggle", toggle),
+    quiet("next", next),
+    quiet("prev", prev),
+    command("skip", skip),
+    command("repeat", repeat),
+    quiet("stop", stop),
+];
+
+fn parse<T: FromStr>(word: &[u8]) -> Option<T> {
+    str::from_utf8(word).ok()?.parse().ok()
+}
+
+/// Strips the `\r` of CRLF line endings.
+///
+/// Only that, any other whitespace at the end may be part of a file name.
+fn strip_cr(data: &[u8]) -> &[u8] {
+    match data.split_last() {
+        Some((b'\r', rest)) => rest,
+        _ => data,
+    }
+}
+
+/// Renders a word from a command for diagnostics, escaping anything unprintable.
+fn escape(word: &[u8]) -> String {
+    word.iter()
+        .flat_map(|c| ascii::escape_default(*c))
+        .map(char::from)
+        .collect()
+}
+
+fn skip_spaces(data: &[u8]) -> &[u8] {
+    let start = data.iter().position(|c| *c != b' ').unwrap_or(data.len());
+    &data[start..]
+}
+
+/// The rest of the command line after the given number of words.
+///
+/// Used for arguments that may contain spaces, like paths.
+fn tail(cmd: &[u8], words: usize) -> &[u8] {
+    let mut rest = skip_spaces(cmd);
+    for _ in 0..words {
+        let end = rest.iter().position(|c| *c == b' ').unwrap_or(rest.len());
+        rest = skip_spaces(&rest[end..]);
+    }
+    rest
+}
+
+/// Parses and executes a single command line, answering to the client.
+///
+/// Returns false if the connection should be closed.
+pub(crate) fn handle(
+    line: &[u8],
+    lines: &mut dyn Iterator<Item = Result<Vec<u8>, IoError>>,
+    player: &dyn Fn(Cmd),
+    client: Client,
+    out: &mut Writer<impl Write>,
+) -> Result<bool, Error> {
+    let line = strip_cr(line);
+    let mut words = line.split(|c| *c == b' ').filter(|word| !word.is_empty());
+    let name = match words.next() {
+        Some(name) => name,
+        // Empty command, ignore
+        None => return Ok(true),
+    };
+
+    let command = match COMMANDS.iter().find(|c| c.name.as_bytes() == name) {
+        Some(command) => command,
+        None => {
+            out.send_quiet(&Err(format_err!("Unknown command {}", escape(name))))?;
+            return Ok(true);
+        }
+    };
+
+    let mut ctx = Context {
+        line,
+        args: words.collect(),
+        lines,
+        player,
+        format: out.format(),
+        quiet: out.quiet(),
+        client,
+    };
+    let outcome = (command.handler)(&mut ctx)?;
+    out.set_format(ctx.format);
+    out.set_quiet(ctx.quiet);
+    if config::get().audit {
+        let result = match &outcome {
+            Outcome::Reply(Ok(_)) | Outcome::Answer(Ok(_)) => "ok".to_owned(),
+            Outcome::Reply(Err(e)) | Outcome::Answer(Err(e)) => format!("error: {}", e),
+            Outcome::Close => "close".to_owned(),
+        };
+        info!(
+            target: "audit",
+            "#{} {}: {} -> {}",
+            client.num,
+            client.transport,
+            String::from_utf8_lossy(line),
+            result,
+        );
+    }
+    match outcome {
+        Outcome::Reply(reply) if command.quiet => out.send_quiet(&reply)?,
+        Outcome::Reply(reply) | Outcome::Answer(reply) => out.send(&reply)?,
+        Outcome::Close => return Ok(false),
+    }
+    Ok(true)
+}
+
+fn json(ctx: &mut Context) -> Result<Outcome, Error> {
+    ctx.format = Format::Json;
+    Ok(Outcome::Reply(Ok(Response::new())))
+}
+
+fn go_quiet(ctx: &mut Context) -> Result<Outcome, Error> {
+    ctx.quiet = true;
+    Ok(Outcome::Reply(Ok(Response::new())))
+}
+
+fn version(_: &mut Context) -> Result<Outcome, Error> {
+    let mut response = Response::new();
+    response.add("version", env!("CARGO_PKG_VERSION"));
+    for feature in FEATURES {
+        response.push("feature", *feature);
+    }
+    Ok(Outcome::Reply(Ok(response)))
+}
+
+/// What the clients may use, taken right from the command table.
+fn capabilities(_: &mut Context) -> Result<Outcome, Error> {
+    let mut response = Response::new();
+    for command in COMMANDS {
+        response.push("command", command.name);
+    }
+    for mode in Mode::ALL {
+        response.push("mode", mode.name());
+    }
+    Ok(Outcome::Reply(Ok(response)))
+}
+
+fn conn_info(ctx: &mut Context) -> Result<Outcome, Error> {
+    let response = Response::new()
+        .with("connection", ctx.client.num.to_string())
+        .with("transport", ctx.client.transport)
+        // Everyone may do everything so far
+        .with("access", "control");
+    Ok(Outcome::Reply(Ok(response)))
+}
+
+/// Checks the daemon is alive, without bothering the player.
+fn ping(ctx: &mut Context) -> Result<Outcome, Error> {
+    let mut response = Response::new();
+    if let Some(token) = ctx.arg(0) {
+        response.add("pong", token);
+    }
+    Ok(Outcome::Reply(Ok(response)))
+}
+
+fn mode(ctx: &mut Context) -> Result<Outcome, Error> {
+    let mode = match ctx.arg(0) {
+        Some(name) => {
+            Mode::from_name(name).ok_or_else(|| format_err!("Unknown mode {}", escape(name)))
+        }
+        None => return Ok(Outcome::Answer(ctx.query(Cmd::GetMode))),
+    };
+    Ok(Outcome::Reply(mode.and_then(|mode| ctx.fire(Cmd::Mode(mode)))))
+}
+
+/// What `load` and `load-fd` are asked to do by their flags.
+struct LoadFlags {
+    how: LoadMode,
+    order: PlaylistOrder,
+    clear_history: bool,
+}
+
+/// Reads the flags of `load` and `load-fd`.
+fn load_flags(args: &[&[u8]]) -> Result<LoadFlags, Error> {
+    let flags = args.iter().cloned().collect::<HashSet<_>>();
+    let append = flags.contains(b"append" as &[_]);
+    let replace = flags.contains(b"replace" as &[_]);
+    let how = match (append, replace) {
+        (true, true) => bail!("specify append or replace"),
+        (true, false) => LoadMode::Append,
+        (false, true) => LoadMode::Replace,
+        (false, false) if config::get().legacy_load => LoadMode::Replace,
+        (false, false) => LoadMode::Initial,
+    };
+    let order = match flags.iter().find(|flag| flag.starts_with(b"order=")) {
+        Some(flag) => {
+            let name = String::from_utf8_lossy(&flag[b"order=".len()..]);
+            name.parse::<PlaylistOrder>().map_err(|e| format_err!("{}", e))?
+        }
+        None => config::get().playlist_order,
+    };
+    // Appending stays in the same session, so it never clears
+    let clear_history = how != LoadMode::Append
+        && (config::get().clear_history_on_load || flags.contains(b"clear-history" as &[_]));
+    Ok(LoadFlags {
+        how,
+        order,
+        clear_history,
+    })
+}
+
+/// Reads the paths following a command, up to an empty line.
+fn read_block(ctx: &mut Context) -> Result<Vec<PathBuf>, Error> {
+    let mut sources = Vec::new();
+    for line in &mut ctx.lines {
+        let mut line = line?;
+        // Clients with CRLF line endings
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        if line.is_empty() {
+            // End of block
+            break;
+        }
+
+        sources.push(song_path(&line));
+    }
+    Ok(sources)
+}
+
+fn load(ctx: &mut Context) -> Result<Outcome, Error> {
+    let flags = load_flags(&ctx.args);
+    let sources = read_block(ctx)?;
+    // Only after reading the whole block, the paths must not be taken as commands
+    match flags {
+        Ok(flags) => Ok(Outcome::Reply(load_sources(ctx, sources, flags))),
+        Err(e) => Ok(Outcome::Reply(Err(e))),
+    }
+}
+
+/// Replaces the loaded songs and plays them from the start, all at once.
+fn play_now(ctx: &mut Context) -> Result<Outcome, Error> {
+    let sources = read_block(ctx)?;
+    let Scan { songs, skipped } = library::scan(&sources, config::get().playlist_order);
+    if songs.is_empty() {
+        return Ok(Outcome::Reply(Err(format_err!("no valid songs"))));
+    }
+    let reply = ctx.query(|sender| Cmd::PlayNow {
+        songs,
+        sources,
+        sender,
+    });
+    let reply = reply.map(|mut response| {
+        for skip in skipped {
+            response.push("skip", skip);
+        }
+        response
+    });
+    Ok(Outcome::Reply(reply))
+}
+
+/// Like `load`, but the paths are read from a file the client sent the descriptor of.
+fn load_fd(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = catch! {
+        let flags = load_flags(&ctx.args)?;
+        let received = ctx.client.received.ok_or_else(|| format_err!("not on a unix socket"))?;
+        let fd = received
+            .borrow_mut()
+            .pop_front()
+            .ok_or_else(|| format_err!("no file descriptor received"))?;
+        if !fd.is_file() {
+            bail!("not a file");
+        }
+        let mut sources = Vec::new();
+        for line in BufReader::new(fd).split(b'\n') {
+            let mut line = line?;
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            // Unlike in the block, an empty line doesn't end anything. The file may be an m3u.
+            if !line.is_empty() && !line.starts_with(b"#") {
+                sources.push(song_path(&line));
+            }
+        }
+        load_sources(ctx, sources, flags)?
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn load_sources(ctx: &mut Context, sources: Vec<PathBuf>, flags: LoadFlags) -> Reply {
+    let Scan { songs, skipped } = library::scan(&sources, flags.order);
+    // Don't throw the loaded songs away because of a typo. Only an empty block clears them.
+    if songs.is_empty() && !sources.is_empty() {
+        return if flags.how == LoadMode::Append {
+            Ok(Response::new())
+        } else {
+            Err(format_err!("no valid songs"))
+        };
+    }
+    let reply = ctx.query(|sender| Cmd::Load {
+        songs,
+        sources,
+        order: flags.order,
+        how: flags.how,
+        clear_history: flags.clear_history,
+        sender: Some(sender),
+    });
+    reply.map(|mut response| {
+        for skip in skipped {
+            response.push("skip", skip);
+        }
+        response
+    })
+}
+
+/// Describes an alarm for the `alarm` command.
+fn alarm_line(id: u64, alarm: &Alarm) -> Vec<u8> {
+    let when = if alarm.daily { "daily" } else { "once" };
+    let mut line = format!("{} {} {} ", id, alarm.time.format("%H:%M:%S"), when).into_bytes();
+    line.extend_from_slice(alarm.playlist.as_os_str().as_bytes());
+    line
+}
+
+fn alarm(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = catch! {
+        let mut response = Response::new();
+        match ctx.arg(0) {
+            None | Some(b"list") => {
+                for (id, alarm) in alarm::list() {
+                    response.push("alarm", alarm_line(id, &alarm));
+                }
+            }
+            Some(b"cancel") => {
+                let id = ctx.arg(1).ok_or_else(|| format_err!("Missing alarm id"))?;
+                if !parse(id).map(alarm::cancel).unwrap_or(false) {
+                    bail!("No alarm {}", escape(id));
+                }
+            }
+            Some(time) => {
+                let text = str::from_utf8(time).unwrap_or_default();
+                let time = NaiveTime::parse_from_str(text, "%H:%M:%S")
+                    .or_else(|_| NaiveTime::parse_from_str(text, "%H:%M"))
+                    .map_err(|_| format_err!("Invalid time {}", escape(time)))?;
+                let daily = ctx.arg(1) == Some(b"daily");
+                let arg = tail(ctx.line, if daily { 3 } else { 2 });
+                if arg.is_empty() {
+                    bail!("Missing playlist");
+                }
+                let playlist = song_path(arg);
+                if !playlist.exists() {
+                    bail!("Not found {}", escape(arg));
+                }
+                let alarm = Alarm {
+                    time,
+                    daily,
+                    playlist,
+                };
+                let line = alarm_line(alarm::add(alarm.clone()), &alarm);
+                response.add("alarm", line);
+            }
+        }
+        response
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn reload(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.query(Cmd::Reload)))
+}
+
+fn quit(_: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Close)
+}
+
+fn terminate(ctx: &mut Context) -> Result<Outcome, Error> {
+    let now = match ctx.arg(0) {
+        Some(b"now") => true,
+        Some(other) => {
+            let reply = Err(format_err!("Invalid terminate mode {}", escape(other)));
+            return Ok(Outcome::Reply(reply));
+        }
+        None => false,
+    };
+    // The player exits the whole daemon, this comes back only if it's already terminating
+    Ok(Outcome::Reply(ctx.query(|s| Cmd::Terminate(now, s))))
+}
+
+fn chapter(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(b"next") => ctx.query(|s| Cmd::Chapter(ChapterCmd::Next, s)),
+        Some(b"prev") => ctx.query(|s| Cmd::Chapter(ChapterCmd::Prev, s)),
+        Some(num) => match parse(num) {
+            Some(num) => ctx.query(|s| Cmd::Chapter(ChapterCmd::Jump(num), s)),
+            None => Err(format_err!("Invalid chapter {}", escape(num))),
+        },
+        None => Err(format_err!("Missing chapter")),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn chapters(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.query(Cmd::Chapters)))
+}
+
+fn bookmark(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(b"clear") => {
+            let path = song_path(tail(ctx.line, 2));
+            ctx.query(|s| Cmd::ClearBookmark(path, s))
+        }
+        Some(unknown) => Err(format_err!("Unknown bookmark command {}", escape(unknown))),
+        None => Err(format_err!("Missing bookmark command")),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn speed(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(speed) => match parse::<f64>(speed) {
+            Some(speed) if speed.is_finite() => ctx.query(|s| Cmd::Speed(Some(speed), s)),
+            _ => Err(format_err!("Invalid speed {}", escape(speed))),
+        },
+        None => ctx.query(|s| Cmd::Speed(None, s)),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn seek(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(secs) => match parse::<f64>(secs) {
+            Some(value) if value.is_finite() => {
+                let relative = secs[0] == b'+' || secs[0] == b'-';
+                ctx.query(|s| Cmd::Seek(value, relative, s))
+            }
+            _ => Err(format_err!("Invalid time {}", escape(secs))),
+        },
+        None => Err(format_err!("Missing time")),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn seek_percent(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(percent) => match parse::<f64>(percent) {
+            Some(percent) if percent.is_finite() => ctx.query(|s| Cmd::SeekPercent(percent, s)),
+            _ => Err(format_err!("Invalid percent {}", escape(percent))),
+        },
+        None => Err(format_err!("Missing percent")),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn remaining(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.query(Cmd::Remaining)))
+}
+
+fn time(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.query(Cmd::Time)))
+}
+
+fn mute(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        None => ctx.query(|s| Cmd::Mute(Some(true), s)),
+        Some(b"toggle") => ctx.query(|s| Cmd::Mute(None, s)),
+        Some(unknown) => Err(format_err!("Unknown mute command {}", escape(unknown))),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn unmute(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.query(|s| Cmd::Mute(Some(false), s))))
+}
+
+fn volume(ctx: &mut Context) -> Result<Outcome, Error> {
+    let cmd = match ctx.arg(0) {
+        None => Ok(VolumeCmd::Get),
+        Some(volume) => match parse::<f64>(volume) {
+            Some(value) if value.is_finite() => match volume[0] {
+                b'+' | b'-' => Ok(VolumeCmd::Change(value)),
+                _ => Ok(VolumeCmd::Set(value)),
+            },
+            _ => Err(format_err!("Invalid volume {}", escape(volume))),
+        },
+    };
+    Ok(Outcome::Reply(cmd.and_then(|cmd| ctx.query(|s| Cmd::Volume(cmd, s)))))
+}
+
+fn duck(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match (ctx.arg(0), ctx.arg(1)) {
+        (Some(percent), Some(secs)) => match (parse::<f64>(percent), parse::<f64>(secs)) {
+            (Some(percent), _) if !(percent >= 0.0 && percent.is_finite()) => {
+                Err(format_err!("Invalid volume {}", percent))
+            }
+            (None, _) => Err(format_err!("Invalid volume {}", escape(percent))),
+            (Some(percent), Some(secs)) if secs >= 0.0 && secs.is_finite() => {
+                let time = Duration::from_millis((secs * 1000.0) as u64);
+                ctx.query(|s| Cmd::Duck(percent, time, s))
+            }
+            (Some(_), _) => Err(format_err!("Invalid time {}", escape(secs))),
+        },
+        (Some(_), None) => Err(format_err!("Missing time")),
+        (None, _) => Err(format_err!("Missing volume")),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn filter(ctx: &mut Context) -> Result<Outcome, Error> {
+    let cmd = match ctx.arg(0) {
+        None | Some(b"list") => Ok(FilterCmd::List),
+        Some(b"clear") => Ok(FilterCmd::Clear),
+        Some(_) => {
+            let filter = String::from_utf8_lossy(tail(ctx.line, 1));
+            TagFilter::parse(&filter)
+                .map(FilterCmd::Add)
+                .ok_or_else(|| format_err!("Invalid filter {}", filter))
+        }
+    };
+    Ok(Outcome::Reply(cmd.and_then(|cmd| ctx.query(|s| Cmd::Filter(cmd, s)))))
+}
+
+fn find(ctx: &mut Context) -> Result<Outcome, Error> {
+    let needle = String::from_utf8_lossy(tail(ctx.line, 1)).to_lowercase();
+    let reply = if needle.is_empty() {
+        Err(format_err!("Missing search string"))
+    } else {
+        ctx.query(|s| Cmd::Find(needle, s))
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+/// Checks a song given by a client could be loaded (and so it can be asked about).
+fn loadable(arg: &[u8]) -> Result<PathBuf, Error> {
+    let path = song_path(arg);
+    if !path.is_file() {
+        bail!("Not a file {}", escape(arg));
+    }
+    if library::forbidden(&path) {
+        bail!("Forbidden file {}", escape(arg));
+    }
+    library::restrict(&path)?
+        .ok_or_else(|| format_err!("Outside of the allowed roots {}", escape(arg)))
+}
+
+fn info(ctx: &mut Context) -> Result<Outcome, Error> {
+    let arg = tail(ctx.line, 1);
+    let reply = if arg.is_empty() {
+        Err(format_err!("Missing path"))
+    } else {
+        loadable(arg).and_then(|path| ctx.query(|s| Cmd::Info(path, s)))
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn artwork(ctx: &mut Context) -> Result<Outcome, Error> {
+    let arg = tail(ctx.line, 1);
+    let reply = if arg.is_empty() {
+        ctx.query(|s| Cmd::Artwork(None, s))
+    } else {
+        loadable(arg).and_then(|path| ctx.query(|s| Cmd::Artwork(Some(path), s)))
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn peek(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.query(Cmd::Peek)))
+}
+
+fn current(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.query(Cmd::Current)))
+}
+
+fn status(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.query(Cmd::Status)))
+}
+
+fn forbidden(ctx: &mut Context) -> Result<Outcome, Error> {
+    let ext = ctx.arg(1).and_then(|ext| str::from_utf8(ext).ok());
+    let reply = match (ctx.arg(0), ext) {
+        (None, _) | (Some(b"list"), _) => Ok(()),
+        (Some(b"add"), Some(ext)) => {
+            library::set_forbidden(ext, true);
+            Ok(())
+        }
+        (Some(b"remove"), Some(ext)) => {
+            library::set_forbidden(ext, false);
+            Ok(())
+        }
+        (Some(b"add"), None) | (Some(b"remove"), None) => Err(format_err!("Missing extension")),
+        (Some(other), _) => Err(format_err!("Invalid forbidden command {}", escape(other))),
+    };
+    let reply = reply.map(|()| {
+        let mut response = Response::new();
+        for ext in library::forbidden_exts() {
+            response.push("ext", ext);
+        }
+        response
+    });
+    Ok(Outcome::Reply(reply))
+}
+
+fn total_duration(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.query(Cmd::TotalDuration)))
+}
+
+fn reindex(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(b"cancel") => ctx.query(|s| Cmd::Reindex(false, s)),
+        Some(other) => Err(format_err!("Invalid reindex argument {}", escape(other))),
+        None => ctx.query(|s| Cmd::Reindex(true, s)),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn signal(ctx: &mut Context) -> Result<Outcome, Error> {
+    // Only the ones that can't confuse the player too much
+    let reply = match ctx.arg(0) {
+        Some(b"STOP") => ctx.query(|s| Cmd::Signal(Signal::SIGSTOP, s)),
+        Some(b"CONT") => ctx.query(|s| Cmd::Signal(Signal::SIGCONT, s)),
+        Some(b"TERM") => ctx.query(|s| Cmd::Signal(Signal::SIGTERM, s)),
+        Some(unknown) => Err(format_err!("Signal {} not allowed", escape(unknown))),
+        None => Err(format_err!("Missing signal")),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn export(ctx: &mut Context) -> Result<Outcome, Error> {
+    let flags = ctx.args
+        .iter()
+        .take_while(|arg| [&b"queue"[..], b"relative"].contains(arg))
+        .count();
+    let (flags, path) = (&ctx.args[..flags], tail(ctx.line, 1 + flags));
+    let reply = if ctx.client.transport == "tcp" {
+        // Anybody can connect there, they must not overwrite any file they like
+        Err(format_err!("export is not allowed over TCP, use save"))
+    } else if path.is_empty() {
+        Err(format_err!("Missing path"))
+    } else {
+        ctx.query(|sender| Cmd::Export {
+            path: song_path(path),
+            queue: flags.contains(&&b"queue"[..]),
+            relative: flags.contains(&&b"relative"[..]),
+            sender,
+        })
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+/// The file of a playlist saved under the name, in the `--playlist-dir`.
+fn playlist_file(name: &[u8]) -> Result<PathBuf, Error> {
+    if name.is_empty() {
+        bail!("Missing playlist name");
+    }
+    // Must stay inside the directory and not be hidden
+    if name.contains(&b'/') || name.starts_with(b".") {
+        bail!("Invalid playlist name {}", escape(name));
+    }
+    let mut file = name.to_vec();
+    file.extend_from_slice(b".m3u8");
+    Ok(config::get().playlist_dir().join(OsStr::from_bytes(&file)))
+}
+
+fn save(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = catch! {
+        let path = playlist_file(tail(ctx.line, 1))?;
+        let dir = config::get().playlist_dir();
+        fs::create_dir_all(dir).map_err(|e| format_err!("Can't create {}: {}", dir.display(), e))?;
+        ctx.query(|sender| Cmd::Export {
+            path,
+            queue: false,
+            relative: true,
+            sender,
+        })?
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+/// Like `load` (with the same flags before the name), but from a playlist stored by `save`.
+fn load_list(ctx: &mut Context) -> Result<Outcome, Error> {
+    let is_flag = |arg: &[u8]| {
+        [&b"append"[..], b"replace", b"clear-history"].contains(&arg) || arg.starts_with(b"order=")
+    };
+    let flags = ctx.args.iter().take_while(|arg| is_flag(arg)).count();
+    let reply = catch! {
+        let load = load_flags(&ctx.args[..flags])?;
+        let name = tail(ctx.line, 1 + flags);
+        let path = playlist_file(name)?;
+        if !path.is_file() {
+            bail!("No playlist {}", escape(name));
+        }
+        let sources = library::list(&path)
+            .map_err(|e| format_err!("Can't read {}: {}", path.display(), e))?;
+        load_sources(ctx, sources, load)?
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn history(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(count) => match parse(count) {
+            Some(count) => ctx.query(|s| Cmd::History(Some(count), s)),
+            None => Err(format_err!("Invalid count {}", escape(count))),
+        },
+        None => ctx.query(|s| Cmd::History(None, s)),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn history_clear(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.query(|s| Cmd::TrimHistory(0, s))))
+}
+
+fn history_trim(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(keep) => match parse(keep) {
+            Some(keep) => ctx.query(|s| Cmd::TrimHistory(keep, s)),
+            None => Err(format_err!("Invalid count {}", escape(keep))),
+        },
+        None => Err(format_err!("Missing count")),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn queue_shuffle(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(seed) => match parse(seed) {
+            Some(seed) => ctx.query(|s| Cmd::ShuffleQueue(Some(seed), s)),
+            None => Err(format_err!("Invalid seed {}", escape(seed))),
+        },
+        None => ctx.query(|s| Cmd::ShuffleQueue(None, s)),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn queue_detail(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.query(Cmd::QueueDetail)))
+}
+
+fn list(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match (ctx.arg(0), ctx.arg(1)) {
+        (None, _) => ctx.query(|s| Cmd::List(None, s)),
+        (Some(b"upcoming"), Some(count)) => match parse(count) {
+            Some(count) => ctx.query(|s| Cmd::List(Some(count), s)),
+            None => Err(format_err!("Invalid count {}", escape(count))),
+        },
+        (Some(b"upcoming"), None) => Err(format_err!("Missing count")),
+        (Some(unknown), _) => Err(format_err!("Unknown list command {}", escape(unknown))),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn goto(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(index) => match parse(index) {
+            Some(index) => ctx.query(|s| Cmd::Goto(index, s)),
+            None => Err(format_err!("Invalid index {}", escape(index))),
+        },
+        None => Err(format_err!("Missing index")),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn remove(ctx: &mut Context) -> Result<Outcome, Error> {
+    let song = tail(ctx.line, 1);
+    let reply = if song.is_empty() {
+        Err(format_err!("Missing song"))
+    } else {
+        let cmd = match parse(song) {
+            Some(index) => RemoveCmd::Index(index),
+            None => RemoveCmd::Path(song_path(song)),
+        };
+        ctx.query(|s| Cmd::Remove(cmd, s))
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn plan(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(count) => match parse(count) {
+            Some(count) if count <= MAX_PLAN => ctx.query(|s| Cmd::Plan(count, s)),
+            Some(_) => Err(format_err!("At most {} songs can be planned", MAX_PLAN)),
+            None => Err(format_err!("Invalid count {}", escape(count))),
+        },
+        None => Err(format_err!("Missing count")),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn position(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(position) => match parse(position) {
+            Some(position) => ctx.query(|s| Cmd::Position(Some(position), s)),
+            None => Err(format_err!("Invalid position {}", escape(position))),
+        },
+        None => ctx.query(|s| Cmd::Position(None, s)),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn play(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.fire(Cmd::Play)))
+}
+
+fn pause(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.fire(Cmd::Pause)))
+}
+
+fn resume(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.fire(Cmd::Resume)))
+}
+
+/// The same as `play`, but answering even after `quiet`.
+fn toggle(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.fire(Cmd::Play)))
+}
+
+fn next(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.query(Cmd::Next)))
+}
+
+fn prev(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.fire(Cmd::Prev)))
+}
+
+fn skip(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(count) => match parse(count) {
+            Some(count) => ctx.fire(Cmd::Skip(count)),
+            None => Err(format_err!("Invalid count {}", escape(count))),
+        },
+        None => Err(format_err!("Missing count")),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn repeat(ctx: &mut Context) -> Result<Outcome, Error> {
+    let reply = match ctx.arg(0) {
+        Some(b"inf") => ctx.query(|s| Cmd::Repeat(Some(Repeat::Forever), s)),
+        Some(passes) => match parse(passes) {
+            Some(passes) => {
+                // Going through the songs 0 times makes no sense, so it's the same as once
+                let repeat = Repeat::Times(cmp::max(passes, 1));
+                ctx.query(|s| Cmd::Repeat(Some(repeat), s))
+            }
+            None => Err(format_err!("Invalid repeat count {}", escape(passes))),
+        },
+        None => ctx.query(|s| Cmd::Repeat(None, s)),
+    };
+    Ok(Outcome::Reply(reply))
+}
+
+fn stop(ctx: &mut Context) -> Result<Outcome, Error> {
+    Ok(Outcome::Reply(ctx.fire(Cmd::Stop)))
+}
@@ -0,0 +1,758 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::iter;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use failure::{bail, format_err, Error};
+use glob::Pattern;
+use once_cell::sync::OnceCell;
+use structopt::clap::ErrorKind;
+use structopt::StructOpt;
+use toml::Value;
+
+use crate::player::Mode;
+use crate::state;
+
+/// Anything smaller is unlikely to survive even a single log message.
+const MIN_STACK_SIZE: usize = 16_384;
+
+static CONFIG: OnceCell<Config> = OnceCell::INIT;
+
+/// What `next` does on the last song in the sequence mode.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum SequenceEnd {
+    /// Stop the playback (like when the song ends on its own).
+    Stop,
+    /// Go to the first song.
+    Wrap,
+    /// Keep playing the last song and refuse.
+    Hold,
+}
+
+impl FromStr for SequenceEnd {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "stop" => Ok(SequenceEnd::Stop),
+            "wrap" => Ok(SequenceEnd::Wrap),
+            "hold" => Ok(SequenceEnd::Hold),
+            _ => Err(format!("Unknown policy {}", s)),
+        }
+    }
+}
+
+/// What to do once there are no more songs to play.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum OnEmpty {
+    Stop,
+    /// Start from the beginning.
+    Loop,
+    /// Shuffle the songs and start from the beginning.
+    Reshuffle,
+    /// Run the shell command (which may load more songs) and try again.
+    Command(String),
+}
+
+impl FromStr for OnEmpty {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        const COMMAND_PREFIX: &str = "command:";
+        match s {
+            "stop" => Ok(OnEmpty::Stop),
+            "loop" => Ok(OnEmpty::Loop),
+            "reshuffle" => Ok(OnEmpty::Reshuffle),
+            _ if s.starts_with(COMMAND_PREFIX) => {
+                Ok(OnEmpty::Command(s[COMMAND_PREFIX.len()..].to_owned()))
+            }
+            _ => Err(format!("Unknown policy {}", s)),
+        }
+    }
+}
+
+/// Options of mpv the daemon uses to control it, these can't be overridden.
+const RESERVED_MPV_OPTIONS: &[&str] = &["input-ipc-client", "input-ipc-server", "input-file"];
+
+/// An additional argument of mpv, used in one of the modes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct ModeArg {
+    pub(crate) mode: Mode,
+    pub(crate) arg: String,
+}
+
+impl FromStr for ModeArg {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let colon = s.find(':').ok_or_else(|| format!("Expected <mode>:<argument>, got {}", s))?;
+        let (mode, arg) = (&s[..colon], &s[colon + 1..]);
+        let mode = Mode::from_name(mode.as_bytes())
+            .ok_or_else(|| format!("Unknown mode {}", mode))?;
+        if arg.is_empty() {
+            return Err(format!("Missing argument for mode {}", mode.name()));
+        }
+        let option = arg.trim_start_matches('-');
+        if RESERVED_MPV_OPTIONS.iter().any(|reserved| option.starts_with(reserved)) {
+            return Err(format!("{} would break the control of mpv", arg));
+        }
+        Ok(ModeArg {
+            mode,
+            arg: arg.to_owned(),
+        })
+    }
+}
+
+/// A moved directory, for the paths stored in the state files.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct PathRemap {
+    pub(crate) old: PathBuf,
+    pub(crate) new: PathBuf,
+}
+
+impl PathRemap {
+    /// The path in the new place, or None if it's not inside the old directory.
+    pub(crate) fn apply(&self, path: &Path) -> Option<PathBuf> {
+        path.strip_prefix(&self.old).ok().map(|rest| self.new.join(rest))
+    }
+}
+
+impl FromStr for PathRemap {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let eq = s.find('=').ok_or_else(|| format!("Expected <old>=<new>, got {}", s))?;
+        let (old, new) = (&s[..eq], &s[eq + 1..]);
+        if old.is_empty() || new.is_empty() {
+            return Err(format!("Expected <old>=<new>, got {}", s));
+        }
+        Ok(PathRemap {
+            old: PathBuf::from(old),
+            new: PathBuf::from(new),
+        })
+    }
+}
+
+/// How the songs found in a directory are ordered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum PlaylistOrder {
+    /// By the bytes of the path.
+    Name,
+    /// By the path, but numbers compared by their value (`track2` before `track10`).
+    Natural,
+    /// Oldest files first.
+    Mtime,
+    /// By the track number in the tags.
+    Track,
+}
+
+impl FromStr for PlaylistOrder {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "name" => Ok(PlaylistOrder::Name),
+            "natural" => Ok(PlaylistOrder::Natural),
+            "mtime" => Ok(PlaylistOrder::Mtime),
+            "track" => Ok(PlaylistOrder::Track),
+            _ => Err(format!("Unknown order {}", s)),
+        }
+    }
+}
+
+/// What makes songs belong together (to an album).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum GroupBy {
+    Directory,
+    Album,
+    Artist,
+}
+
+impl FromStr for GroupBy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "directory" => Ok(GroupBy::Directory),
+            "album" => Ok(GroupBy::Album),
+            "artist" => Ok(GroupBy::Artist),
+            _ => Err(format!("Unknown grouping {}", s)),
+        }
+    }
+}
+
+/// A daemon playing songs through mpv, controlled through a unix domain socket.
+#[derive(Debug, StructOpt)]
+#[structopt(name = "playlist_mgr")]
+pub(crate) struct Config {
+    /// Read the settings from this file.
+    ///
+    /// It has one `key = value` per line, the keys being the long option names without the
+    /// dashes (for example `min-play-time = 500`). Switches take `true` or `false`. Options given
+    /// on the command line take precedence over the file.
+    #[structopt(long = "config", parse(from_os_str))]
+    pub(crate) config: Option<PathBuf>,
+
+    /// Path of the control socket.
+    ///
+    /// Without it, `$XDG_RUNTIME_DIR/clue_play_socket` is used, or `~/.clue_play_socket` if the
+    /// runtime directory isn't set.
+    #[structopt(long = "socket", env = "CLUE_PLAY_SOCKET", parse(from_os_str))]
+    socket: Option<PathBuf>,
+
+    /// Hold a lock on this file while running, refusing to start if another instance holds it.
+    ///
+    /// Unlike the socket, this works across different `--socket` paths. The PID of the holder
+    /// is written into the file.
+    #[structopt(long = "single-instance-lock", parse(from_os_str))]
+    pub(crate) single_instance_lock: Option<PathBuf>,
+
+    /// Accept control connections over TCP on this address too (may be given multiple times).
+    ///
+    /// There's no authentication, anybody able to connect controls the player.
+    #[structopt(long = "listen-tcp")]
+    pub(crate) listen_tcp: Vec<SocketAddr>,
+
+    /// Accept MPD clients on this address (may be given multiple times).
+    ///
+    /// Only a subset of the MPD protocol is understood. Like `--listen-tcp`, there's no
+    /// authentication.
+    #[structopt(long = "listen-mpd")]
+    pub(crate) listen_mpd: Vec<SocketAddr>,
+
+    /// The directory relative paths of the MPD clients start in.
+    ///
+    /// Songs inside it are shown to them relative to it too.
+    #[structopt(long = "mpd-music-dir", parse(from_os_str))]
+    pub(crate) mpd_music_dir: Option<PathBuf>,
+
+    /// Try binding the socket (and the TCP addresses) this many more times before giving up.
+    ///
+    /// For starting before the directory of the socket or the network is ready.
+    #[structopt(long = "bind-retry", default_value = "0")]
+    pub(crate) bind_retry: u32,
+
+    /// Wait this many milliseconds before the first retry of binding, twice as long before each
+    /// next one.
+    #[structopt(long = "bind-retry-interval", default_value = "500")]
+    pub(crate) bind_retry_interval: u64,
+
+    /// The mpv binary used to play the songs.
+    ///
+    /// Anything accepting the same command line and control channel works, for example a fake
+    /// one for testing.
+    #[structopt(long = "mpv", default_value = "/usr/bin/mpv", parse(from_os_str))]
+    pub(crate) mpv: PathBuf,
+
+    /// A profile from the mpv configuration to play with.
+    #[structopt(long = "mpv-profile")]
+    pub(crate) mpv_profile: Option<String>,
+
+    /// An additional mpv argument used only in one mode, as `<mode>:<argument>`.
+    ///
+    /// Can be given multiple times, eg. `--mode-args random:--no-video`. These go after the
+    /// profile, so they can override it.
+    #[structopt(long = "mode-args")]
+    pub(crate) mode_args: Vec<ModeArg>,
+
+    /// Stack size of the coroutines, in bytes.
+    ///
+    /// The main loop and every control connection run in a coroutine with this stack. Bigger
+    /// stacks cost memory for each open connection, too small ones crash the whole daemon when
+    /// they overflow.
+    #[structopt(long = "stack-size", default_value = "65536")]
+    pub(crate) stack_size: usize,
+
+    /// Stack size of the player coroutine, in bytes.
+    ///
+    /// There's only one player, but it does the heavier work (walking directories, reading
+    /// tags), so it gets a bigger stack than the connections by default.
+    #[structopt(long = "player-stack-size", default_value = "262144")]
+    pub(crate) player_stack_size: usize,
+
+    /// A song ending sooner than this (in milliseconds) on its own is considered failed.
+    #[structopt(long = "min-play-time", default_value = "1000")]
+    pub(crate) min_play_time: u64,
+
+    /// How long to wait (in milliseconds) before starting another song after a failed one.
+    ///
+    /// This prevents burning through a whole directory of broken files at full speed.
+    #[structopt(long = "failure-backoff", default_value = "1000")]
+    pub(crate) failure_backoff: u64,
+
+    /// A song played for less than this (in milliseconds) is not put into the history.
+    ///
+    /// Songs skipped right away then don't get in the way of prev. Everything is kept by
+    /// default.
+    #[structopt(long = "min-history-time", default_value = "0")]
+    pub(crate) min_history_time: u64,
+
+    /// How many of the played songs are kept in the history (for `prev`).
+    #[structopt(long = "history-size", default_value = "100")]
+    pub(crate) history_size: usize,
+
+    /// Fade the song out over this many milliseconds on terminate (unless `terminate now`).
+    #[structopt(long = "terminate-fade", default_value = "0")]
+    pub(crate) terminate_fade: u64,
+
+    /// Fade each song in and out very shortly, to get rid of clicks at abrupt starts and ends.
+    ///
+    /// The end is faded only for songs with a known length.
+    #[structopt(long = "declick")]
+    pub(crate) declick: bool,
+
+    /// How long (in milliseconds) the `--declick` fades take.
+    #[structopt(long = "declick-time", default_value = "30")]
+    pub(crate) declick_time: u64,
+
+    /// Skip the silence at the start and the end of songs.
+    ///
+    /// The silence is found by ffmpeg in the background the first time a song plays (which
+    /// decodes the whole song) and remembered for the next times.
+    #[structopt(long = "trim-silence")]
+    pub(crate) trim_silence: bool,
+
+    /// Even out the loudness of songs, like ReplayGain.
+    ///
+    /// The loudness is measured with ffmpeg the first time a song plays (in the background) and
+    /// remembered for the next times.
+    #[structopt(long = "auto-gain")]
+    pub(crate) auto_gain: bool,
+
+    /// The ffmpeg binary used to analyze the songs.
+    #[structopt(long = "ffmpeg", default_value = "/usr/bin/ffmpeg", parse(from_os_str))]
+    pub(crate) ffmpeg: PathBuf,
+
+    /// Order of songs loaded from a directory: name, natural, mtime or track.
+    ///
+    /// Can be changed for a single load with `order=...`.
+    #[structopt(long = "playlist-order", default_value = "natural")]
+    pub(crate) playlist_order: PlaylistOrder,
+
+    /// How songs are grouped into albums: directory, album or artist.
+    ///
+    /// The album and artist come from the tags; songs without the tag are grouped by their
+    /// directory.
+    #[structopt(long = "group-by", default_value = "directory")]
+    pub(crate) group_by: GroupBy,
+
+    /// Don't pick songs shorter than this many seconds (like jingles).
+    ///
+    /// The songs stay loaded, they are just skipped. Songs queued explicitly (for example by
+    /// going back with prev) are played anyway. The length is taken from the tags.
+    #[structopt(long = "min-song-length")]
+    pub(crate) min_song_length: Option<u64>,
+
+    /// Don't pick songs longer than this many seconds (like hour-long mixes).
+    #[structopt(long = "max-song-length")]
+    pub(crate) max_song_length: Option<u64>,
+
+    /// Skip songs of unknown length too when --min-song-length or --max-song-length is used.
+    #[structopt(long = "skip-unknown-length")]
+    pub(crate) skip_unknown_length: bool,
+
+    /// What to do when the songs run out: stop, loop, reshuffle or command:<shell command>.
+    ///
+    /// The command may load more songs (through the socket); the playback continues once it
+    /// finishes. If there are still no songs, it stops.
+    #[structopt(long = "on-empty", default_value = "stop")]
+    pub(crate) on_empty: OnEmpty,
+
+    /// The mode to start in: random, sequence, circular or album-random.
+    ///
+    /// A restored session (see `--save-interval`) keeps its last mode instead.
+    #[structopt(long = "default-mode", default_value = "random")]
+    pub(crate) default_mode: Mode,
+
+    /// Start in the `--default-mode` even when restoring a session.
+    #[structopt(long = "force-default-mode")]
+    pub(crate) force_default_mode: bool,
+
+    /// What `next` does on the last song in the sequence mode: stop, wrap or hold.
+    ///
+    /// With hold, the song keeps playing and `next` is an error.
+    #[structopt(long = "sequence-end", default_value = "stop")]
+    pub(crate) sequence_end: SequenceEnd,
+
+    /// Songs to load on startup.
+    ///
+    /// Either a directory, or a file listing paths (songs or directories) one per line.
+    #[structopt(long = "startup-playlist", parse(from_os_str))]
+    pub(crate) startup_playlist: Option<PathBuf>,
+
+    /// Start playing right away (with --startup-playlist).
+    #[structopt(long = "autoplay")]
+    pub(crate) autoplay: bool,
+
+    /// Where `save` and `loadlist` keep the named playlists.
+    ///
+    /// Without it, `~/.clue_play_playlists` is used. It's created by the first `save`.
+    #[structopt(long = "playlist-dir", parse(from_os_str))]
+    playlist_dir: Option<PathBuf>,
+
+    /// Write the logs into this file instead of stderr.
+    ///
+    /// What gets logged is still controlled by the `RUST_LOG` environment variable. The songs
+    /// being played are printed to stdout either way.
+    #[structopt(long = "log-file", parse(from_os_str))]
+    pub(crate) log_file: Option<PathBuf>,
+
+    /// Once the log file would grow over this many bytes, it's rotated.
+    #[structopt(long = "log-max-size", default_value = "10485760")]
+    pub(crate) log_max_size: u64,
+
+    /// How many rotated log files to keep (as `<log-file>.1` and so on, the lower the newer).
+    #[structopt(long = "log-keep", default_value = "3")]
+    pub(crate) log_keep: usize,
+
+    /// A named pipe to read commands from (created if it doesn't exist).
+    ///
+    /// Meant for simple automation, like pausing when headphones are unplugged: anything
+    /// written into the pipe is executed as if sent to the socket, but nobody gets answers.
+    #[structopt(long = "pause-trigger-fifo", parse(from_os_str))]
+    pub(crate) pause_trigger_fifo: Option<PathBuf>,
+
+    /// Close connections silent for this many seconds (0 for never).
+    ///
+    /// Clients that connect and then forget about the connection would keep it open for ever
+    /// otherwise.
+    #[structopt(long = "conn-idle-timeout", default_value = "3600")]
+    pub(crate) conn_idle_timeout: u64,
+
+    /// Close connections sending a longer line than this many bytes.
+    ///
+    /// Otherwise, a client sending a lot of data without any newline could use up all the
+    /// memory.
+    #[structopt(long = "max-line-length", default_value = "65536")]
+    pub(crate) max_line_length: usize,
+
+    /// Maximum number of commands a client may send in a second.
+    ///
+    /// Clients sending more are slowed down (their commands wait), so a buggy one can't keep the
+    /// player spawning mpv all the time. Unlimited by default.
+    #[structopt(long = "max-commands-per-sec")]
+    pub(crate) max_commands_per_sec: Option<u32>,
+
+    /// A shell command to run whenever a song starts.
+    ///
+    /// The song is described in the `PM_PATH`, `PM_TITLE`, `PM_ARTIST` and `PM_ALBUM`
+    /// environment variables (the tags only if present).
+    #[structopt(long = "announce-command", parse(from_os_str))]
+    pub(crate) announce_command: Option<OsString>,
+
+    /// How long (in milliseconds) a song has to play before it's announced.
+    ///
+    /// Quickly skipped songs are not announced at all then, only the one that stays.
+    #[structopt(long = "announce-delay", default_value = "500")]
+    pub(crate) announce_delay: u64,
+
+    /// Only songs under this directory may be loaded (can be given multiple times).
+    ///
+    /// Without any, everything the daemon can read may be loaded.
+    #[structopt(long = "root", parse(from_os_str))]
+    pub(crate) roots: Vec<PathBuf>,
+
+    /// Don't load files with the path matching this glob (can be given multiple times).
+    ///
+    /// The `*` matches across directories too, so `*/samples/*` skips everything in any
+    /// `samples` directory.
+    #[structopt(long = "exclude-pattern")]
+    pub(crate) exclude_patterns: Vec<Pattern>,
+
+    /// Load only files with the path matching one of these globs (can be given multiple times).
+    #[structopt(long = "include-pattern")]
+    pub(crate) include_patterns: Vec<Pattern>,
+
+    /// Never load files with this extension, in addition to the built-in ones (can be given
+    /// multiple times).
+    ///
+    /// Changes the initial state of the `forbidden` command.
+    #[structopt(long = "forbidden-ext")]
+    pub(crate) forbidden_exts: Vec<String>,
+
+    /// Allow loading files with this extension, even if it's one of the built-in forbidden ones
+    /// (can be given multiple times).
+    #[structopt(long = "allowed-ext")]
+    pub(crate) allowed_exts: Vec<String>,
+
+    /// Forget the history when `load` replaces the loaded songs.
+    ///
+    /// Then `prev` doesn't go back to the songs of the previous playlist.
+    #[structopt(long = "clear-history-on-load")]
+    pub(crate) clear_history_on_load: bool,
+
+    /// Let a plain `load` replace already loaded songs.
+    ///
+    /// Otherwise, `load` needs `append` or `replace` once something is loaded, so the songs are
+    /// not thrown away by accident. This is for clients written before that.
+    #[structopt(long = "legacy-load")]
+    pub(crate) legacy_load: bool,
+
+    /// Log every executed command with its result under the `audit` target.
+    ///
+    /// Use `RUST_LOG=audit=info` to see them (possibly with other targets).
+    #[structopt(long = "audit")]
+    pub(crate) audit: bool,
+
+    /// Accept URLs (like `http://...`) of streams in load.
+    ///
+    /// They are passed to mpv as they are, which makes it access the network.
+    #[structopt(long = "allow-urls")]
+    pub(crate) allow_urls: bool,
+
+    /// Store a snapshot of the loaded songs, the mode and the position every this many seconds.
+    ///
+    /// The snapshot is restored on the next start, so even a crash loses at most the last
+    /// interval. Without this, it's stored only when terminating.
+    #[structopt(long = "save-interval")]
+    pub(crate) save_interval: Option<u64>,
+
+    /// Replace the beginning of the paths in the state files, `<old>=<new>`.
+    ///
+    /// For when the music moved to another directory. Can be given multiple times, the first
+    /// matching one is used.
+    #[structopt(long = "path-remap")]
+    pub(crate) path_remaps: Vec<PathRemap>,
+
+    /// Gzip the state files when writing them.
+    ///
+    /// Compressed files are recognized when reading no matter this setting, so it can be turned
+    /// on and off freely.
+    #[structopt(long = "compress-state")]
+    pub(crate) compress_state: bool,
+
+    /// Maximum number of songs returned by the find command.
+    #[structopt(long = "find-limit", default_value = "100")]
+    pub(crate) find_limit: usize,
+}
+
+impl Config {
+    /// Path of the control socket (the default filled in by then).
+    pub(crate) fn socket(&self) -> &Path {
+        self.socket.as_ref().expect("Socket not resolved")
+    }
+
+    /// Directory of the named playlists (the default filled in by then).
+    pub(crate) fn playlist_dir(&self) -> &Path {
+        self.playlist_dir.as_ref().expect("Playlist directory not resolved")
+    }
+
+    fn validate(&mut self) -> Result<(), Error> {
+        for &(name, size) in &[
+            ("stack-size", self.stack_size),
+            ("player-stack-size", self.player_stack_size),
+        ] {
+            if size < MIN_STACK_SIZE {
+                bail!("--{} of {} is too small, at least {} is needed", name, size, MIN_STACK_SIZE);
+            }
+        }
+        if self.mpv_profile.as_ref().is_some_and(String::is_empty) {
+            bail!("--mpv-profile can't be empty");
+        }
+        if self.save_interval == Some(0) {
+            bail!("--save-interval must be at least 1");
+        }
+        if self.max_commands_per_sec == Some(0) {
+            bail!("--max-commands-per-sec must be at least 1");
+        }
+        for ext in self.forbidden_exts.iter().chain(&self.allowed_exts) {
+            if ext.trim_start_matches('.').is_empty() {
+                bail!("Empty extension in --forbidden-ext or --allowed-ext");
+            }
+        }
+        if self.socket.as_ref().is_some_and(|socket| socket.as_os_str().is_empty()) {
+            bail!("--socket can't be empty");
+        }
+        if self.socket.is_none() {
+            self.socket = Some(default_socket());
+        }
+        if self.playlist_dir.is_none() {
+            self.playlist_dir = Some(state::path("playlists"));
+        }
+        // The loaded songs are compared in the canonical form, the roots must match
+        for root in &mut self.roots {
+            *root = root
+                .canonicalize()
+                .map_err(|e| format_err!("--root {}: {}", root.display(), e))?;
+        }
+        Ok(())
+    }
+}
+
+/// The socket without `--socket` (or `CLUE_PLAY_SOCKET`).
+///
+/// The runtime directory belongs to the user and is cleaned on logout, which suits a socket
+/// better than the home directory.
+fn default_socket() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => Path::new(&dir).join("clue_play_socket"),
+        _ => state::path("socket"),
+    }
+}
+
+/// Whether the option is present on the command line.
+fn on_command_line(cli: &[OsString], key: &str) -> bool {
+    let long = format!("--{}", key);
+    let with_value = format!("--{}=", key);
+    cli.iter()
+        .filter_map(|arg| arg.to_str())
+        .any(|arg| arg == long || arg.starts_with(&with_value))
+}
+
+/// Whether the arguments are known options (their values are not checked).
+fn accepted(args: &[&str]) -> bool {
+    let args = iter::once("playlist_mgr").chain(args.iter().cloned());
+    !matches!(
+        Config::clap().get_matches_from_safe(args),
+        Err(ref e) if e.kind == ErrorKind::UnknownArgument
+    )
+}
+
+/// A setting read from the config file.
+struct Entry {
+    /// Where in the file it is, for the messages.
+    location: String,
+    key: String,
+    /// An option that may repeat can have several values.
+    values: Vec<String>,
+}
+
+/// Reads the config file with one `key = value` per line.
+fn plain_entries(path: &Path, content: &str) -> Result<Vec<Entry>, Error> {
+    let mut entries = Vec::new();
+    for (num, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let location = format!("{}:{}", path.display(), num + 1);
+        let (key, value) = match line.find('=') {
+            Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+            None => bail!("{}: Expected key = value", location),
+        };
+        let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+            &value[1..value.len() - 1]
+        } else {
+            value
+        };
+        entries.push(Entry {
+            location,
+            key: key.to_owned(),
+            values: vec![value.to_owned()],
+        });
+    }
+    Ok(entries)
+}
+
+/// Reads a TOML config file.
+///
+/// The keys are the same as in the plain one, options that may repeat take arrays.
+fn toml_entries(path: &Path, content: &str) -> Result<Vec<Entry>, Error> {
+    let table: BTreeMap<String, Value> =
+        toml::from_str(content).map_err(|e| format_err!("{}: {}", path.display(), e))?;
+    let location = path.display().to_string();
+    let scalar = |key: &str, value: Value| match value {
+        Value::String(value) => Ok(value),
+        Value::Integer(value) => Ok(value.to_string()),
+        Value::Float(value) => Ok(value.to_string()),
+        Value::Boolean(value) => Ok(value.to_string()),
+        _ => Err(format_err!("{}: {} must be a string, a number or a boolean", location, key)),
+    };
+    table
+        .into_iter()
+        .map(|(key, value)| {
+            let values = match value {
+                Value::Array(values) => values
+                    .into_iter()
+                    .map(|value| scalar(&key, value))
+                    .collect::<Result<_, _>>()?,
+                value => vec![scalar(&key, value)?],
+            };
+            Ok(Entry {
+                location: location.clone(),
+                key,
+                values,
+            })
+        }).collect()
+}
+
+/// Turns the config file into command line arguments.
+///
+/// Files ending with `.toml` are TOML, the others have one `key = value` per line. Only the
+/// options not present on the command line are returned. Problems that don't prevent starting
+/// (like unknown keys) are added to the warnings.
+fn file_args(path: &Path, cli: &[OsString], warnings: &mut Vec<String>)
+    -> Result<Vec<OsString>, Error>
+{
+    let content = fs::read_to_string(path)
+        .map_err(|e| format_err!("Can't read config {}: {}", path.display(), e))?;
+    let entries = if path.extension() == Some(OsStr::new("toml")) {
+        toml_entries(path, &content)?
+    } else {
+        plain_entries(path, &content)?
+    };
+    let mut args = Vec::new();
+    for Entry { location, key, values } in entries {
+        if key == "config" {
+            warnings.push(format!("{}: Can't include other config", location));
+            continue;
+        }
+        if on_command_line(cli, &key) {
+            continue;
+        }
+        let long = format!("--{}", key);
+        for value in values {
+            // Let clap tell if it knows the option and if it takes a value
+            let switch = if accepted(&[&long, &value]) {
+                false
+            } else if accepted(&[&long]) {
+                true
+            } else {
+                warnings.push(format!("{}: Unknown key {}", location, key));
+                break;
+            };
+            match (switch, value.as_str()) {
+                (true, "true") => args.push(long.clone().into()),
+                (true, "false") => (),
+                (true, _) => bail!("{}: {} must be true or false", location, key),
+                (false, _) => {
+                    args.push(long.clone().into());
+                    args.push(value.into());
+                }
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// The config file read without `--config`, if there's any.
+///
+/// It's `playlist_mgr/config.toml` in `$XDG_CONFIG_HOME` (`~/.config` by default).
+fn default_config() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("playlist_mgr").join("config.toml")).filter(|path| path.is_file())
+}
+
+/// Parses the command line (and the config file) and makes the configuration globally available.
+///
+/// Returns warnings to be logged once logging is set up (it is configured here too).
+pub(crate) fn init() -> Result<Vec<String>, Error> {
+    let mut warnings = Vec::new();
+    let mut cli = env::args_os().collect::<Vec<_>>();
+    let mut config = Config::from_iter(&cli);
+    if let Some(path) = config.config.clone().or_else(default_config) {
+        let extra = file_args(&path, &cli, &mut warnings)?;
+        cli.extend(extra);
+        config = Config::from_iter(&cli);
+    }
+    config.validate()?;
+    if CONFIG.set(config).is_err() {
+        bail!("Configuration already initialized");
+    }
+    Ok(warnings)
+}
+
+pub(crate) fn get() -> &'static Config {
+    CONFIG.get().expect("Configuration not initialized")
+}
@@ -0,0 +1,79 @@
+//! Shell-like expansion of `~` and environment variables in paths.
+//!
+//! The expansion uses the environment of the daemon, not of the client sending the paths.
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+
+/// Home directory of a user, from `/etc/passwd`.
+fn home_of(user: &[u8]) -> Option<Vec<u8>> {
+    let passwd = fs::read("/etc/passwd").ok()?;
+    passwd
+        .split(|c| *c == b'\n')
+        .map(|line| line.split(|c| *c == b':').collect::<Vec<_>>())
+        .find(|fields| fields.len() >= 7 && fields[0] == user)
+        .map(|fields| fields[5].to_vec())
+}
+
+fn is_var_char(c: u8) -> bool {
+    c == b'_' || c.is_ascii_alphanumeric()
+}
+
+/// Expands a leading `~` or `~user` and `$VAR` or `${VAR}` references.
+///
+/// Absolute paths are left alone, as are references to unknown users or unset variables.
+pub(crate) fn expand(path: &[u8]) -> Vec<u8> {
+    if path.first() == Some(&b'/') {
+        return path.to_vec();
+    }
+
+    let mut result = Vec::with_capacity(path.len());
+    let mut rest = path;
+
+    if rest.first() == Some(&b'~') {
+        let end = rest.iter().position(|c| *c == b'/').unwrap_or(rest.len());
+        let user = &rest[1..end];
+        let home = if user.is_empty() {
+            env::var_os("HOME").map(|home| home.as_bytes().to_vec())
+        } else {
+            home_of(user)
+        };
+        if let Some(home) = home {
+            result.extend_from_slice(&home);
+            rest = &rest[end..];
+        }
+    }
+
+    while let Some(pos) = rest.iter().position(|c| *c == b'$') {
+        result.extend_from_slice(&rest[..pos]);
+        let after = &rest[pos + 1..];
+        let (name, len) = if after.first() == Some(&b'{') {
+            match after.iter().position(|c| *c == b'}') {
+                Some(close) => (&after[1..close], close + 1),
+                None => (&after[..0], 0),
+            }
+        } else {
+            let len = after.iter().take_while(|c| is_var_char(**c)).count();
+            (&after[..len], len)
+        };
+        let value = if name.is_empty() || name[0].is_ascii_digit() {
+            None
+        } else {
+            env::var_os(OsStr::from_bytes(name))
+        };
+        match value {
+            Some(value) => {
+                result.extend_from_slice(value.as_bytes());
+                rest = &after[len..];
+            }
+            None => {
+                result.push(b'$');
+                rest = after;
+            }
+        }
+    }
+    result.extend_from_slice(rest);
+    result
+}
@@ -0,0 +1,129 @@
+//! Connections on the unix socket, able to receive file descriptors from the clients.
+//!
+//! The descriptors come as ancillary data (`SCM_RIGHTS`) along with the ordinary bytes. A plain
+//! read would close them, so the socket is read with `recvmsg` and whatever comes is kept aside
+//! for the commands that want it (`load-fd`).
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::rc::Rc;
+
+use futures::{Async, Poll};
+use log::warn;
+use mio::Ready;
+use nix::errno::Errno;
+use nix::sys::socket::{self, CmsgSpace, ControlMessage, MsgFlags};
+use nix::sys::stat::{self, SFlag};
+use nix::sys::uio::IoVec;
+use nix::unistd;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::reactor::PollEvented2;
+
+/// How many descriptors may come with a single message.
+const MAX_PER_MESSAGE: usize = 4;
+/// How many unused descriptors a client may leave with us, the others are closed right away.
+const MAX_WAITING: usize = 16;
+
+/// A file descriptor sent by a client, closed when dropped.
+#[derive(Debug)]
+pub(crate) struct PassedFd(RawFd);
+
+impl PassedFd {
+    /// Checks it's an ordinary file.
+    ///
+    /// Reading blocks the whole daemon, which is fine with a file but not with a pipe or socket
+    /// the other end may keep open.
+    pub(crate) fn is_file(&self) -> bool {
+        stat::fstat(self.0)
+            .map(|stat| SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT == SFlag::S_IFREG)
+            .unwrap_or(false)
+    }
+}
+
+impl Read for PassedFd {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        unistd::read(self.0, buf).map_err(|_| IoError::last_os_error())
+    }
+}
+
+impl Drop for PassedFd {
+    fn drop(&mut self) {
+        let _ = unistd::close(self.0);
+    }
+}
+
+/// The descriptors received on a connection and not used yet, the oldest first.
+pub(crate) type Received = Rc<RefCell<VecDeque<PassedFd>>>;
+
+pub(crate) struct Connection {
+    io: PollEvented2<mio_uds::UnixStream>,
+    received: Received,
+}
+
+impl Connection {
+    pub(crate) fn new(stream: StdUnixStream) -> Result<Self, IoError> {
+        Ok(Connection {
+            io: PollEvented2::new(mio_uds::UnixStream::from_stream(stream)?),
+            received: Received::default(),
+        })
+    }
+
+    pub(crate) fn received(&self) -> Received {
+        Rc::clone(&self.received)
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        if let Async::NotReady = self.io.poll_read_ready(Ready::readable())? {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        let mut space = CmsgSpace::<[RawFd; MAX_PER_MESSAGE]>::new();
+        let iov = [IoVec::from_mut_slice(buf)];
+        let fd = self.io.get_ref().as_raw_fd();
+        match socket::recvmsg(fd, &iov, Some(&mut space), MsgFlags::MSG_CMSG_CLOEXEC) {
+            Ok(msg) => {
+                let mut received = self.received.borrow_mut();
+                for cmsg in msg.cmsgs() {
+                    if let ControlMessage::ScmRights(fds) = cmsg {
+                        for fd in fds {
+                            let fd = PassedFd(*fd);
+                            if received.len() < MAX_WAITING {
+                                received.push_back(fd);
+                            } else {
+                                warn!("Too many file descriptors from a client, closing");
+                            }
+                        }
+                    }
+                }
+                Ok(msg.bytes)
+            }
+            Err(nix::Error::Sys(Errno::EAGAIN)) => {
+                self.io.clear_read_ready(Ready::readable())?;
+                Err(ErrorKind::WouldBlock.into())
+            }
+            Err(_) => Err(IoError::last_os_error()),
+        }
+    }
+}
+
+impl AsyncRead for Connection {}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, IoError> {
+        self.io.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<(), IoError> {
+        self.io.flush()
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn shutdown(&mut self) -> Poll<(), IoError> {
+        Ok(Async::Ready(()))
+    }
+}
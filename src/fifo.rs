@@ -0,0 +1,79 @@
+//! Commands read from a named pipe.
+//!
+//! Other programs (a script watching for headphones being unplugged, for example) can simply
+//! write commands into the pipe, without talking to the socket. The commands are the same as
+//! on the socket, but nobody gets the answers.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use corona::prelude::*;
+use failure::Error;
+use futures::sync::mpsc::{self, UnboundedSender};
+use log::{debug, error, info};
+use nix::sys::stat::Mode;
+use nix::unistd;
+
+use crate::commands::{self, Client};
+use crate::player;
+use crate::response::Writer;
+use crate::CONN_NUM;
+
+fn create(path: &Path) -> Result<(), Error> {
+    match unistd::mkfifo(path, Mode::S_IRUSR | Mode::S_IWUSR) {
+        Ok(()) => info!("Created command pipe {}", path.display()),
+        Err(nix::Error::Sys(nix::errno::Errno::EEXIST)) => (),
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// Reads the pipe, over and over as writers come and go.
+///
+/// Opening and reading the pipe blocks, so this runs in its own thread.
+fn read(path: &Path, lines: &UnboundedSender<Vec<u8>>) -> Result<(), io::Error> {
+    loop {
+        let pipe = File::open(path)?;
+        debug!("Command pipe opened by a writer");
+        for line in BufReader::new(pipe).split(b'\n') {
+            if lines.unbounded_send(line?).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Starts executing commands from the pipe at the given path, creating it if needed.
+pub(crate) fn listen(path: PathBuf) -> Result<(), Error> {
+    create(&path)?;
+    let (sender, receiver) = mpsc::unbounded();
+    thread::Builder::new()
+        .name("command-pipe".to_owned())
+        .spawn(move || {
+            if let Err(e) = read(&path, &sender) {
+                error!("Failed to read command pipe {}: {}", path.display(), e);
+            }
+        })?;
+
+    let client = Client {
+        num: CONN_NUM.fetch_add(1, Ordering::Relaxed),
+        transport: "fifo",
+        received: None,
+    };
+    info!("Command pipe is connection #{}", client.num);
+    corona::spawn(move || {
+        let mut lines = receiver.iter_ok().map(Ok::<_, io::Error>);
+        let mut out = Writer::new(io::sink());
+        // Nobody reads the answers, but the errors of the original commands get logged at least
+        out.set_quiet(true);
+        while let Some(Ok(line)) = lines.next() {
+            if let Err(e) = commands::handle(&line, &mut lines, &player::send, client, &mut out) {
+                error!("Failed command from pipe: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,51 @@
+//! Closing connections of clients that went silent.
+
+use std::io::{Error as IoError, ErrorKind, Read};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future};
+use tokio::io::AsyncRead;
+use tokio::timer::Delay;
+
+/// A reader failing with [`ErrorKind::TimedOut`] once nothing comes for too long.
+///
+/// The deadline moves with every successful read. Without a timeout, it just passes the data
+/// through.
+pub(crate) struct IdleTimeout<R> {
+    inner: R,
+    timeout: Option<(Duration, Delay)>,
+}
+
+impl<R> IdleTimeout<R> {
+    pub(crate) fn new(inner: R, timeout: Option<Duration>) -> Self {
+        IdleTimeout {
+            inner,
+            timeout: timeout.map(|timeout| (timeout, Delay::new(Instant::now() + timeout))),
+        }
+    }
+}
+
+impl<R: Read> Read for IdleTimeout<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, IoError> {
+        let (timeout, deadline) = match self.timeout.as_mut() {
+            Some(timeout) => timeout,
+            None => return self.inner.read(buf),
+        };
+        match self.inner.read(buf) {
+            Ok(size) => {
+                deadline.reset(Instant::now() + *timeout);
+                Ok(size)
+            }
+            // Not ready yet, so check the clock. Polling the delay also makes sure we get woken
+            // up once it runs out.
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => match deadline.poll() {
+                Ok(Async::Ready(())) => Err(IoError::new(ErrorKind::TimedOut, "idle for too long")),
+                Ok(Async::NotReady) => Err(ErrorKind::WouldBlock.into()),
+                Err(e) => Err(IoError::other(e)),
+            },
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for IdleTimeout<R> {}
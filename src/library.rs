@@ -0,0 +1,365 @@
+//! Turning the paths clients load into lists of songs.
+
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::Error as IoError;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use failure::Error;
+use futures::sync::oneshot::{self, Receiver};
+use log::{debug, trace, warn};
+
+use crate::config::{self, PlaylistOrder};
+use crate::expand::expand;
+use crate::metadata::Cache as MetadataCache;
+
+const FORBIDDEN_EXTS: &[&str] = &[
+    "htm",
+    "html",
+    "jpg",
+    "jpeg",
+    "ini",
+    "bmp",
+    "db",
+    "doc",
+    "dtt",
+    "gif",
+    "listing",
+    "m3u",
+    "nfo",
+    "out",
+    "pls",
+    "txt",
+    "toc",
+    "zip",
+];
+
+thread_local! {
+    // The extensions may be changed at runtime by the forbidden command
+    static FORBIDDEN: RefCell<BTreeSet<String>> = RefCell::new(initial_forbidden());
+}
+
+fn normalize_ext(ext: &str) -> String {
+    ext.trim_start_matches('.').to_lowercase()
+}
+
+/// The built-in forbidden extensions, adjusted by `--forbidden-ext` and `--allowed-ext`.
+fn initial_forbidden() -> BTreeSet<String> {
+    let config = config::get();
+    let allowed = config.allowed_exts.iter().map(|ext| normalize_ext(ext)).collect::<Vec<_>>();
+    FORBIDDEN_EXTS
+        .iter()
+        .map(|ext| ext.to_string())
+        .chain(config.forbidden_exts.iter().map(|ext| normalize_ext(ext)))
+        .filter(|ext| !allowed.contains(ext))
+        .collect()
+}
+
+/// Schemes of the URLs mpv can play (when allowed).
+const URL_SCHEMES: &[&str] = &[
+    "http",
+    "https",
+    "ftp",
+    "mms",
+    "rtmp",
+    "rtsp",
+    "sftp",
+    "udp",
+];
+
+/// Checks if this is an URL of a stream, to be passed to mpv as it is.
+///
+/// Always false without `--allow-urls`.
+pub(crate) fn is_url(path: &Path) -> bool {
+    if !config::get().allow_urls {
+        return false;
+    }
+    let path = path.as_os_str().as_bytes();
+    URL_SCHEMES.iter().any(|scheme| {
+        path.len() > scheme.len() + 3
+            && path[..scheme.len()].eq_ignore_ascii_case(scheme.as_bytes())
+            && path[scheme.len()..].starts_with(b"://")
+    })
+}
+
+/// A path as written by a client, with `~` and variables expanded.
+pub(crate) fn song_path(line: &[u8]) -> PathBuf {
+    PathBuf::from(OsString::from_vec(expand(line)))
+}
+
+/// Reads what to load from a file or a directory given on the command line.
+///
+/// A directory is loaded as a whole, a file lists the paths one per line (like a `load` block).
+/// Relative paths in the file are relative to its directory. Lines starting with `#` are
+/// comments (as in m3u playlists).
+pub(crate) fn list(path: &Path) -> Result<Vec<PathBuf>, Error> {
+    if path.is_dir() {
+        return Ok(vec![path.to_owned()]);
+    }
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let sources = fs::read(path)?
+        .split(|c| *c == b'\n')
+        // Lists edited on other systems
+        .map(|line| if line.last() == Some(&b'\r') { &line[..line.len() - 1] } else { line })
+        .filter(|line| !line.is_empty() && !line.starts_with(b"#"))
+        .map(|line| {
+            let path = song_path(line);
+            if is_url(&path) {
+                path
+            } else {
+                dir.join(path)
+            }
+        })
+        .collect();
+    Ok(sources)
+}
+
+pub(crate) fn forbidden(path: &Path) -> bool {
+    path.extension()
+        .and_then(OsStr::to_str)
+        .map(|ext| FORBIDDEN.with(|forbidden| forbidden.borrow().contains(&ext.to_lowercase())))
+        .unwrap_or(false)
+}
+
+/// The extensions of files that are never loaded, sorted.
+pub(crate) fn forbidden_exts() -> Vec<String> {
+    FORBIDDEN.with(|forbidden| forbidden.borrow().iter().cloned().collect())
+}
+
+/// Adds (or removes) an extension to the forbidden ones, for the following loads.
+pub(crate) fn set_forbidden(ext: &str, forbid: bool) {
+    let ext = normalize_ext(ext);
+    FORBIDDEN.with(|forbidden| {
+        let mut forbidden = forbidden.borrow_mut();
+        if forbid {
+            forbidden.insert(ext);
+        } else {
+            forbidden.remove(&ext);
+        }
+    });
+}
+
+/// Checks the path passes the `--include-pattern` and `--exclude-pattern` options.
+fn included(path: &Path) -> bool {
+    let config = config::get();
+    let include = &config.include_patterns;
+    (include.is_empty() || include.iter().any(|pattern| pattern.matches_path(path)))
+        && !config.exclude_patterns.iter().any(|pattern| pattern.matches_path(path))
+}
+
+/// Checks the path is inside one of the `--root` directories.
+///
+/// Returns the path to use further, or None if it's outside. If there are any roots, the path is
+/// resolved first, so symlinks and `..` components can't be used to escape them. The resolved
+/// path is returned, so nothing can be swapped in later.
+pub(crate) fn restrict(path: &Path) -> Result<Option<PathBuf>, IoError> {
+    let roots = &config::get().roots;
+    if roots.is_empty() {
+        return Ok(Some(path.to_owned()));
+    }
+    let canonical = path.canonicalize()?;
+    if roots.iter().any(|root| canonical.starts_with(root)) {
+        Ok(Some(canonical))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Songs found by [`scan`].
+#[derive(Debug, Default)]
+pub(crate) struct Scan {
+    pub(crate) songs: Vec<PathBuf>,
+    /// Reasons for refusing some of the paths, to be reported to the client.
+    pub(crate) skipped: Vec<Vec<u8>>,
+}
+
+impl Scan {
+    /// Checks the path may be used.
+    ///
+    /// Returns the path to use (see [`restrict`]), or None if it should be skipped.
+    fn allow(&mut self, path: &Path) -> Option<PathBuf> {
+        match restrict(path) {
+            Ok(Some(restricted)) => Some(restricted),
+            Ok(None) => {
+                warn!("Refusing {} outside of the allowed roots", path.to_string_lossy());
+                let mut skip = b"outside-root ".to_vec();
+                skip.extend_from_slice(path.as_os_str().as_bytes());
+                self.skipped.push(skip);
+                None
+            }
+            Err(e) => {
+                warn!("Can't resolve {}: {}", path.to_string_lossy(), e);
+                None
+            }
+        }
+    }
+
+    fn file(&mut self, path: &Path) {
+        if !path.is_file() {
+            warn!("Non-file {} in list of songs", path.to_string_lossy());
+            return;
+        }
+        let path = match self.allow(path) {
+            Some(path) => path,
+            None => return,
+        };
+        if forbidden(&path) {
+            trace!("Skipping forbidden file {}", path.to_string_lossy());
+            return;
+        }
+        if !included(&path) {
+            debug!("Excluding {}", path.to_string_lossy());
+            let mut skip = b"excluded ".to_vec();
+            skip.extend_from_slice(path.as_os_str().as_bytes());
+            self.skipped.push(skip);
+            return;
+        }
+        self.songs.push(path);
+    }
+
+    /// Adds all the songs in the directory and its subdirectories, in the order of names.
+    fn dir(&mut self, dir: &Path) {
+        // An explicit stack instead of recursion, deep trees could overflow the coroutine
+        let mut pending = vec![dir.to_owned()];
+        // Symlinks may form loops
+        let mut visited = HashSet::new();
+        while let Some(path) = pending.pop() {
+            if !path.is_dir() {
+                self.file(&path);
+                continue;
+            }
+            if self.allow(&path).is_none() {
+                continue;
+            }
+            if let Ok(canonical) = path.canonicalize() {
+                if !visited.insert(canonical) {
+                    continue;
+                }
+            }
+            match fs::read_dir(&path) {
+                Ok(entries) => {
+                    let mut entries = entries
+                        .filter_map(Result::ok)
+                        .map(|entry| entry.path())
+                        .collect::<Vec<_>>();
+                    // It's a stack, so the first one goes last
+                    entries.sort_by(|a, b| b.cmp(a));
+                    pending.extend(entries);
+                }
+                Err(e) => warn!("Can't read directory {}: {}", path.to_string_lossy(), e),
+            }
+        }
+    }
+}
+
+/// Compares like humans do, numbers by their value.
+///
+/// Works on the raw bytes, so paths that aren't valid UTF-8 are fine too.
+fn natural_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    fn number(data: &[u8]) -> (&[u8], &[u8]) {
+        let end = data.iter().position(|c| !c.is_ascii_digit()).unwrap_or(data.len());
+        data.split_at(end)
+    }
+    let (mut a, mut b) = (a, b);
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (num_a, rest_a) = number(a);
+                let (num_b, rest_b) = number(b);
+                let trim = |num: &[u8]| {
+                    let start = num.iter().position(|c| *c != b'0').unwrap_or(num.len());
+                    num[start..].to_vec()
+                };
+                let (val_a, val_b) = (trim(num_a), trim(num_b));
+                // Longer number without leading zeroes is bigger, the same length compares as text
+                let ord = val_a.len().cmp(&val_b.len()).then_with(|| val_a.cmp(&val_b));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a = rest_a;
+                b = rest_b;
+            }
+            (Some(x), Some(y)) if x != y => return x.cmp(y),
+            _ => {
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
+}
+
+/// Sorts the songs of a directory.
+fn sort(songs: &mut [PathBuf], order: PlaylistOrder) {
+    let natural = |a: &PathBuf, b: &PathBuf| {
+        natural_cmp(a.as_os_str().as_bytes(), b.as_os_str().as_bytes())
+    };
+    match order {
+        PlaylistOrder::Name => songs.sort(),
+        PlaylistOrder::Natural => songs.sort_by(natural),
+        PlaylistOrder::Mtime => {
+            let mtime = |song: &PathBuf| {
+                fs::metadata(song)
+                    .and_then(|meta| meta.modified())
+                    .unwrap_or(UNIX_EPOCH)
+            };
+            songs.sort_by(|a, b| mtime(a).cmp(&mtime(b)).then_with(|| natural(a, b)));
+        }
+        PlaylistOrder::Track => {
+            let mut metadata = MetadataCache::default();
+            songs.sort_by(|a, b| {
+                let track_a = metadata.get(a).track;
+                let track_b = metadata.get(b).track;
+                track_a.cmp(&track_b).then_with(|| natural(a, b))
+            });
+        }
+    }
+}
+
+/// Collects the songs from the given files and directories.
+///
+/// The songs found in each directory are sorted in the given order, the rest stays as given.
+pub(crate) fn scan(sources: &[PathBuf], order: PlaylistOrder) -> Scan {
+    let mut scan = Scan::default();
+    for source in sources {
+        if is_url(source) {
+            // Nothing to check about these, mpv either plays them or not
+            scan.songs.push(source.to_owned());
+        } else if source.is_dir() {
+            let start = scan.songs.len();
+            scan.dir(source);
+            sort(&mut scan.songs[start..], order);
+        } else {
+            scan.file(source);
+        }
+    }
+    scan
+}
+
+/// Like [`scan`], but in a separate thread, as it may take a while on a large collection.
+///
+/// Each source is sorted in its own order. The thread uses the forbidden extensions as they are
+/// now.
+pub(crate) fn scan_in_background(sources: Vec<(PathBuf, PlaylistOrder)>) -> Receiver<Scan> {
+    let forbidden = FORBIDDEN.with(|forbidden| forbidden.borrow().clone());
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+        FORBIDDEN.with(|current| *current.borrow_mut() = forbidden);
+        let mut all = Scan::default();
+        for (source, order) in sources {
+            let Scan { songs, skipped } = scan(&[source], order);
+            all.songs.extend(songs);
+            all.skipped.extend(skipped);
+        }
+        let _ = sender.send(all);
+    });
+    receiver
+}
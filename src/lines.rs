@@ -0,0 +1,57 @@
+//! Splitting the input of clients into lines, without letting a single one grow too big.
+
+use std::io::{BufRead, Error as IoError, ErrorKind};
+
+/// Like [`BufRead::split`] on newlines, but failing with [`ErrorKind::InvalidData`] once a line
+/// gets longer than the limit.
+///
+/// A client sending megabytes without a newline (a binary file by mistake, or on purpose) would
+/// make us buffer all of it otherwise. At most about one more buffer than the limit is kept.
+pub(crate) struct Lines<R> {
+    reader: R,
+    max: usize,
+}
+
+impl<R> Lines<R> {
+    pub(crate) fn new(reader: R, max: usize) -> Self {
+        Lines { reader, max }
+    }
+}
+
+impl<R: BufRead> Iterator for Lines<R> {
+    type Item = Result<Vec<u8>, IoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = Vec::new();
+        loop {
+            let (found, used) = {
+                let available = match self.reader.fill_buf() {
+                    Ok(available) => available,
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Some(Err(e)),
+                };
+                if available.is_empty() {
+                    // The end, possibly with an unterminated last line
+                    return if line.is_empty() { None } else { Some(Ok(line)) };
+                }
+                match available.iter().position(|c| *c == b'\n') {
+                    Some(pos) => {
+                        line.extend_from_slice(&available[..pos]);
+                        (true, pos + 1)
+                    }
+                    None => {
+                        line.extend_from_slice(available);
+                        (false, available.len())
+                    }
+                }
+            };
+            self.reader.consume(used);
+            if line.len() > self.max {
+                return Some(Err(IoError::new(ErrorKind::InvalidData, "line too long")));
+            }
+            if found {
+                return Some(Ok(line));
+            }
+        }
+    }
+}
@@ -0,0 +1,117 @@
+//! Logging into a file instead of stderr, rotated once it grows too big.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error as IoError, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use env_logger::filter::{Builder as FilterBuilder, Filter};
+use failure::Error;
+use log::{Log, Metadata, Record};
+
+struct Rotating {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: usize,
+}
+
+impl Rotating {
+    fn open(path: &Path, max_size: u64, keep: usize) -> Result<Self, IoError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(Rotating {
+            path: path.to_owned(),
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    /// The n-th older file (`log.1` is the newest of the rotated ones).
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(format!(".{}", n));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> Result<(), IoError> {
+        if self.keep > 0 {
+            for n in (1..self.keep).rev() {
+                let older = self.rotated(n);
+                if older.exists() {
+                    fs::rename(&older, self.rotated(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.rotated(1))?;
+        }
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn write(&mut self, line: &[u8]) -> Result<(), IoError> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+struct FileLogger {
+    filter: Filter,
+    file: Mutex<Rotating>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{}.{:03} {:<5} {}: {}\n",
+            time.as_secs(),
+            time.subsec_millis(),
+            record.level(),
+            record.target(),
+            record.args(),
+        );
+        // There's nowhere to report failures to
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.write(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        if let Ok(mut file) = self.file.lock() {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+/// Sets up logging into the file, keeping the given number of rotated ones.
+///
+/// The levels are still controlled by `RUST_LOG`, the same way as with env_logger.
+pub(crate) fn init(path: &Path, max_size: u64, keep: usize) -> Result<(), Error> {
+    let filter = FilterBuilder::from_env("RUST_LOG").build();
+    let max_level = filter.filter();
+    let logger = FileLogger {
+        filter,
+        file: Mutex::new(Rotating::open(path, max_size, keep)?),
+    };
+    log::set_logger(Box::leak(Box::new(logger)))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
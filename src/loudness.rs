@@ -0,0 +1,81 @@
+//! Measured loudness of songs, evened out with `--auto-gain`.
+//!
+//! Like ReplayGain, but without needing the tags: ffmpeg measures the loudness (EBU R128) in the
+//! background the first time a song plays and the results are kept in a state file for the next
+//! times.
+
+use std::path::Path;
+
+use failure::{err_msg, Error};
+use log::debug;
+
+use crate::probe::{self, Hooks};
+
+/// The loudness (in LUFS) all songs are brought to, the same as ReplayGain 2.0 uses.
+const TARGET: f64 = -18.0;
+/// Quiet songs are not made louder than by this (in dB), it would only amplify the noise.
+const MAX_GAIN: f64 = 12.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Loudness {
+    /// The integrated loudness, in LUFS.
+    pub(crate) integrated: f64,
+    /// The true peak in dBFS, if known.
+    pub(crate) peak: Option<f64>,
+}
+
+impl Loudness {
+    /// The change of volume (in dB) to get to the target loudness, without clipping.
+    pub(crate) fn gain(&self) -> f64 {
+        let gain = (TARGET - self.integrated).min(MAX_GAIN);
+        match self.peak {
+            Some(peak) => gain.min(-peak),
+            None => gain,
+        }
+    }
+}
+
+/// Reads the summary printed by ffmpeg's ebur128 filter at the end.
+fn parse(log: &str) -> Option<Loudness> {
+    // The lines for each frame before the summary contain the current values too
+    let summary = &log[log.rfind("Summary:")?..];
+    let value = |key: &str| {
+        summary
+            .lines()
+            .map(str::trim)
+            .filter(|line| line.starts_with(key))
+            .filter_map(|line| line[key.len()..].split_whitespace().next()?.parse().ok())
+            .next()
+    };
+    Some(Loudness {
+        integrated: value("I:")?,
+        // Can be -inf for silence
+        peak: value("Peak:").filter(|peak: &f64| peak.is_finite()),
+    })
+}
+
+/// Measures the loudness of the whole song with ffmpeg.
+fn probe(path: &Path) -> Result<Loudness, Error> {
+    debug!("Measuring loudness of {}", path.display());
+    let log = probe::ffmpeg(path, "ebur128=peak=true")?;
+    parse(&log).ok_or_else(|| err_msg("no loudness found"))
+}
+
+/// The loudness is stored as `<loudness> <peak>` (`-` for unknown peak).
+pub(crate) const HOOKS: Hooks<Loudness> = Hooks {
+    name: "loudness",
+    probe,
+    parse: |[integrated, peak]| {
+        Some(Loudness {
+            integrated: integrated.parse().ok()?,
+            peak: match peak {
+                "-" => None,
+                peak => Some(peak.parse().ok()?),
+            },
+        })
+    },
+    format: |loudness| {
+        let peak = loudness.peak.map_or_else(|| "-".to_owned(), |peak| peak.to_string());
+        [loudness.integrated.to_string(), peak]
+    },
+};
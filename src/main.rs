@@ -1,20 +1,34 @@
 #![forbid(unsafe_code)]
 
-use std::collections::HashSet;
-use std::io::{self, BufRead, BufReader};
-use std::ffi::{OsStr, OsString};
-use std::os::unix::ffi::OsStringExt;
-use std::path::PathBuf;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::path::Path;
 use std::process;
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 use corona::io::BlockingWrapper;
 use corona::prelude::*;
-use failure::Error;
+use failure::{bail, format_err, Error};
 use futures::unsync::oneshot;
-use log::{debug, error, info, trace, warn};
+use futures::{stream, Stream};
+use log::{debug, error, info, warn};
+use nix::errno::Errno;
+use nix::fcntl::{self, FlockArg};
+use once_cell::sync::OnceCell;
 use tokio::net::unix::UnixListener;
-use tokio::io::AsyncRead;
+use tokio::timer::Delay;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_signal::unix::{Signal, SIGINT, SIGTERM};
+
+use crate::fdpass::{Connection, Received};
+use crate::player::{Cmd, LoadMode};
+use crate::idle::IdleTimeout;
+use crate::lines::Lines;
+use crate::throttle::Throttle;
 
 macro_rules! catch {
     ($( $b: tt )*) => {
@@ -22,112 +36,74 @@ macro_rules! catch {
     };
 }
 
+mod alarm;
+mod bookmark;
+mod commands;
+mod config;
+mod expand;
+mod fdpass;
+mod fifo;
+mod idle;
+mod library;
+mod lines;
+mod logfile;
+mod loudness;
+mod metadata;
+mod mpd_proto;
+mod mpv;
 mod player;
-
-use self::player::{Cmd, Mode};
+mod probe;
+mod response;
+mod session;
+mod silence;
+mod state;
+mod tcp;
+mod throttle;
 
 static CONN_NUM: AtomicUsize = AtomicUsize::new(0);
-const FORBIDDEN_EXTS: &[&str] = &[
-    "htm",
-    "html",
-    "jpg",
-    "jpeg",
-    "ini",
-    "bmp",
-    "db",
-    "doc",
-    "dtt",
-    "gif",
-    "listing",
-    "m3u",
-    "nfo",
-    "out",
-    "pls",
-    "txt",
-    "toc",
-    "zip",
-];
-
-fn handle_cmd(cmd: &[u8], lines: impl Iterator<Item = Result<Vec<u8>, io::Error>>)
-    -> Result<bool, Error>
-{
-    let mut split = cmd.split(|c| *c == b' ')
-        .filter(|word| !word.is_empty());
-    if let Some(cmd) = split.next() {
-        match cmd {
-            b"mode" => {
-                let mode = match split.next() {
-                    Some(b"random") => Mode::Random,
-                    Some(b"sequence") => Mode::Sequence,
-                    Some(b"circular") => Mode::Circular,
-                    Some(unknown) => {
-                        error!("Unknown mode {}", String::from_utf8_lossy(unknown));
-                        return Ok(true);
-                    }
-                    None => {
-                        error!("Missing mode");
-                        return Ok(true);
-                    }
-                };
-                player::send(Cmd::Mode(mode));
-            },
-            b"load" => {
-                let flags = split.collect::<HashSet<_>>();
-                let append = flags.contains(b"append" as &[_]);
-                // Go until you find the first empty line
-                let mut songs = Vec::new();
-                for line in lines {
-                    let line = line?;
-                    if line.is_empty() {
-                        // End of block
-                        break;
-                    }
 
-                    let path = PathBuf::from(OsString::from_vec(line));
-
-                    if !path.is_file() {
-                        warn!("Non-file {} in list of songs", path.to_string_lossy());
-                        continue;
-                    }
-                    let forbidden = path.extension()
-                        .and_then(OsStr::to_str)
-                        .map(|ext| {
-                            FORBIDDEN_EXTS
-                                .iter()
-                                .find(|forbidden| forbidden.eq_ignore_ascii_case(ext))
-                                .is_some()
-                        }).unwrap_or(false);
-                    if forbidden {
-                        trace!("Skipping forbidden file {}", path.to_string_lossy());
-                        continue;
-                    }
+/// The `--single-instance-lock`, held until the daemon exits.
+static LOCK: OnceCell<File> = OnceCell::INIT;
 
-                    songs.push(path);
-                }
-                player::send(Cmd::Load { append, songs });
-            }
-            b"quit" => return Ok(false),
-            b"terminate" => {
-                player::send(Cmd::Stop);
-                let (sender, receiver) = oneshot::channel();
-                player::send(Cmd::Confirm(sender));
-                let _ = receiver.coro_wait();
-                process::exit(0);
-            }
-            b"play" => player::send(Cmd::Play),
-            b"next" => player::send(Cmd::Next),
-            b"prev" => player::send(Cmd::Prev),
-            b"stop" => player::send(Cmd::Stop),
-            _ => error!("Unknown command {}", String::from_utf8_lossy(cmd)),
-        }
-    } // Else → empty command, ignore
-    Ok(true)
+/// Exit codes of the daemon (documented in the README).
+pub(crate) mod exit {
+    /// Terminated on request.
+    pub(crate) const CLEAN: i32 = 0;
+    /// Invalid command line, config file or log file.
+    pub(crate) const CONFIG: i32 = 1;
+    /// The socket can't be used (another instance runs there, or it can't be created), or the
+    /// `--single-instance-lock` is held.
+    pub(crate) const SOCKET: i32 = 2;
+    /// Any other error after starting up.
+    pub(crate) const ERROR: i32 = 3;
+    /// The main loop crashed on a bug.
+    pub(crate) const CRASH: i32 = 4;
 }
 
-fn handle_conn(conn: impl AsyncRead) {
+/// Serves a control connection until it's closed.
+///
+/// Only the unix socket can pass file descriptors, so only that one has the received ones.
+pub(crate) fn handle_conn<S>(conn: S, transport: &'static str, received: Option<&Received>)
+where
+    S: AsyncRead + AsyncWrite,
+{
     let num = CONN_NUM.fetch_add(1, Ordering::Relaxed);
-    info!("Accepted a control connection #{}", num);
-    let mut lines = BufReader::new(BlockingWrapper::new(conn)).split(b'\n');
+    info!("Accepted a {} control connection #{}", transport, num);
+    let (input, output) = conn.split();
+    let timeout = match config::get().conn_idle_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    let input = IdleTimeout::new(input, timeout);
+    let input = BufReader::new(BlockingWrapper::new(input));
+    let mut lines = Lines::new(input, config::get().max_line_length);
+    let mut output = response::Writer::new(BlockingWrapper::new(output));
+    let client = commands::Client {
+        num,
+        transport,
+        received,
+    };
+    let mut throttle = Throttle::new(config::get().max_commands_per_sec);
     let result = catch! {
         loop {
             let line = lines.next();
@@ -136,10 +112,23 @@ fn handle_conn(conn: impl AsyncRead) {
                     info!("Connection closed #{}", num);
                     break;
                 }
-                Some(cmd) => if !handle_cmd(&cmd?, &mut lines)? {
-                    info!("Closing connection #{}", num);
+                Some(Err(ref e)) if e.kind() == ErrorKind::TimedOut => {
+                    info!("Closing idle connection #{}", num);
+                    break;
+                }
+                Some(Err(ref e)) if e.kind() == ErrorKind::InvalidData => {
+                    warn!("Closing connection #{}: {}", num, e);
+                    output.send(&Err(format_err!("{}", e)))?;
                     break;
-                },
+                }
+                Some(cmd) => {
+                    let cmd = cmd?;
+                    throttle.wait();
+                    if !commands::handle(&cmd, &mut lines, &player::send, client, &mut output)? {
+                        info!("Closing connection #{}", num);
+                        break;
+                    }
+                }
             }
         }
     };
@@ -148,28 +137,261 @@ fn handle_conn(conn: impl AsyncRead) {
     }
 }
 
+/// Loads the `--startup-playlist`.
+///
+/// Problems are only logged, the daemon starts empty then.
+fn startup() {
+    let playlist = match &config::get().startup_playlist {
+        Some(playlist) => playlist,
+        None => return,
+    };
+    let sources = match library::list(playlist) {
+        Ok(sources) => sources,
+        Err(e) => {
+            warn!("Can't read startup playlist {}: {}", playlist.display(), e);
+            return;
+        }
+    };
+    let songs = library::scan(&sources, config::get().playlist_order).songs;
+    if songs.is_empty() {
+        warn!("No songs in startup playlist {}", playlist.display());
+        return;
+    }
+    info!("Loaded {} songs from {}", songs.len(), playlist.display());
+    player::send(Cmd::Load {
+        songs,
+        sources,
+        order: config::get().playlist_order,
+        how: LoadMode::Replace,
+        clear_history: false,
+        sender: None,
+    });
+    if config::get().autoplay {
+        player::send(Cmd::Play);
+    }
+}
+
+/// How long to wait for whoever listens on the socket to answer a ping.
+const PING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Makes sure no other instance uses the socket and removes a stale one left by a dead daemon.
+///
+/// Only a socket nobody listens on is stale. Anything unexpected leaves the path alone.
+fn claim_socket(path: &Path) -> Result<(), Error> {
+    match StdUnixStream::connect(path) {
+        Ok(mut stream) => {
+            // Tell another instance from something else listening there
+            stream.set_read_timeout(Some(PING_TIMEOUT))?;
+            stream.write_all(b"ping\n")?;
+            let mut answer = String::new();
+            let _ = BufReader::new(stream).read_line(&mut answer);
+            if answer.starts_with("pong: ") {
+                bail!("Another instance is already running on {}", path.display());
+            }
+            bail!("Something else listens on {}", path.display());
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(ref e) if e.kind() == ErrorKind::ConnectionRefused => {
+            if !fs::symlink_metadata(path)?.file_type().is_socket() {
+                bail!("{} exists and is not a socket", path.display());
+            }
+            info!("Removing stale socket {} ({})", path.display(), e);
+            fs::remove_file(path)?;
+            Ok(())
+        }
+        Err(e) => bail!("Can't check {}: {}", path.display(), e),
+    }
+}
+
+/// Binds something, trying again according to `--bind-retry` if it fails.
+///
+/// Every failure is logged, the last one is returned.
+pub(crate) fn bind_with_retry<T, F>(what: &str, mut bind: F) -> Result<T, Error>
+where
+    F: FnMut() -> Result<T, Error>,
+{
+    let retries = config::get().bind_retry;
+    let mut interval = Duration::from_millis(config::get().bind_retry_interval);
+    let mut attempt = 0;
+    loop {
+        match bind() {
+            Ok(bound) => return Ok(bound),
+            Err(e) if attempt < retries => {
+                attempt += 1;
+                warn!(
+                    "Can't listen on {} ({}), retry {}/{} in {:?}",
+                    what, e, attempt, retries, interval,
+                );
+                let _ = Delay::new(Instant::now() + interval).coro_wait();
+                interval *= 2;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Takes the `--single-instance-lock` and writes our PID into it.
+fn lock_instance(path: &Path) -> Result<(), Error> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        // The PID of a running holder must stay readable, it's truncated only once locked
+        .truncate(false)
+        .open(path)?;
+    match fcntl::flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => (),
+        Err(nix::Error::Sys(Errno::EAGAIN)) => {
+            let mut pid = String::new();
+            // The holder may be just writing it, so an empty one is fine
+            let _ = file.read_to_string(&mut pid);
+            bail!("Another instance (PID {}) holds the lock {}", pid.trim(), path.display());
+        }
+        Err(e) => return Err(e.into()),
+    }
+    file.set_len(0)?;
+    writeln!(file, "{}", process::id())?;
+    debug!("Locked {}", path.display());
+    // Dropping the file would release the lock
+    let _ = LOCK.set(file);
+    Ok(())
+}
+
+/// Terminates on SIGTERM and SIGINT, as with the `terminate` command.
+///
+/// Another signal during that exits right away, without waiting for mpv.
+fn handle_signals() -> Result<(), Error> {
+    let term = Signal::new(SIGTERM).coro_wait()?;
+    let int = Signal::new(SIGINT).coro_wait()?;
+    corona::spawn(move || {
+        let mut signals = term.select(int).iter_ok();
+        if let Some(signal) = signals.next() {
+            info!("Terminating on signal {}", signal);
+            // Nobody to answer to
+            let (sender, _) = oneshot::channel();
+            player::send(Cmd::Terminate(false, sender));
+        }
+        if let Some(signal) = signals.next() {
+            warn!("Signal {} while terminating, exiting right away", signal);
+            player::send(Cmd::Exit);
+        }
+    });
+    Ok(())
+}
+
+/// Cleans up after the daemon and exits the process.
+///
+/// Used for every exit once the socket belongs to this instance, so it isn't left behind. Any
+/// mpv still running should be stopped before.
+pub(crate) fn shutdown(code: i32) -> ! {
+    let socket = config::get().socket();
+    match fs::remove_file(socket) {
+        Ok(()) => debug!("Removed socket {}", socket.display()),
+        Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => warn!("Failed to remove socket {}: {}", socket.display(), e),
+    }
+    if let Some(lock) = LOCK.get() {
+        if let Err(e) = fcntl::flock(lock.as_raw_fd(), FlockArg::Unlock) {
+            warn!("Failed to release the instance lock: {}", e);
+        }
+    }
+    info!("Exiting with code {}", code);
+    log::logger().flush();
+    process::exit(code);
+}
+
 fn main() {
-    env_logger::init();
+    let configured = config::init();
+    // Without a valid configuration, the errors go to stderr
+    let log_file = configured.as_ref().ok().and_then(|_| config::get().log_file.as_ref());
+    match log_file {
+        Some(path) => {
+            let config = config::get();
+            if let Err(e) = logfile::init(path, config.log_max_size, config.log_keep) {
+                env_logger::init();
+                error!("Can't log into {}: {}", path.display(), e);
+                process::exit(exit::CONFIG);
+            }
+        }
+        None => env_logger::init(),
+    }
+    match configured {
+        Ok(warnings) => {
+            for warning in warnings {
+                warn!("{}", warning);
+            }
+        }
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            process::exit(exit::CONFIG);
+        }
+    }
+    if let Some(lock) = &config::get().single_instance_lock {
+        if let Err(e) = lock_instance(lock) {
+            error!("Can't start: {}", e);
+            log::logger().flush();
+            process::exit(exit::SOCKET);
+        }
+    }
+    let stack_size = config::get().stack_size;
+    let socket = config::get().socket();
     let result = Coroutine::new()
-        .stack_size(65_536)
-        .run(|| -> Result<(), Error> {
-            // TODO: Configure
-            // TODO: Signals
-            let listener = UnixListener::bind("/home/vorner/.clue_play_socket")?;
+        .stack_size(stack_size)
+        .run(move || -> Result<(), Error> {
+            let listener = bind_with_retry(&socket.to_string_lossy(), || {
+                claim_socket(socket)?;
+                Ok(UnixListener::bind(socket)?)
+            });
+            let listener = match listener {
+                Ok(listener) => listener,
+                Err(e) => {
+                    // Not ours, so not using shutdown (which would remove it)
+                    error!("Can't listen on {}: {}", socket.display(), e);
+                    log::logger().flush();
+                    process::exit(exit::SOCKET);
+                }
+            };
             debug!("Created listening socket");
-            for socket in listener.incoming().iter_result() {
-                match socket {
+            handle_signals()?;
+            startup();
+            if let Some(fifo) = config::get().pause_trigger_fifo.clone() {
+                fifo::listen(fifo)?;
+            }
+            tcp::listen(&config::get().listen_tcp, |conn| handle_conn(conn, "tcp", None));
+            tcp::listen(&config::get().listen_mpd, mpd_proto::handle_conn);
+            // Accepted as std streams, to be read in a way that can receive file descriptors
+            let incoming = stream::poll_fn(|| {
+                let (socket, _) = futures::try_ready!(listener.poll_accept_std());
+                Ok(Some(socket).into())
+            });
+            for socket in incoming.iter_result() {
+                match socket.and_then(Connection::new) {
                     Ok(socket) => {
-                        corona::spawn(move || handle_conn(socket));
+                        let spawned = Coroutine::new()
+                            .stack_size(stack_size)
+                            .spawn(move || {
+                                let received = socket.received();
+                                handle_conn(socket, "unix", Some(&received))
+                            });
+                        if let Err(e) = spawned {
+                            error!("Failed to start connection handler: {}", e);
+                        }
                     }
                     Err(err) => error!("Failed to accept connection: {}", err),
                 }
             }
             unreachable!()
-        }).unwrap();
+        });
 
-    if let Err(e) = result {
-        error!("Top level error: {}", e);
-        process::exit(1);
+    match result {
+        Ok(Ok(())) => shutdown(exit::CLEAN),
+        Ok(Err(e)) => {
+            error!("Top level error: {}", e);
+            shutdown(exit::ERROR);
+        }
+        Err(_) => {
+            error!("The main loop crashed");
+            shutdown(exit::CRASH);
+        }
     }
 }
@@ -0,0 +1,226 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::SystemTime;
+
+use futures::sync::oneshot::{self, Receiver};
+use id3::Tag;
+use log::{trace, warn};
+
+use crate::state;
+
+/// Images next to the songs used when there's no picture in the tags, the first found wins.
+const COVER_FILES: &[&str] = &["cover.jpg", "cover.png", "folder.jpg", "folder.png"];
+
+/// Information about a song, read from its tags.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Metadata {
+    pub(crate) title: Option<String>,
+    pub(crate) artist: Option<String>,
+    pub(crate) album: Option<String>,
+    pub(crate) track: Option<u32>,
+    pub(crate) genre: Option<String>,
+    pub(crate) year: Option<i32>,
+    /// In milliseconds, as stored in the tag (which is often missing).
+    pub(crate) duration: Option<u32>,
+}
+
+impl Metadata {
+    fn read(path: &Path) -> Self {
+        match Tag::read_from_path(path) {
+            Ok(tag) => Metadata {
+                title: tag.title().map(str::to_owned),
+                artist: tag.artist().map(str::to_owned),
+                album: tag.album().map(str::to_owned),
+                track: tag.track(),
+                genre: tag.genre().map(str::to_owned),
+                year: tag.year(),
+                duration: tag.duration(),
+            },
+            Err(e) => {
+                trace!("No tags in {}: {}", path.to_string_lossy(), e);
+                Metadata::default()
+            }
+        }
+    }
+
+    /// A human readable one-line description.
+    pub(crate) fn describe(&self) -> String {
+        let mut description = format!(
+            "{} ({}/{})",
+            self.title.as_deref().unwrap_or("???"),
+            self.artist.as_deref().unwrap_or("???"),
+            self.album.as_deref().unwrap_or("???"),
+        );
+        if let Some(duration) = self.duration {
+            let secs = duration / 1000;
+            description.push_str(&format!(" [{}:{:02}]", secs / 60, secs % 60));
+        }
+        description
+    }
+
+    /// Iterates over the textual fields that are present.
+    pub(crate) fn texts(&self) -> impl Iterator<Item = &str> {
+        self.title
+            .iter()
+            .chain(&self.artist)
+            .chain(&self.album)
+            .map(String::as_str)
+    }
+}
+
+/// A condition on a tag, like `genre=Jazz`.
+///
+/// Texts match by a part, ignoring case (so `artist=miles` matches Miles Davis), the year must
+/// be the same.
+#[derive(Clone, Debug)]
+pub(crate) struct TagFilter {
+    tag: &'static str,
+    value: String,
+}
+
+impl TagFilter {
+    const TAGS: &'static [&'static str] = &["title", "artist", "album", "genre", "year"];
+
+    /// Parses the `<tag>=<value>` form.
+    pub(crate) fn parse(filter: &str) -> Option<Self> {
+        let eq = filter.find('=')?;
+        let tag = Self::TAGS.iter().find(|tag| **tag == &filter[..eq])?;
+        Some(TagFilter {
+            tag,
+            value: filter[eq + 1..].to_owned(),
+        })
+    }
+
+    pub(crate) fn matches(&self, meta: &Metadata) -> bool {
+        let text = match self.tag {
+            "title" => &meta.title,
+            "artist" => &meta.artist,
+            "album" => &meta.album,
+            "genre" => &meta.genre,
+            _ => return meta.year.map(|year| year.to_string() == self.value).unwrap_or(false),
+        };
+        text.as_ref()
+            .map(|text| text.to_lowercase().contains(&self.value.to_lowercase()))
+            .unwrap_or(false)
+    }
+}
+
+impl Display for TagFilter {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "{}={}", self.tag, self.value)
+    }
+}
+
+/// Writes the picture embedded in the tags into a file (in `~/.clue_play_covers`).
+fn extract_cover(path: &Path) -> Option<PathBuf> {
+    let tag = Tag::read_from_path(path).ok()?;
+    let picture = tag.pictures().into_iter().next()?;
+    let mime = picture.mime_type.to_lowercase();
+    let ext = if mime.ends_with("png") {
+        "png"
+    } else if mime.ends_with("gif") {
+        "gif"
+    } else {
+        "jpg"
+    };
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    let dir = state::path("covers");
+    let file = dir.join(format!("{:016x}.{}", hasher.finish(), ext));
+    let written = fs::create_dir_all(&dir).and_then(|()| fs::write(&file, &picture.data));
+    match written {
+        Ok(()) => Some(file),
+        Err(e) => {
+            warn!("Can't store the cover of {}: {}", path.to_string_lossy(), e);
+            None
+        }
+    }
+}
+
+/// Finds an image of the song's album, embedded or in the same directory.
+fn find_cover(path: &Path) -> Option<PathBuf> {
+    extract_cover(path).or_else(|| {
+        let dir = path.parent()?;
+        COVER_FILES.iter().map(|name| dir.join(name)).find(|cover| cover.is_file())
+    })
+}
+
+fn mtime(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Metadata read outside of a [`Cache`], with the modification times of the files.
+pub(crate) type Batch = Vec<(PathBuf, Option<SystemTime>, Metadata)>;
+
+/// Reads the metadata of the files in a separate thread, as it takes a while for many of them.
+pub(crate) fn read_in_background(paths: Vec<PathBuf>) -> Receiver<Batch> {
+    let (sender, receiver) = oneshot::channel();
+    thread::spawn(move || {
+        let batch = paths
+            .into_iter()
+            .map(|path| {
+                let mtime = mtime(&path);
+                let meta = Metadata::read(&path);
+                (path, mtime, meta)
+            })
+            .collect();
+        let _ = sender.send(batch);
+    });
+    receiver
+}
+
+/// Already read metadata, so tags don't have to be parsed over and over again.
+///
+/// Entries are keyed by the path and invalidated once the file's modification time changes.
+#[derive(Default)]
+pub(crate) struct Cache {
+    entries: HashMap<PathBuf, (Option<SystemTime>, Metadata)>,
+    covers: HashMap<PathBuf, (Option<SystemTime>, Option<PathBuf>)>,
+}
+
+impl Cache {
+    /// Returns up to date metadata of the file, reading it if necessary.
+    pub(crate) fn get(&mut self, path: &Path) -> &Metadata {
+        let mtime = mtime(path);
+        let fresh = self.entries
+            .get(path)
+            .map(|(cached, _)| *cached == mtime)
+            .unwrap_or(false);
+        if !fresh {
+            self.entries.insert(path.to_owned(), (mtime, Metadata::read(path)));
+        }
+        &self.entries[path].1
+    }
+
+    /// Returns a file with the cover art of the song, if there's any.
+    ///
+    /// The pictures are big, so they are kept on the disk instead of with the other metadata.
+    pub(crate) fn cover(&mut self, path: &Path) -> Option<&Path> {
+        let mtime = mtime(path);
+        let fresh = self.covers
+            .get(path)
+            .map(|(cached, _)| *cached == mtime)
+            .unwrap_or(false);
+        if !fresh {
+            self.covers.insert(path.to_owned(), (mtime, find_cover(path)));
+        }
+        self.covers[path].1.as_deref()
+    }
+
+    /// Adds metadata read elsewhere (by [`read_in_background`]).
+    pub(crate) fn add(&mut self, batch: Batch) {
+        for (path, mtime, meta) in batch {
+            self.entries.insert(path, (mtime, meta));
+        }
+    }
+
+    /// Returns the metadata if it was read before, without touching the disk.
+    pub(crate) fn cached(&self, path: &Path) -> Option<&Metadata> {
+        self.entries.get(path).map(|(_, meta)| meta)
+    }
+}
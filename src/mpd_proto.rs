@@ -0,0 +1,567 @@
+//! A subset of the MPD protocol, so MPD clients (like `mpc` or ncmpcpp) can control the player.
+//!
+//! The loaded songs are the MPD playlist, their indices being both the positions and the ids.
+//! Nothing in the player announces changes, so `idle` looks at its state periodically.
+
+use std::fmt::Display;
+use std::io::{BufReader, Error as IoError, Write};
+use std::path::{Path, PathBuf};
+use std::slice;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use corona::io::BlockingWrapper;
+use corona::prelude::*;
+use failure::Error;
+use futures::future::Either;
+use futures::unsync::mpsc::{self, UnboundedReceiver};
+use futures::unsync::oneshot::{self, Sender};
+use futures::{Future, Stream};
+use log::{debug, error, info, warn};
+use tokio::io::AsyncRead;
+use tokio::net::TcpStream;
+use tokio::timer::Delay;
+
+use crate::config;
+use crate::library;
+use crate::lines::Lines;
+use crate::metadata::Metadata;
+use crate::player::{self, Changes, Cmd, LoadMode, Mode, VolumeCmd};
+use crate::response::Reply;
+
+/// The version of the protocol the clients are told.
+const GREETING: &[u8] = b"OK MPD 0.19.0\n";
+
+/// How often `idle` looks for changes.
+const IDLE_POLL: Duration = Duration::from_millis(250);
+
+// Error codes of the protocol
+const ACK_ARG: u32 = 2;
+const ACK_UNKNOWN: u32 = 5;
+const ACK_NO_EXIST: u32 = 50;
+const ACK_SYSTEM: u32 = 52;
+
+/// A failed command, answered by an `ACK` line.
+struct Ack {
+    code: u32,
+    message: String,
+}
+
+impl Ack {
+    fn new(code: u32, message: impl Into<String>) -> Self {
+        Ack {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn args(command: &str) -> Self {
+        Ack::new(ACK_ARG, format!("wrong number of arguments for \"{}\"", command))
+    }
+}
+
+impl From<Error> for Ack {
+    fn from(e: Error) -> Self {
+        Ack::new(ACK_SYSTEM, e.to_string())
+    }
+}
+
+type Handler = fn(&mut Conn, &[String]) -> Result<(), Ack>;
+
+const COMMANDS: &[(&str, Handler)] = &[
+    ("status", status),
+    ("currentsong", current_song),
+    ("play", play),
+    ("playid", play),
+    ("pause", pause),
+    ("setvol", set_volume),
+    ("stop", stop),
+    ("next", next),
+    ("previous", previous),
+    ("add", add),
+    ("clear", clear),
+    ("playlistinfo", playlist_info),
+    ("plchanges", playlist_changes),
+    ("idle", idle),
+    ("ping", ping),
+    ("commands", commands),
+    ("notcommands", ping),
+    ("tagtypes", tag_types),
+    ("urlhandlers", ping),
+];
+
+/// What came from the client.
+enum Input {
+    Line(String),
+    Closed,
+    Timeout,
+}
+
+struct Conn {
+    /// Lines read by a separate coroutine, so `idle` can wait for the next one with a timeout.
+    input: UnboundedReceiver<Result<Vec<u8>, IoError>>,
+    /// The answer being put together.
+    out: Vec<u8>,
+    /// Close the connection once the answer is sent.
+    close: bool,
+}
+
+impl Conn {
+    fn read(&mut self, timeout: Option<Duration>) -> Input {
+        let next = (&mut self.input).into_future();
+        let line = match timeout {
+            None => next.coro_wait().ok().and_then(|(line, _)| line),
+            Some(timeout) => {
+                let delay = Delay::new(Instant::now() + timeout);
+                match next.select2(delay).coro_wait() {
+                    Ok(Either::A(((line, _), _))) => line,
+                    Ok(Either::B(_)) => return Input::Timeout,
+                    Err(_) => None,
+                }
+            }
+        };
+        match line {
+            Some(Ok(line)) => Input::Line(String::from_utf8_lossy(&line).trim().to_owned()),
+            Some(Err(e)) => {
+                warn!("Closing MPD connection: {}", e);
+                Input::Closed
+            }
+            None => Input::Closed,
+        }
+    }
+
+    fn field(&mut self, key: &str, value: impl Display) {
+        let _ = writeln!(self.out, "{}: {}", key, value);
+    }
+
+    fn ack(&mut self, ack: &Ack, num: usize, line: &str) {
+        let name = line.split_whitespace().next().unwrap_or_default();
+        let _ = writeln!(self.out, "ACK [{}@{}] {{{}}} {}", ack.code, num, name, ack.message);
+    }
+
+    /// Describes a song as `playlistinfo` and `currentsong` do.
+    fn song(&mut self, path: &Path, pos: Option<usize>, meta: Option<&Metadata>) {
+        self.field("file", uri(path));
+        if let Some(meta) = meta {
+            let fields = [("Title", &meta.title), ("Artist", &meta.artist), ("Album", &meta.album)];
+            for (key, value) in &fields {
+                if let Some(value) = value {
+                    self.field(key, value);
+                }
+            }
+            if let Some(track) = meta.track {
+                self.field("Track", track);
+            }
+            if let Some(genre) = &meta.genre {
+                self.field("Genre", genre);
+            }
+            if let Some(year) = meta.year {
+                self.field("Date", year);
+            }
+            if let Some(duration) = meta.duration {
+                self.field("Time", duration / 1000);
+                self.field("duration", f64::from(duration) / 1000.0);
+            }
+        }
+        if let Some(pos) = pos {
+            self.field("Pos", pos);
+            self.field("Id", pos);
+        }
+    }
+
+    fn run(&mut self, line: &str) -> Result<(), Ack> {
+        let mut words = tokenize(line)?;
+        if words.is_empty() {
+            return Err(Ack::new(ACK_UNKNOWN, "No command given"));
+        }
+        let name = words.remove(0);
+        let handler = COMMANDS
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, handler)| *handler)
+            .ok_or_else(|| Ack::new(ACK_UNKNOWN, format!("unknown command \"{}\"", name)))?;
+        debug!("MPD command {}", line);
+        handler(self, &words)
+    }
+
+    /// Runs the commands between `command_list_begin` and `command_list_end`, up to the first
+    /// failing one.
+    fn command_list(&mut self, list_ok: bool) {
+        let mut commands = Vec::new();
+        loop {
+            match self.read(None) {
+                Input::Line(ref line) if line == "command_list_end" => break,
+                Input::Line(line) => commands.push(line),
+                Input::Closed | Input::Timeout => {
+                    self.close = true;
+                    return;
+                }
+            }
+        }
+        for (num, line) in commands.iter().enumerate() {
+            if let Err(ack) = self.run(line) {
+                self.ack(&ack, num, line);
+                return;
+            }
+            if list_ok {
+                self.out.extend_from_slice(b"list_OK\n");
+            }
+        }
+        self.out.extend_from_slice(b"OK\n");
+    }
+
+    fn handle(&mut self, line: &str) {
+        match line {
+            "close" => self.close = true,
+            // Not idling (any more), nothing to stop
+            "noidle" => (),
+            "command_list_begin" | "command_list_ok_begin" => {
+                self.command_list(line == "command_list_ok_begin")
+            }
+            _ => match self.run(line) {
+                Ok(()) if self.close => (),
+                Ok(()) => self.out.extend_from_slice(b"OK\n"),
+                Err(ack) => self.ack(&ack, 0, line),
+            },
+        }
+    }
+}
+
+/// Splits the command into words. Quoted ones may contain spaces and `\"` or `\\`.
+fn tokenize(line: &str) -> Result<Vec<String>, Ack> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let mut word = String::new();
+        match chars.next() {
+            None => return Ok(words),
+            Some('"') => loop {
+                match chars.next() {
+                    Some('"') => break,
+                    Some('\\') => word.extend(chars.next()),
+                    Some(c) => word.push(c),
+                    None => return Err(Ack::new(ACK_ARG, "Missing closing '\"'")),
+                }
+            },
+            Some(c) => {
+                word.push(c);
+                while let Some(c) = chars.peek().cloned().filter(|c| !c.is_whitespace()) {
+                    word.push(c);
+                    chars.next();
+                }
+            }
+        }
+        words.push(word);
+    }
+}
+
+fn number<T: FromStr>(args: &[String], idx: usize) -> Result<Option<T>, Ack> {
+    match args.get(idx) {
+        Some(arg) => match arg.parse() {
+            Ok(num) => Ok(Some(num)),
+            Err(_) => Err(Ack::new(ACK_ARG, format!("Integer expected: {}", arg))),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Parses a `<pos>` or `<start>:<end>` range (the end may be left out).
+fn range(arg: &str) -> Result<(usize, usize), Ack> {
+    let bad = || Ack::new(ACK_ARG, format!("Bad range: {}", arg));
+    let parse = |num: &str| num.parse::<usize>().map_err(|_| bad());
+    match arg.find(':') {
+        Some(pos) if pos + 1 == arg.len() => Ok((parse(&arg[..pos])?, usize::MAX)),
+        Some(pos) => Ok((parse(&arg[..pos])?, parse(&arg[pos + 1..])?)),
+        None => {
+            let pos = parse(arg)?;
+            Ok((pos, pos.checked_add(1).ok_or_else(bad)?))
+        }
+    }
+}
+
+/// How the client sees the song, relative to `--mpd-music-dir` if it's inside.
+fn uri(path: &Path) -> String {
+    let relative = config::get()
+        .mpd_music_dir
+        .as_ref()
+        .and_then(|dir| path.strip_prefix(dir).ok());
+    relative.unwrap_or(path).to_string_lossy().into_owned()
+}
+
+/// The path of a song the client sent.
+fn resolve(uri: &str) -> PathBuf {
+    match &config::get().mpd_music_dir {
+        // An absolute path stays as it is
+        Some(dir) => dir.join(uri),
+        None => PathBuf::from(uri),
+    }
+}
+
+fn query<T, F>(cmd: F) -> Result<T, Ack>
+where
+    F: FnOnce(Sender<T>) -> Cmd,
+{
+    let (sender, receiver) = oneshot::channel();
+    player::send(cmd(sender));
+    receiver
+        .coro_wait()
+        .map_err(|_| Ack::new(ACK_SYSTEM, "player didn't answer"))
+}
+
+fn reply<F>(cmd: F) -> Result<(), Ack>
+where
+    F: FnOnce(Sender<Reply>) -> Cmd,
+{
+    query(cmd)?.map(|_| ()).map_err(Ack::from)
+}
+
+fn status(conn: &mut Conn, _: &[String]) -> Result<(), Ack> {
+    let state = query(Cmd::GetState)?;
+    let random = state.mode == Mode::Random || state.mode == Mode::RandomAlbum;
+    conn.field("volume", state.volume.round());
+    conn.field("repeat", (state.mode == Mode::Circular) as u8);
+    conn.field("random", random as u8);
+    conn.field("single", 0);
+    conn.field("consume", 0);
+    conn.field("playlist", state.loads);
+    conn.field("playlistlength", state.songs);
+    let playing = match &state.current {
+        Some(_) if state.paused => "pause",
+        Some(_) => "play",
+        None => "stop",
+    };
+    conn.field("state", playing);
+    if let Some((_, Some(pos), _)) = &state.current {
+        conn.field("song", pos);
+        conn.field("songid", pos);
+    }
+    if let Some(elapsed) = state.elapsed {
+        let duration = state.duration.unwrap_or(0.0);
+        conn.field("time", format!("{}:{}", elapsed.round(), duration.round()));
+        conn.field("elapsed", format!("{:.3}", elapsed));
+    }
+    if let Some(duration) = state.duration {
+        conn.field("duration", format!("{:.3}", duration));
+    }
+    Ok(())
+}
+
+fn current_song(conn: &mut Conn, _: &[String]) -> Result<(), Ack> {
+    if let Some((song, pos, meta)) = query(Cmd::GetState)?.current {
+        conn.song(&song, pos, Some(&meta));
+    }
+    Ok(())
+}
+
+fn play(_: &mut Conn, args: &[String]) -> Result<(), Ack> {
+    let pos = match number::<usize>(args, 0)? {
+        Some(pos) => pos,
+        None => {
+            player::send(Cmd::Resume);
+            return Ok(());
+        }
+    };
+    query(|sender| Cmd::Position(Some(pos), sender))?
+        .map_err(|e| Ack::new(ACK_ARG, e.to_string()))?;
+    // The position only picks the next song
+    reply(Cmd::Next)
+}
+
+fn pause(_: &mut Conn, args: &[String]) -> Result<(), Ack> {
+    let cmd = match number::<u8>(args, 0)? {
+        None => Cmd::Play,
+        Some(0) => Cmd::Resume,
+        Some(_) => Cmd::Pause,
+    };
+    player::send(cmd);
+    Ok(())
+}
+
+fn set_volume(_: &mut Conn, args: &[String]) -> Result<(), Ack> {
+    let volume = number::<u8>(args, 0)?.ok_or_else(|| Ack::new(ACK_ARG, "Missing volume"))?;
+    reply(|sender| Cmd::Volume(VolumeCmd::Set(f64::from(volume)), sender))
+}
+
+fn stop(_: &mut Conn, _: &[String]) -> Result<(), Ack> {
+    player::send(Cmd::Stop);
+    Ok(())
+}
+
+fn next(_: &mut Conn, _: &[String]) -> Result<(), Ack> {
+    reply(Cmd::Next)
+}
+
+fn previous(_: &mut Conn, _: &[String]) -> Result<(), Ack> {
+    player::send(Cmd::Prev);
+    Ok(())
+}
+
+fn add(_: &mut Conn, args: &[String]) -> Result<(), Ack> {
+    let path = match args {
+        [uri] => resolve(uri),
+        _ => return Err(Ack::args("add")),
+    };
+    let songs = library::scan(slice::from_ref(&path), config::get().playlist_order).songs;
+    if songs.is_empty() {
+        return Err(Ack::new(ACK_NO_EXIST, "No such song"));
+    }
+    reply(|sender| Cmd::Load {
+        songs,
+        sources: vec![path],
+        order: config::get().playlist_order,
+        how: LoadMode::Append,
+        clear_history: false,
+        sender: Some(sender),
+    })
+}
+
+fn clear(_: &mut Conn, _: &[String]) -> Result<(), Ack> {
+    player::send(Cmd::Stop);
+    reply(|sender| Cmd::Load {
+        songs: Vec::new(),
+        sources: Vec::new(),
+        order: config::get().playlist_order,
+        how: LoadMode::Replace,
+        clear_history: false,
+        sender: Some(sender),
+    })
+}
+
+fn playlist_info(conn: &mut Conn, args: &[String]) -> Result<(), Ack> {
+    let (start, end) = match args.first() {
+        Some(arg) => range(arg)?,
+        None => (0, usize::MAX),
+    };
+    let songs = query(Cmd::Songs)?;
+    if args.len() == 1 && start >= songs.len() {
+        return Err(Ack::new(ACK_ARG, "Bad song index"));
+    }
+    for (pos, (song, meta)) in songs.iter().enumerate().take(end).skip(start) {
+        conn.song(song, Some(pos), meta.as_ref());
+    }
+    Ok(())
+}
+
+/// The whole playlist if it changed since the version, nothing otherwise.
+fn playlist_changes(conn: &mut Conn, args: &[String]) -> Result<(), Ack> {
+    let version = number::<u64>(args, 0)?.ok_or_else(|| Ack::args("plchanges"))?;
+    if query(Cmd::GetState)?.loads != version {
+        playlist_info(conn, &[])?;
+    }
+    Ok(())
+}
+
+/// The subsystems that changed between the two states.
+fn changes(before: &Changes, now: &Changes) -> Vec<&'static str> {
+    let mut changes = Vec::new();
+    let started = before.starts != now.starts || before.current != now.current;
+    if started || before.paused != now.paused {
+        changes.push("player");
+    }
+    if before.loads != now.loads {
+        changes.push("playlist");
+    }
+    if before.mode != now.mode {
+        changes.push("options");
+    }
+    changes
+}
+
+/// Waits until something the client is interested in changes, or until `noidle`.
+fn idle(conn: &mut Conn, args: &[String]) -> Result<(), Ack> {
+    let wanted = |subsystem: &str| args.is_empty() || args.iter().any(|arg| arg == subsystem);
+    let before = query(Cmd::GetChanges)?;
+    loop {
+        match conn.read(Some(IDLE_POLL)) {
+            Input::Line(ref line) if line == "noidle" => return Ok(()),
+            Input::Line(line) => {
+                warn!("Closing MPD connection, got {} while idle", line);
+                conn.close = true;
+                return Ok(());
+            }
+            Input::Closed => {
+                conn.close = true;
+                return Ok(());
+            }
+            Input::Timeout => {
+                let now = query(Cmd::GetChanges)?;
+                let changed = changes(&before, &now)
+                    .into_iter()
+                    .filter(|subsystem| wanted(subsystem))
+                    .collect::<Vec<_>>();
+                if !changed.is_empty() {
+                    for subsystem in changed {
+                        conn.field("changed", subsystem);
+                    }
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+fn ping(_: &mut Conn, _: &[String]) -> Result<(), Ack> {
+    Ok(())
+}
+
+fn commands(conn: &mut Conn, _: &[String]) -> Result<(), Ack> {
+    let special = ["close", "noidle", "command_list_begin", "command_list_ok_begin"];
+    let names = COMMANDS.iter().map(|(name, _)| *name).chain(special.iter().cloned());
+    for name in names {
+        conn.field("command", name);
+    }
+    Ok(())
+}
+
+fn tag_types(conn: &mut Conn, _: &[String]) -> Result<(), Ack> {
+    for tag in &["Artist", "Album", "Title", "Track", "Genre", "Date"] {
+        conn.field("tagtype", tag);
+    }
+    Ok(())
+}
+
+/// Serves an MPD client until it disconnects.
+pub(crate) fn handle_conn(conn: TcpStream) {
+    let peer = conn
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_else(|_| "unknown".to_owned());
+    info!("Accepted an MPD connection from {}", peer);
+    let (input, output) = conn.split();
+    let (sender, receiver) = mpsc::unbounded();
+    corona::spawn(move || {
+        let input = BufReader::new(BlockingWrapper::new(input));
+        for line in Lines::new(input, config::get().max_line_length) {
+            if sender.unbounded_send(line).is_err() {
+                break;
+            }
+        }
+    });
+    let mut output = BlockingWrapper::new(output);
+    let mut conn = Conn {
+        input: receiver,
+        out: GREETING.to_vec(),
+        close: false,
+    };
+    let result = catch! {
+        loop {
+            output.write_all(&conn.out)?;
+            output.flush()?;
+            conn.out.clear();
+            if conn.close {
+                break;
+            }
+            match conn.read(None) {
+                Input::Line(line) => conn.handle(&line),
+                Input::Closed | Input::Timeout => break,
+            }
+        }
+    };
+    match result {
+        Ok(()) => info!("MPD connection from {} closed", peer),
+        Err(e) => error!("Error on MPD connection from {}: {}", peer, e),
+    }
+}
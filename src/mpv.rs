@@ -0,0 +1,154 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use corona::io::BlockingWrapper;
+use corona::prelude::*;
+use failure::{err_msg, Error};
+use futures::future::Either;
+use futures::unsync::oneshot::{self, Sender};
+use futures::Future;
+use log::{debug, trace, warn};
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, ReadHalf, WriteHalf};
+use tokio::net::unix::UnixStream;
+use tokio::timer::Delay;
+
+/// How long to wait for mpv to answer a command.
+///
+/// It answers right away when it's fine, but one stopped by a signal (or hung) never does and
+/// it must not block the whole player.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+type Pending = Rc<RefCell<HashMap<u64, Sender<Result<Value, String>>>>>;
+
+/// Gets the messages mpv sends on its own (like changes of observed properties).
+type Events = Box<dyn Fn(&Value)>;
+
+/// The control channel of a running mpv.
+///
+/// This speaks the JSON IPC protocol over the socket passed to mpv with `--input-ipc-client`.
+/// Replies are matched to requests by their `request_id` in a separate coroutine, so the one
+/// asking just waits for the answer.
+pub(crate) struct Mpv {
+    writer: BlockingWrapper<WriteHalf<UnixStream>>,
+    pending: Pending,
+    next_id: u64,
+}
+
+impl Mpv {
+    /// Starts talking to mpv, passing the events from it to the handler.
+    pub(crate) fn new(stream: UnixStream, events: impl Fn(&Value) + 'static) -> Self {
+        let (reader, writer) = stream.split();
+        let pending = Pending::default();
+        let reader_pending = Rc::clone(&pending);
+        let events: Events = Box::new(events);
+        corona::spawn(move || read_replies(reader, &reader_pending, &events));
+        Mpv {
+            writer: BlockingWrapper::new(writer),
+            pending,
+            next_id: 0,
+        }
+    }
+
+    /// Encodes the command with a new `request_id`.
+    fn request(&mut self, args: Value) -> (u64, Vec<u8>) {
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut request = json!({ "command": args, "request_id": id }).to_string().into_bytes();
+        request.push(b'\n');
+        debug!("Sending request {}", String::from_utf8_lossy(&request).trim_end());
+        (id, request)
+    }
+
+    /// Runs a JSON command without waiting for its result.
+    ///
+    /// For commands after which mpv may be gone before answering (like `quit`).
+    pub(crate) fn notify(&mut self, args: Value) {
+        // Nobody waits for the reply, it gets thrown away
+        let (_, request) = self.request(args);
+        // It fails only if mpv terminated, and then there's nobody to tell anything anyway
+        let _ = self.writer.write_all(&request);
+    }
+
+    /// Runs a JSON command and waits for its result (at most for the [`REPLY_TIMEOUT`]).
+    pub(crate) fn command(&mut self, args: Value) -> Result<Value, Error> {
+        let (id, request) = self.request(args);
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending.borrow_mut().insert(id, sender);
+        if let Err(e) = self.writer.write_all(&request) {
+            self.pending.borrow_mut().remove(&id);
+            return Err(e.into());
+        }
+
+        let delay = Delay::new(Instant::now() + REPLY_TIMEOUT);
+        match receiver.select2(delay).coro_wait() {
+            Ok(Either::A((Ok(data), _))) => Ok(data),
+            Ok(Either::A((Err(e), _))) => Err(err_msg(e)),
+            Ok(Either::B(_)) => {
+                // A late answer has nobody to go to
+                self.pending.borrow_mut().remove(&id);
+                Err(err_msg("mpv didn't answer"))
+            }
+            Err(Either::A(_)) => Err(err_msg("mpv terminated")),
+            Err(Either::B((e, _))) => Err(e.into()),
+        }
+    }
+
+    /// Asks mpv to send an event whenever the property changes.
+    ///
+    /// This doesn't wait for the answer, so an mpv that doesn't support it can't block us.
+    pub(crate) fn observe(&mut self, name: &str) {
+        // The id of the request that comes next, unique among the observations too
+        let id = self.next_id;
+        self.notify(json!(["observe_property", id, name]));
+    }
+
+    pub(crate) fn get_property(&mut self, name: &str) -> Result<Value, Error> {
+        self.command(json!(["get_property", name]))
+    }
+
+    pub(crate) fn set_property(&mut self, name: &str, value: impl Into<Value>) -> Result<(), Error> {
+        self.command(json!(["set_property", name, value.into()]))
+            .map(|_| ())
+    }
+}
+
+fn read_replies(reader: ReadHalf<UnixStream>, pending: &Pending, events: &Events) {
+    for line in BufReader::new(BlockingWrapper::new(reader)).split(b'\n') {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                debug!("Control channel of mpv broken: {}", e);
+                break;
+            }
+        };
+        let msg: Value = match serde_json::from_slice(&line) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Malformed message from mpv: {}", e);
+                continue;
+            }
+        };
+        trace!("Message from mpv: {}", msg);
+
+        if let Some(id) = msg.get("request_id").and_then(Value::as_u64) {
+            if let Some(sender) = pending.borrow_mut().remove(&id) {
+                let result = match msg["error"].as_str() {
+                    Some("success") => Ok(msg["data"].clone()),
+                    Some(error) => Err(error.to_owned()),
+                    None => Err("Malformed reply".to_owned()),
+                };
+                let _ = sender.send(result);
+            }
+        } else if msg.get("event").is_some() {
+            events(&msg);
+        }
+    }
+
+    // Nobody is going to answer these any more
+    pending.borrow_mut().clear();
+}
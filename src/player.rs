@@ -1,94 +1,605 @@
-use std::cell::RefCell;
-use std::collections::VecDeque;
-use std::io::{Error as IoError, Write};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::{self, OpenOptions};
+use std::mem;
+use std::io::Error as IoError;
+use std::os::unix::ffi::OsStrExt;
+use std::panic::{self, AssertUnwindSafe};
 use std::os::unix::io::AsRawFd;
 use std::os::unix::process::CommandExt as UnixCommandExt;
 use std::os::unix::net::UnixStream as StdUnixStream;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
-use std::time::{Duration, Instant};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime};
 
-use corona::io::BlockingWrapper;
+use chrono::{DateTime, Local};
 use corona::prelude::*;
-use failure::Error;
-use futures::unsync::oneshot::Sender;
+use failure::{bail, err_msg, format_err, Error, Fail};
+use futures::unsync::oneshot::{self, Sender};
 use futures::unsync::mpsc::{self, UnboundedSender as QueueSender};
-use id3::Tag;
-use log::{debug, error, info};
-use nix::unistd;
-use rand::Rng;
+use log::{debug, error, info, warn};
+use nix::errno::Errno;
+use nix::sys::signal::{self, Signal};
+use nix::sys::wait;
+use nix::unistd::{self, Pid};
+use rand::prng::ChaChaRng;
+use rand::{Rng, SeedableRng};
+use serde_json::{json, Value};
 use tokio::reactor::Handle;
+use tokio::timer::Delay;
 use tokio::net::unix::UnixStream;
-use tokio_process::CommandExt;
+use tokio_process::{Child, CommandExt};
 
-#[derive(Debug, Eq, PartialEq)]
+use crate::bookmark::{self, Bookmarks};
+use crate::config::{self, GroupBy, OnEmpty, PlaylistOrder, SequenceEnd};
+use crate::library;
+use crate::loudness::{self, Loudness};
+use crate::metadata::{self, Cache as MetadataCache, Metadata, TagFilter};
+use crate::mpv::Mpv;
+use crate::response::{Reply, Response};
+use crate::session::Session;
+use crate::probe::ProbeCache;
+use crate::silence::{self, Trim};
+
+const MIN_SPEED: f64 = 0.25;
+const MAX_SPEED: f64 = 4.0;
+/// The loudest `volume` allowed, higher would distort (and mpv refuses over 130 by default).
+const MAX_VOLUME: f64 = 100.0;
+/// How many times the volume is lowered during a fade out.
+const FADE_STEPS: u32 = 20;
+/// How long it takes to get the volume back after ducking.
+const UNDUCK_TIME: Duration = Duration::from_secs(1);
+/// Prev after playing longer than this restarts the current song instead of going back.
+const PREV_RESTART_TIME: Duration = Duration::from_secs(2);
+/// How long terminate waits for mpv to exit.
+const REAP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What went wrong when starting mpv.
+#[derive(Debug)]
+enum StartError {
+    /// Creating or registering the control channel.
+    Channel(IoError),
+    /// Opening `/dev/null` for mpv's standard streams.
+    Null(IoError),
+    /// Duplicating the `/dev/null` descriptor for each of the streams.
+    Dup(IoError),
+    /// Running mpv itself.
+    Spawn(IoError),
+}
+
+impl StartError {
+    /// Ran out of file descriptors (or processes), which is likely to pass.
+    fn transient(&self) -> bool {
+        let e = match self {
+            StartError::Channel(e) | StartError::Null(e) | StartError::Dup(e) => e,
+            StartError::Spawn(e) => e,
+        };
+        let exhausted = [Errno::EMFILE, Errno::ENFILE, Errno::EAGAIN, Errno::ENOMEM];
+        e.raw_os_error().is_some_and(|code| exhausted.contains(&Errno::from_i32(code)))
+    }
+}
+
+impl Display for StartError {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match self {
+            StartError::Channel(e) => {
+                write!(fmt, "Can't create the control channel for mpv: {}", e)
+            }
+            StartError::Null(e) => write!(fmt, "Can't open /dev/null for mpv: {}", e),
+            StartError::Dup(e) => write!(fmt, "Can't duplicate /dev/null for mpv: {}", e),
+            StartError::Spawn(e) => write!(fmt, "Failed to start mpv: {}", e),
+        }
+    }
+}
+
+impl Fail for StartError {}
+
+/// The volume lowered (or raised) for a while by the `duck` command.
+struct Duck {
+    /// Only the restore of the latest duck counts, the older ones were overridden.
+    id: u64,
+    /// Part of the original volume to play at.
+    level: f64,
+    /// The volume of the current mpv before ducking, None until known.
+    base: Option<f64>,
+}
+
+/// What the player does, for the MPD clients (see `--listen-mpd`).
+#[derive(Clone, Debug)]
+pub(crate) struct State {
+    pub(crate) mode: Mode,
+    /// The current song, its index among the loaded ones (unless it's not loaded any more) and
+    /// its tags.
+    pub(crate) current: Option<(PathBuf, Option<usize>, Metadata)>,
+    pub(crate) paused: bool,
+    /// How far the current song is, in seconds.
+    pub(crate) elapsed: Option<f64>,
+    /// The length of the current song in seconds, from the tags or mpv.
+    pub(crate) duration: Option<f64>,
+    pub(crate) songs: usize,
+    /// Changes whenever the loaded songs change.
+    pub(crate) loads: u64,
+    /// The volume in percent, not counting a duck (as the `volume` command reports it).
+    pub(crate) volume: f64,
+}
+
+/// What the MPD clients wait for changes of (by `idle`).
+///
+/// Unlike the whole `State`, getting it doesn't ask mpv anything, so it can be polled often.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct Changes {
+    pub(crate) mode: Mode,
+    pub(crate) current: Option<PathBuf>,
+    pub(crate) paused: bool,
+    /// Changes whenever the loaded songs change.
+    pub(crate) loads: u64,
+    /// Changes whenever a song starts.
+    pub(crate) starts: u64,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub(crate) enum Mode {
     Random,
     Sequence,
     Circular,
+    /// Whole albums (songs in the same directory) in random order, each in track order.
+    RandomAlbum,
+}
+
+impl Mode {
+    pub(crate) const ALL: &'static [Mode] =
+        &[Mode::Random, Mode::Sequence, Mode::Circular, Mode::RandomAlbum];
+
+    pub(crate) fn from_name(name: &[u8]) -> Option<Self> {
+        match name {
+            b"random" => Some(Mode::Random),
+            b"sequence" => Some(Mode::Sequence),
+            b"circular" => Some(Mode::Circular),
+            b"album-random" => Some(Mode::RandomAlbum),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Mode::Random => "random",
+            Mode::Sequence => "sequence",
+            Mode::Circular => "circular",
+            Mode::RandomAlbum => "album-random",
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        Mode::from_name(s.as_bytes()).ok_or_else(|| format!("Unknown mode {}", s))
+    }
+}
+
+/// How many times (including the current one) to go through the songs in the sequence mode.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Repeat {
+    Times(u64),
+    Forever,
+}
+
+impl Display for Repeat {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match self {
+            Repeat::Times(passes) => write!(fmt, "{}", passes),
+            Repeat::Forever => write!(fmt, "inf"),
+        }
+    }
+}
+
+/// What to do with the already loaded songs.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum LoadMode {
+    Append,
+    Replace,
+    /// Replace, but only if nothing is loaded yet.
+    Initial,
+}
+
+/// Songs in the same group belong together (see `--group-by`).
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+enum Group {
+    Directory(PathBuf),
+    Tag(String),
+}
+
+#[derive(Debug)]
+pub(crate) enum ChapterCmd {
+    Next,
+    Prev,
+    Jump(u64),
+}
+
+#[derive(Debug)]
+pub(crate) enum VolumeCmd {
+    Get,
+    Set(f64),
+    /// Change by this many percentage points.
+    Change(f64),
+}
+
+#[derive(Debug)]
+pub(crate) enum RemoveCmd {
+    /// The loaded song with the index.
+    Index(usize),
+    /// All the loaded copies of the song.
+    Path(PathBuf),
+}
+
+#[derive(Debug)]
+pub(crate) enum FilterCmd {
+    List,
+    Clear,
+    Add(TagFilter),
 }
 
 #[derive(Debug)]
 pub(crate) enum Cmd {
     Play,
+    Pause,
+    /// Unlike `Play`, this doesn't pause an already playing song.
+    Resume,
     Stop,
-    Next,
+    Next(Sender<Reply>),
     Prev,
+    Skip(i64),
+    /// Set (or just read) how many times to go through the songs in the sequence mode.
+    Repeat(Option<Repeat>, Sender<Reply>),
     Load {
         songs: Vec<PathBuf>,
-        append: bool,
+        /// What the client asked for (directories aren't expanded), for reloading.
+        sources: Vec<PathBuf>,
+        /// How the songs in the directories were sorted, to sort them the same on reload.
+        order: PlaylistOrder,
+        how: LoadMode,
+        /// Forget the history (unless appending).
+        clear_history: bool,
+        /// Nobody waits for the answer when loading the startup playlist.
+        sender: Option<Sender<Reply>>,
     },
+    /// Replace the loaded songs, forget the queue and the history and play the first song.
+    PlayNow {
+        songs: Vec<PathBuf>,
+        sources: Vec<PathBuf>,
+        sender: Sender<Reply>,
+    },
+    Reload(Sender<Reply>),
+    /// The songs found by the reload, started when this many loads happened.
+    Rescanned(u64, Vec<PathBuf>, Sender<Reply>),
     Mode(Mode),
-    Confirm(Sender<()>),
+    /// Which mode is used now.
+    GetMode(Sender<Reply>),
+    Chapter(ChapterCmd, Sender<Reply>),
+    Chapters(Sender<Reply>),
+    ClearBookmark(PathBuf, Sender<Reply>),
+    Speed(Option<f64>, Sender<Reply>),
+    /// Jump to this percentage of the current song.
+    SeekPercent(f64, Sender<Reply>),
+    /// Jump to this second of the current song, or (when relative) by this many seconds.
+    Seek(f64, bool, Sender<Reply>),
+    /// How long the current song still plays.
+    Remaining(Sender<Reply>),
+    /// Where the current song is and how long it is.
+    Time(Sender<Reply>),
+    /// Mute, unmute or (with None) toggle.
+    Mute(Option<bool>, Sender<Reply>),
+    Volume(VolumeCmd, Sender<Reply>),
+    Find(String, Sender<Reply>),
+    /// Restrict (or stop restricting) which songs are chosen by their tags.
+    Filter(FilterCmd, Sender<Reply>),
+    Info(PathBuf, Sender<Reply>),
+    Peek(Sender<Reply>),
+    Current(Sender<Reply>),
+    /// An overview of the player state.
+    Status(Sender<Reply>),
+    /// The state for the MPD clients.
+    GetState(Sender<State>),
+    /// What the waiting MPD clients look at.
+    GetChanges(Sender<Changes>),
+    /// The loaded songs, with their tags if they are cached.
+    Songs(Sender<Vec<(PathBuf, Option<Metadata>)>>),
+    /// The cover art of the song (the current one with None).
+    Artwork(Option<PathBuf>, Sender<Reply>),
+    /// How long it takes to play all the loaded songs.
+    TotalDuration(Sender<Reply>),
+    /// The tags missing for the total duration were read.
+    TagsRead(metadata::Batch, Sender<Reply>),
+    /// The tags missing for the random picks were read.
+    PickTagsRead(metadata::Batch),
+    Signal(Signal, Sender<Reply>),
+    /// The most recent songs from the history (all of them without a count), the oldest first.
+    History(Option<usize>, Sender<Reply>),
+    /// Keep only this many of the most recent songs in the history.
+    TrimHistory(usize, Sender<Reply>),
+    /// Shuffle the queued songs, with the given seed to get the same order every time.
+    ShuffleQueue(Option<u64>, Sender<Reply>),
+    /// The queued songs in the order they'll play, with the already known metadata.
+    QueueDetail(Sender<Reply>),
+    /// Drop songs from the loaded ones.
+    Remove(RemoveCmd, Sender<Reply>),
+    /// Play the loaded song with the index now, or queue it in the random modes.
+    Goto(usize, Sender<Reply>),
+    /// The loaded songs and the queue, or (with a count) the songs that play next.
+    List(Option<usize>, Sender<Reply>),
+    /// Make this many choices of the next songs right away and queue them.
+    Plan(usize, Sender<Reply>),
+    /// Write the loaded songs (or with true the queue) into an m3u playlist, with the paths
+    /// relative to it if asked to.
+    Export {
+        path: PathBuf,
+        queue: bool,
+        relative: bool,
+        sender: Sender<Reply>,
+    },
+    /// Read or move the index of the next song in the pool.
+    Position(Option<usize>, Sender<Reply>),
+    /// Start (true) or cancel (false) re-reading the tags of all the songs.
+    Reindex(bool, Sender<Reply>),
+    /// The silence in the song was found (see `--trim-silence`).
+    Probed(PathBuf, Result<Trim, Error>),
+    /// The loudness of the song was measured (see `--auto-gain`).
+    Measured(PathBuf, Result<Loudness, Error>),
+    /// Make sure the metadata of the song are up to date (part of reindexing).
+    Refresh(PathBuf, Sender<()>),
+    /// Store a snapshot of the session (see `--save-interval`).
+    Snapshot,
+    /// The title announced by the stream changed (the stream, the title).
+    StreamTitle(PathBuf, String),
+    /// mpv paused or resumed the song started as the given one (counted by `starts`).
+    Paused(u64, bool),
+    /// mpv got further in the song started as the given one (the elapsed seconds).
+    Elapsed(u64, Option<f64>),
+    /// mpv found out the length of the song started as the given one (in seconds).
+    MpvDuration(u64, Option<f64>),
+    /// Change the volume to the given percentage for the given time.
+    Duck(f64, Duration, Sender<Reply>),
+    /// A step in getting the volume back after the duck with the given id.
+    Unduck(u64, u32),
+    /// Stop playing and exit (with true right away, without fading out or waiting for mpv).
+    ///
+    /// Nobody gets the answer unless it is refused, the daemon exits first.
+    Terminate(bool, Sender<Reply>),
+    /// Exit even if mpv is still running (after terminate).
+    Exit,
     Done,
+    /// Try starting again after a failure.
+    Retry,
+    /// Announce the song if it's still the one started as this one (see `starts`).
+    Announce(u64),
+}
+
+impl Cmd {
+    /// Whether the command changes the state of the player (refused when terminating).
+    fn mutates(&self) -> bool {
+        use self::Cmd::*;
+        matches!(
+            self,
+            Play | Pause | Resume | Next(_) | Prev | Skip(_) | Goto(..) | Repeat(..)
+                | Load { .. } | PlayNow { .. } | Reload(_) | Rescanned(..) | Remove(..) | Mode(_)
+                | Chapter(..) | ClearBookmark(..) | Speed(..) | Mute(..) | Volume(..) | Seek(..)
+                | SeekPercent(..) | Filter(..) | Signal(..) | TrimHistory(..) | ShuffleQueue(..)
+                | Plan(..) | Position(Some(_), _) | Reindex(true, _) | Duck(..) | Terminate(..)
+        )
+    }
+
+    /// Answers the command with an error (if anybody waits for the answer).
+    fn refuse(self, error: &'static str) {
+        use self::Cmd::*;
+        let sender = match self {
+            Next(sender)
+            | Repeat(_, sender)
+            | Load {
+                sender: Some(sender),
+                ..
+            }
+            | PlayNow { sender, .. }
+            | Reload(sender)
+            | Rescanned(_, _, sender)
+            | Chapter(_, sender)
+            | ClearBookmark(_, sender)
+            | Speed(_, sender)
+            | SeekPercent(_, sender)
+            | Seek(_, _, sender)
+            | Mute(_, sender)
+            | Volume(_, sender)
+            | Filter(_, sender)
+            | Signal(_, sender)
+            | TrimHistory(_, sender)
+            | ShuffleQueue(_, sender)
+            | Plan(_, sender)
+            | Goto(_, sender)
+            | Remove(_, sender)
+            | Position(_, sender)
+            | Reindex(_, sender)
+            | Duck(_, _, sender)
+            | Terminate(_, sender) => sender,
+            _ => return,
+        };
+        let _ = sender.send(Err(err_msg(error)));
+    }
 }
 
 struct Player {
     mode: Mode,
     songs: Vec<PathBuf>,
-    history: VecDeque<PathBuf>,
+    /// The loaded files and directories, with the order of the songs in them.
+    sources: Vec<(PathBuf, PlaylistOrder)>,
+    /// The played songs with the time they started, the most recent last.
+    history: VecDeque<(PathBuf, SystemTime)>,
     playlist: Vec<PathBuf>,
     current: Option<PathBuf>,
     should_play: bool,
+    /// The current song is paused, as mpv last told.
+    paused: bool,
+    /// How far the current song is (in seconds), as mpv last told.
+    elapsed: Option<f64>,
+    /// The length of the current song (in seconds), as mpv last told.
+    mpv_duration: Option<f64>,
     position: usize,
-    control_pipe: Option<BlockingWrapper<UnixStream>>,
+    repeat: Repeat,
+    /// The position was already randomly chosen (by peeking) and should be used.
+    rolled: bool,
+    /// The tags for the random picks are being read in the background.
+    reading_tags: bool,
+    control_pipe: Option<Mpv>,
     last_start: Option<Instant>,
+    /// The wall clock time of the last start, for the history.
+    started_at: Option<SystemTime>,
+    pid: Option<Pid>,
+    stopping: bool,
+    bookmarks: Bookmarks,
+    /// The silence found in the songs (see `--trim-silence`).
+    trims: ProbeCache<Trim>,
+    /// The loudness measured in the songs (see `--auto-gain`).
+    gains: ProbeCache<Loudness>,
+    speed: f64,
+    muted: bool,
+    /// Set by the `volume` command, mpv's own default until then.
+    volume: Option<f64>,
+    metadata: MetadataCache,
+    /// The `--on-empty` policy was applied since the last song started.
+    on_empty_ran: bool,
+    /// What a playing stream (internet radio) says it's playing now.
+    stream_title: Option<String>,
+    /// Cancels the running reindex when set. Shared with its coroutine, so it is running as long
+    /// as there's another reference.
+    reindex: Option<Rc<Cell<bool>>>,
+    /// Terminating, only waiting for mpv to exit. Holds the answer to terminate, which is never
+    /// sent.
+    draining: Option<Sender<Reply>>,
+    duck: Option<Duck>,
+    /// Only songs matching all of these are chosen.
+    filters: Vec<TagFilter>,
+    /// How many ducks there were, to give each one an id.
+    ducks: u64,
+    /// How many songs were started, to recognize the current one later.
+    starts: u64,
+    /// How many times the loaded songs changed, so MPD clients know to read them again.
+    loads: u64,
+    /// Sum of the known song durations (in milliseconds) and the number of unknown ones.
+    ///
+    /// Reading the tags of all songs is slow, so this is kept until the songs change.
+    total_duration: Option<(u64, usize)>,
 }
 
 impl Player {
     fn new() -> Self {
         Player {
-            mode: Mode::Random,
+            mode: config::get().default_mode,
             songs: Vec::new(),
+            sources: Vec::new(),
             history: VecDeque::new(),
             playlist: Vec::new(),
             current: None,
             should_play: false,
+            paused: false,
+            elapsed: None,
+            mpv_duration: None,
             position: 0,
+            repeat: Repeat::Times(1),
+            rolled: false,
+            reading_tags: false,
             control_pipe: None,
             last_start: None,
+            started_at: None,
+            pid: None,
+            stopping: false,
+            bookmarks: Bookmarks::load(),
+            trims: ProbeCache::load(silence::HOOKS),
+            gains: ProbeCache::load(loudness::HOOKS),
+            speed: 1.0,
+            muted: false,
+            volume: None,
+            metadata: MetadataCache::default(),
+            on_empty_ran: false,
+            stream_title: None,
+            reindex: None,
+            draining: None,
+            duck: None,
+            filters: Vec::new(),
+            ducks: 0,
+            starts: 0,
+            loads: 0,
+            total_duration: None,
         }
     }
 
     fn done(&mut self) {
+        // If mpv exits on its own right after starting, something is wrong with the song (or the
+        // whole directory is on an unreachable network mount). Don't spin through the playlist.
+        let min_play_time = Duration::from_millis(config::get().min_play_time);
+        let failed = !self.stopping && self.played()
+            .map(|played| played < min_play_time)
+            .unwrap_or(false);
+        // Songs skipped right away were not really listened to
+        let min_history_time = Duration::from_millis(config::get().min_history_time);
+        let listened = self.played()
+            .map(|played| played >= min_history_time)
+            .unwrap_or(true);
+
         if let Some(current) = self.current.take() {
-            self.history.push_back(current);
-            while self.history.len() > 100 {
-                self.history.pop_front();
+            // Played to the very end, nothing to resume
+            if !self.stopping {
+                self.bookmarks.clear(&current);
+            }
+            // Streams don't end, it dropped. If it worked for a while, it's worth another try.
+            if self.should_play && !self.stopping && !failed && library::is_url(&current) {
+                info!("Stream {} ended, reconnecting", current.to_string_lossy());
+                self.playlist.push(current);
+            } else if listened {
+                let started = self.started_at.take().unwrap_or_else(SystemTime::now);
+                self.history.push_back((current, started));
+                while self.history.len() > config::get().history_size {
+                    self.history.pop_front();
+                }
+            } else {
+                debug!("Not adding {} to history, played too short", current.to_string_lossy());
             }
         }
 
         self.control_pipe = None;
+        if let Some(duck) = self.duck.as_mut() {
+            // The next song may have a different volume
+            duck.base = None;
+        }
+        self.stream_title = None;
         self.last_start = None;
+        self.pid = None;
+        self.paused = false;
+        self.elapsed = None;
+        self.mpv_duration = None;
+        self.stopping = false;
+        if self.draining.is_some() {
+            // Now the song that played is in the history too
+            self.exit();
+        }
 
         if self.should_play {
-            self.start();
+            if failed {
+                debug!("Song ended too soon");
+                self.retry_later();
+            } else {
+                self.start();
+            }
         }
     }
 
-    fn choose_song(&mut self) -> Option<PathBuf> {
-        if let Some(song) = self.playlist.pop() {
-            return Some(song);
+    /// Decides what plays next, without moving on to it.
+    ///
+    /// In the random modes this rolls the dice and the next `choose_song` sticks to the result.
+    fn peek_next(&mut self) -> Option<PathBuf> {
+        if let Some(song) = self.playlist.last() {
+            return Some(song.clone());
         }
 
         if self.songs.is_empty() {
@@ -96,67 +607,408 @@ impl Player {
         }
 
         match self.mode {
-            Mode::Random => self.position = rand::thread_rng().gen_range(0, self.songs.len()),
+            Mode::Random if !self.rolled => {
+                self.position = self.random_position()?;
+                self.rolled = true;
+            }
+            Mode::RandomAlbum => {
+                self.playlist = self.random_album();
+                return self.playlist.last().cloned();
+            }
             Mode::Sequence if self.position > self.songs.len() => self.position = 0,
+            // Going to start over (see choose_song)
+            Mode::Sequence if self.position == self.songs.len() && self.repeating() => {
+                return self.songs.first().cloned();
+            }
             Mode::Circular if self.position >= self.songs.len() => self.position = 0,
             _ => (),
         }
 
-        let next = self.songs.get(self.position).cloned();
+        self.songs.get(self.position).cloned()
+    }
+
+    /// The sequence is going to start over once it ends.
+    fn repeating(&self) -> bool {
+        match self.repeat {
+            Repeat::Times(passes) => passes > 1,
+            Repeat::Forever => true,
+        }
+    }
+
+    /// Picks a random song, out of those with the right length and tags.
+    ///
+    /// Only the tags already in the cache are looked at, reading them for a whole large
+    /// collection would block the player for a long time. The missing ones are read in the
+    /// background and until they are in, the pick is out of all the songs (and `choose_song`
+    /// checks it).
+    fn random_position(&mut self) -> Option<usize> {
+        let config = config::get();
+        let restricted = !self.filters.is_empty()
+            || config.min_song_length.is_some()
+            || config.max_song_length.is_some();
+        if restricted {
+            let unread = self.songs
+                .iter()
+                .filter(|song| self.metadata.cached(song).is_none())
+                .cloned()
+                .collect::<Vec<_>>();
+            if unread.is_empty() {
+                let allowed = (0..self.songs.len())
+                    .filter(|idx| {
+                        let meta = self.metadata.cached(&self.songs[*idx]);
+                        meta.is_some_and(|meta| {
+                            length_fits(meta.duration)
+                                && self.filters.iter().all(|filter| filter.matches(meta))
+                        })
+                    }).collect::<Vec<_>>();
+                if allowed.is_empty() {
+                    return None;
+                }
+                return Some(allowed[rand::thread_rng().gen_range(0, allowed.len())]);
+            }
+            self.read_pick_tags(unread);
+        }
+        Some(rand::thread_rng().gen_range(0, self.songs.len()))
+    }
+
+    fn read_pick_tags(&mut self, songs: Vec<PathBuf>) {
+        if self.reading_tags {
+            return;
+        }
+        self.reading_tags = true;
+        let batch = metadata::read_in_background(songs);
+        // Even if reading failed, so it's tried again next time
+        corona::spawn(move || send(Cmd::PickTagsRead(batch.coro_wait().unwrap_or_default())));
+    }
+
+    fn choose_song(&mut self) -> Option<PathBuf> {
+        // The random modes pick only from the allowed songs. The others go through them in order
+        // and give up eventually if none of the songs has the right length
+        let attempts = self.songs.len() + self.playlist.len();
+        for _ in 0..=attempts {
+            // Songs queued explicitly play no matter what
+            let queued = !self.playlist.is_empty();
+            let song = self.choose_any_song()?;
+            if queued || (self.length_allowed(&song) && self.filters_allow(&song)) {
+                return Some(song);
+            }
+            debug!("Skipping {}, not the right length or tags", song.to_string_lossy());
+        }
+        None
+    }
+
+    /// Whether the song matches all the filters (set by the `filter` command).
+    fn filters_allow(&mut self, song: &Path) -> bool {
+        if self.filters.is_empty() {
+            return true;
+        }
+        let meta = self.metadata.get(song);
+        self.filters.iter().all(|filter| filter.matches(meta))
+    }
+
+    fn filter(&mut self, cmd: FilterCmd) -> Reply {
+        match cmd {
+            FilterCmd::List => (),
+            FilterCmd::Clear => self.filters.clear(),
+            FilterCmd::Add(filter) => self.filters.push(filter),
+        }
+        let mut response = Response::new();
+        for filter in &self.filters {
+            response.push("filter", filter.to_string());
+        }
+        Ok(response)
+    }
+
+    /// Whether the song fits into `--min-song-length` and `--max-song-length`.
+    fn length_allowed(&mut self, song: &Path) -> bool {
+        let config = config::get();
+        // Not to read the tags for nothing
+        if config.min_song_length.is_none() && config.max_song_length.is_none() {
+            return true;
+        }
+        length_fits(self.metadata.get(song).duration)
+    }
+
+    fn choose_any_song(&mut self) -> Option<PathBuf> {
+        let at_end = self.playlist.is_empty() && self.position >= self.songs.len();
+        if self.mode == Mode::Sequence && at_end && self.repeating() {
+            self.position = 0;
+            if let Repeat::Times(passes) = &mut self.repeat {
+                *passes -= 1;
+            }
+        }
 
-        self.position += 1;
+        let next = self.peek_next();
+
+        if self.playlist.pop().is_none() && !self.songs.is_empty() {
+            self.position += 1;
+            self.rolled = false;
+        }
 
         next
     }
 
+    fn set_mode(&mut self, mode: Mode) {
+        if mode == Mode::Sequence || mode == Mode::Circular {
+            // Go on after the current song, not from wherever the last random pick left the
+            // position
+            let current = self.current.as_ref();
+            if let Some(idx) = current.and_then(|c| self.songs.iter().position(|s| s == c)) {
+                self.position = idx + 1;
+            }
+        }
+        self.rolled = false;
+        self.mode = mode;
+    }
 
-    fn start(&mut self) {
-        if let Some(song) = self.choose_song() {
-            assert!(self.control_pipe.is_none());
-            assert!(self.current.is_none());
+    /// Continues with the session snapshot from the last run, if any.
+    fn restore(&mut self) {
+        if let Some(session) = Session::load() {
+            info!("Restoring {} songs from the last session", session.songs.len());
+            if !config::get().force_default_mode {
+                self.mode = session.mode;
+            }
+            self.position = session.position;
+            let order = config::get().playlist_order;
+            self.sources = session.sources.into_iter().map(|source| (source, order)).collect();
+            self.songs = session.songs;
+            let keep = config::get().history_size;
+            let skip = session.history.len().saturating_sub(keep);
+            self.history = session.history.into_iter().skip(skip).collect();
+        }
+    }
+
+    fn session(&self) -> Session {
+        Session {
+            mode: self.mode,
+            position: self.position,
+            sources: self.sources.iter().map(|(source, _)| source.clone()).collect(),
+            songs: self.songs.clone(),
+            history: self.history.iter().cloned().collect(),
+        }
+    }
+
+    /// The group (album) the song belongs to.
+    fn group_of(&mut self, song: &Path) -> Group {
+        let tag = match config::get().group_by {
+            GroupBy::Directory => None,
+            GroupBy::Album => self.metadata.get(song).album.clone(),
+            GroupBy::Artist => self.metadata.get(song).artist.clone(),
+        };
+        match tag {
+            Some(tag) => Group::Tag(tag),
+            None => Group::Directory(song.parent().map(Path::to_owned).unwrap_or_default()),
+        }
+    }
+
+    /// Picks an album and returns its songs, reversed so they can be popped from the playlist.
+    ///
+    /// The songs are sorted by path, which is usually the track order thanks to numbered file
+    /// names.
+    fn random_album(&mut self) -> Vec<PathBuf> {
+        let songs = self.songs
+            .clone()
+            .into_iter()
+            .filter(|song| self.length_allowed(song) && self.filters_allow(song))
+            .collect::<Vec<_>>();
+        let groups = songs.iter().map(|song| self.group_of(song)).collect::<Vec<_>>();
+        let mut albums = groups.iter().collect::<Vec<_>>();
+        albums.sort();
+        albums.dedup();
+        if albums.is_empty() {
+            return Vec::new();
+        }
+        let album = albums[rand::thread_rng().gen_range(0, albums.len())];
+        let mut songs = songs
+            .iter()
+            .zip(&groups)
+            .filter(|(_, group)| *group == album)
+            .map(|(song, _)| song.clone())
+            .collect::<Vec<_>>();
+        songs.sort();
+        songs.reverse();
+        songs
+    }
 
-            let child = catch! {
-                debug!("Starting mpv with {}", song.to_string_lossy());
+    /// Tries starting a song again after the `--failure-backoff`.
+    fn retry_later(&self) {
+        let backoff = Duration::from_millis(config::get().failure_backoff);
+        debug!("Waiting {:?} before starting again", backoff);
+        corona::spawn(move || {
+            let _ = Delay::new(Instant::now() + backoff).coro_wait();
+            send(Cmd::Retry);
+        });
+    }
 
-                let (sender, receiver) = StdUnixStream::pair()?;
+    /// Runs mpv with the song and returns it with the control channel.
+    fn spawn_mpv(&mut self, song: &Path) -> Result<(Child, UnixStream), StartError> {
+        debug!("Starting mpv with {}", song.to_string_lossy());
 
-                let receiver_fd = receiver.as_raw_fd();
+        let (sender, receiver) = StdUnixStream::pair().map_err(StartError::Channel)?;
 
-                let child = Command::new("/usr/bin/mpv")
-                    .args(&["-really-quiet", "-vo", "null", "--input-ipc-client=fd://4"])
-                    .arg(&song)
-                    .stdin(Stdio::null())
-                    .stdout(Stdio::null())
-                    .stderr(Stdio::null())
-                    .before_exec(move || {
-                        unistd::dup2(receiver_fd, 4)
-                            .map(|_| ())
-                            .map_err(|_| IoError::last_os_error())
-                    }).spawn_async()?;
-
-                let sender = UnixStream::from_std(sender, &Handle::default())?;
-                (child, sender)
-            };
+        let receiver_fd = receiver.as_raw_fd();
+
+        // Opened here instead of using Stdio::null, to tell what failed
+        let null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")
+            .map_err(StartError::Null)?;
+        let null = || null.try_clone().map_err(StartError::Dup);
+
+        let mut cmd = Command::new(&config::get().mpv);
+        cmd.args(&["-really-quiet", "-vo", "null", "--input-ipc-client=fd://4"]);
+        if let Some(profile) = &config::get().mpv_profile {
+            cmd.arg(format!("--profile={}", profile));
+        }
+        let mode_args = config::get().mode_args
+            .iter()
+            .filter(|arg| arg.mode == self.mode)
+            .map(|arg| &arg.arg);
+        cmd.args(mode_args);
+        let trim = self.trim(song);
+        let mut start = self.bookmarks.get(song);
+        if let Some(pos) = start {
+            info!("Resuming {} at {}s", song.to_string_lossy(), pos);
+            cmd.arg(format!("--start={}", pos));
+        } else if trim.start > 0.0 {
+            debug!("Skipping {}s of silence", trim.start);
+            cmd.arg(format!("--start={}", trim.start));
+            start = Some(trim.start);
+        }
+        if let Some(end) = trim.end {
+            cmd.arg(format!("--end={}", end));
+        }
+        if config::get().declick {
+            cmd.arg(self.declick(song, start.unwrap_or(0.0), trim.end));
+        }
+        if let Some(gain) = self.gain(song) {
+            debug!("Playing {} with gain {}dB", song.to_string_lossy(), gain);
+            cmd.arg(format!("--af-add=lavfi=[volume={}dB]", gain));
+        }
+        cmd.arg(format!("--speed={}", self.speed));
+        if self.muted {
+            cmd.arg("--mute=yes");
+        }
+        if let Some(volume) = self.volume {
+            cmd.arg(format!("--volume={}", volume));
+        }
+        let child = cmd
+            .arg(song)
+            .stdin(null()?)
+            .stdout(null()?)
+            .stderr(null()?)
+            .before_exec(move || {
+                unistd::dup2(receiver_fd, 4)
+                    .map(|_| ())
+                    .map_err(|_| IoError::last_os_error())
+            }).spawn_async()
+            .map_err(StartError::Spawn)?;
+
+        let sender = UnixStream::from_std(sender, &Handle::default())
+            .map_err(StartError::Channel)?;
+        Ok((child, sender))
+    }
+
+    /// The silence to skip in the song (see `--trim-silence`).
+    ///
+    /// Until the song is probed, nothing is skipped. The first time, the probe starts in the
+    /// background.
+    fn trim(&mut self, song: &Path) -> Trim {
+        if !config::get().trim_silence || library::is_url(song) {
+            return Trim::default();
+        }
+        let probed = |song, trim| send(Cmd::Probed(song, trim));
+        self.trims.get(song, probed).unwrap_or_default()
+    }
+
+    /// The gain to play the song with (see `--auto-gain`).
+    ///
+    /// Until the song is measured, it plays as it is. The first time, the probe starts in the
+    /// background.
+    fn gain(&mut self, song: &Path) -> Option<f64> {
+        if !config::get().auto_gain || library::is_url(song) {
+            return None;
+        }
+        let measured = |song, loudness| send(Cmd::Measured(song, loudness));
+        self.gains.get(song, measured).map(|loudness| loudness.gain())
+    }
 
-            let info = Tag::read_from_path(&song)
-                .map(|tag| {
-                    let album = tag.album().unwrap_or("???");
-                    let artist = tag.artist().unwrap_or("???");
-                    let title = tag.title().unwrap_or("???");
-                    format!("{} ({}/{})", title, artist, album)
-                }).unwrap_or_else(|_| "???".to_owned());
+    fn start(&mut self) {
+        // Already logged and there's nobody else to tell
+        let _ = self.try_start();
+    }
 
-            println!("• {}\n  {}", info, song.to_string_lossy());
+    /// Starts playing the next song, if there's any.
+    ///
+    /// If mpv can't be started for the lack of file descriptors or processes, the same song is
+    /// tried again after the `--failure-backoff`. Other failures stop the playback.
+    fn try_start(&mut self) -> Result<(), StartError> {
+        if let Some(song) = self.choose_song() {
+            assert!(self.control_pipe.is_none());
+            assert!(self.current.is_none());
 
-            match child {
+            match self.spawn_mpv(&song) {
                 Err(e) => {
-                    error!("Failed to start mpv: {}", e);
-                    self.should_play = false;
+                    if e.transient() {
+                        warn!("{}", e);
+                        self.playlist.push(song);
+                        self.retry_later();
+                    } else {
+                        error!("{}", e);
+                        self.should_play = false;
+                    }
+                    return Err(e);
                 }
                 Ok((child, control)) => {
-                    self.control_pipe = Some(BlockingWrapper::new(control));
+                    let info = self.metadata.get(&song).describe();
+
+                    println!("• {}\n  {}", info, song.to_string_lossy());
+
+                    let start = self.starts + 1;
+                    // Radios change the title without changing the song
+                    let stream = Some(song.clone()).filter(|song| library::is_url(song));
+                    let observe_title = stream.is_some();
+                    let mut control = Mpv::new(control, move |event| {
+                        if event["event"] != "property-change" {
+                            return;
+                        }
+                        match (event["name"].as_str(), &stream) {
+                            (Some("pause"), _) => {
+                                if let Some(paused) = event["data"].as_bool() {
+                                    send(Cmd::Paused(start, paused));
+                                }
+                            }
+                            (Some("time-pos"), _) => {
+                                send(Cmd::Elapsed(start, event["data"].as_f64()));
+                            }
+                            (Some("duration"), _) => {
+                                send(Cmd::MpvDuration(start, event["data"].as_f64()));
+                            }
+                            (Some("media-title"), Some(stream)) => {
+                                if let Some(title) = event["data"].as_str() {
+                                    send(Cmd::StreamTitle(stream.clone(), title.to_owned()));
+                                }
+                            }
+                            _ => (),
+                        }
+                    });
+                    control.observe("pause");
+                    control.observe("time-pos");
+                    control.observe("duration");
+                    if observe_title {
+                        control.observe("media-title");
+                    }
+                    self.control_pipe = Some(control);
+                    self.paused = false;
+                    self.apply_duck();
                     self.current = Some(song);
+                    self.on_empty_ran = false;
                     self.last_start = Some(Instant::now());
+                    self.started_at = Some(SystemTime::now());
+                    self.pid = Some(Pid::from_raw(child.id() as i32));
+                    self.starts += 1;
+                    self.announce_later();
 
                     corona::spawn(move || {
                         match child.coro_wait() {
@@ -172,45 +1024,476 @@ impl Player {
                     });
                 }
             }
-        } else {
+        } else if !self.on_empty() {
             info!("Nothing to play");
             self.should_play = false;
         }
+        Ok(())
     }
 
-    fn send_mpv(&mut self, key: &[u8]) {
-        if let Some(control) = self.control_pipe.as_mut() {
-            debug!("Sending command {}", String::from_utf8_lossy(key));
-            // It might fail if the other end terminates, right?
-            let _ = control.write_all(key);
-        } else {
-            debug!("Nowhere to send command {}", String::from_utf8_lossy(key));
+    /// The mpv argument with filters fading the song in and out (see `--declick`).
+    ///
+    /// The fades are placed by the time in the song, so the start is where it's resumed from. The
+    /// end is where the trailing silence starts, if it's skipped.
+    fn declick(&mut self, song: &Path, start: f64, end: Option<f64>) -> String {
+        let time = config::get().declick_time as f64 / 1000.0;
+        let mut filter = format!("--af-add=lavfi=[afade=t=in:st={}:d={}", start, time);
+        let duration = self.metadata.get(song).duration;
+        let end = end.or_else(|| duration.map(|duration| f64::from(duration) / 1000.0));
+        // Without knowing the length, the end is left alone
+        if let Some(end) = end {
+            let end = end - time;
+            if end > start + time {
+                filter.push_str(&format!(",afade=t=out:st={}:d={}", end, time));
+            }
         }
+        filter.push(']');
+        filter
+    }
+
+    fn start_over(&mut self) {
+        debug!("Starting over");
+        self.position = 0;
+        self.rolled = false;
+        self.start();
     }
 
+    /// Handles running out of songs, according to `--on-empty`.
+    ///
+    /// Returns if the playback goes on (possibly later).
+    fn on_empty(&mut self) -> bool {
+        match &config::get().on_empty {
+            OnEmpty::Stop => false,
+            OnEmpty::Loop | OnEmpty::Reshuffle if self.songs.is_empty() => false,
+            // It didn't bring anything new last time (none of the songs may be chosen, or the
+            // command didn't load any)
+            _ if self.on_empty_ran => false,
+            OnEmpty::Loop => {
+                self.on_empty_ran = true;
+                self.start_over();
+                true
+            }
+            OnEmpty::Reshuffle => {
+                self.on_empty_ran = true;
+                rand::thread_rng().shuffle(&mut self.songs);
+                self.start_over();
+                true
+            }
+            OnEmpty::Command(command) => {
+                self.on_empty_ran = true;
+                let child = Command::new("/bin/sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(Stdio::null())
+                    .spawn_async();
+                match child {
+                    Ok(child) => {
+                        // Give it a chance to load more songs before trying again
+                        corona::spawn(move || {
+                            match child.coro_wait() {
+                                Ok(status) if status.success() => (),
+                                Ok(status) => error!("On empty command failed: {}", status),
+                                Err(e) => error!("Error waiting for the on empty command: {}", e),
+                            }
+                            send(Cmd::Retry);
+                        });
+                        true
+                    }
+                    Err(e) => {
+                        error!("Failed to run the on empty command: {}", e);
+                        false
+                    }
+                }
+            }
+        }
+    }
+
+    /// Announces the current song once it plays for the `--announce-delay`.
+    ///
+    /// Songs skipped before that are never announced.
+    fn announce_later(&mut self) {
+        let config = config::get();
+        if config.announce_command.is_none() {
+            return;
+        }
+        let delay = config.announce_delay;
+        if delay == 0 {
+            if let Some(song) = self.current.clone() {
+                self.announce(&song);
+            }
+            return;
+        }
+        let start = self.starts;
+        corona::spawn(move || {
+            let _ = Delay::new(Instant::now() + Duration::from_millis(delay)).coro_wait();
+            send(Cmd::Announce(start));
+        });
+    }
+
+    /// Runs the user's `--announce-command` for a newly started song.
+    ///
+    /// The command runs in the background and whatever it does has no effect on the playback.
+    fn announce(&mut self, song: &Path) {
+        let command = match &config::get().announce_command {
+            Some(command) => command,
+            None => return,
+        };
+        let meta = self.metadata.get(song);
+        let title = self.stream_title.as_ref().or(meta.title.as_ref());
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c")
+            .arg(command)
+            .env("PM_PATH", song)
+            .stdin(Stdio::null());
+        let fields = [
+            ("PM_TITLE", title),
+            ("PM_ARTIST", meta.artist.as_ref()),
+            ("PM_ALBUM", meta.album.as_ref()),
+        ];
+        for (name, value) in &fields {
+            match value {
+                Some(value) => cmd.env(name, value),
+                // Don't let anything leak from our own environment
+                None => cmd.env_remove(name),
+            };
+        }
+        match cmd.spawn_async() {
+            Ok(child) => {
+                corona::spawn(move || match child.coro_wait() {
+                    Ok(status) if status.success() => (),
+                    Ok(status) => error!("Announce command failed: {}", status),
+                    Err(e) => error!("Error waiting for the announce command: {}", e),
+                });
+            }
+            Err(e) => error!("Failed to run the announce command: {}", e),
+        }
+    }
+
+    fn stream_title(&mut self, stream: PathBuf, title: String) {
+        // Late news from a stream that's no longer playing
+        if self.current.as_ref() != Some(&stream) || self.stream_title.as_ref() == Some(&title) {
+            return;
+        }
+        println!("• {}\n  {}", title, stream.to_string_lossy());
+        self.stream_title = Some(title);
+        self.announce(&stream);
+    }
+
+    /// Sends a command to the current mpv (if any) without waiting for the result.
+    fn send_mpv(&mut self, args: Value) {
+        match self.control_pipe.as_mut() {
+            Some(control) => control.notify(args),
+            None => debug!("Nowhere to send command {}", args),
+        }
+    }
+
+    /// Pauses the song (an already paused one stays paused).
     fn pause(&mut self) {
-        self.send_mpv(b"keypress p\n");
+        if let Some(mpv) = self.control_pipe.as_mut() {
+            match mpv.set_property("pause", true) {
+                Ok(()) => self.paused = true,
+                Err(e) => error!("Failed to pause: {}", e),
+            }
+        }
     }
 
-    fn play_pause(&mut self) {
+    /// Unpauses the song, or starts playing if there's none.
+    fn resume(&mut self) {
         self.should_play = true;
-        if self.control_pipe.is_some() {
+        match self.control_pipe.as_mut() {
+            Some(mpv) => match mpv.set_property("pause", false) {
+                Ok(()) => self.paused = false,
+                Err(e) => error!("Failed to resume: {}", e),
+            },
+            None => self.start(),
+        }
+    }
+
+    /// Lowers the volume in steps, blocking the player until done.
+    ///
+    /// Only used before shutting down, so nothing else needs to happen in the meantime.
+    fn fade_out(&mut self, time: Duration) -> Reply {
+        let mpv = match self.control_pipe.as_mut() {
+            Some(mpv) => mpv,
+            None => return Ok(Response::new()),
+        };
+        let volume = mpv.get_property("volume")?.as_f64().unwrap_or(100.0);
+        let step = time / FADE_STEPS;
+        for left in (0..FADE_STEPS).rev() {
+            let _ = Delay::new(Instant::now() + step).coro_wait();
+            mpv.set_property("volume", volume * f64::from(left) / f64::from(FADE_STEPS))?;
+        }
+        Ok(Response::new())
+    }
+
+    /// Stops playing and exits once mpv is gone.
+    ///
+    /// The exit happens here, in the player, so no other command can get in between.
+    fn terminate(&mut self, now: bool, sender: Sender<Reply>) {
+        info!("Terminating");
+        self.draining = Some(sender);
+        let fade = Duration::from_millis(config::get().terminate_fade);
+        let fade = if now { Duration::from_millis(0) } else { fade };
+        // Armed before talking to mpv, so neither an mpv that doesn't answer nor one ignoring
+        // the quit command can keep us alive
+        corona::spawn(move || {
+            let _ = Delay::new(Instant::now() + fade + REAP_TIMEOUT).coro_wait();
+            send(Cmd::Exit);
+        });
+        if fade > Duration::from_millis(0) {
+            if let Err(e) = self.fade_out(fade) {
+                // Not a reason to stay alive
+                warn!("Failed to fade out: {}", e);
+            }
+        }
+        self.stop();
+        if now || self.pid.is_none() {
+            self.exit();
+        }
+    }
+
+    /// Stores the session so the next start continues from here, and exits.
+    ///
+    /// An mpv still running is killed, not to play on its own.
+    fn exit(&self) -> ! {
+        if let Some(pid) = self.pid {
+            debug!("Killing mpv {}", pid);
+            let _ = signal::kill(pid, Signal::SIGKILL);
+            let _ = wait::waitpid(pid, None);
+        }
+        self.session().save();
+        crate::shutdown(crate::exit::CLEAN);
+    }
+
+    /// Changes the volume to a percentage of the current one for a while.
+    ///
+    /// Another duck in the meantime takes over, but the original volume is kept.
+    fn duck(&mut self, percent: f64, time: Duration) -> Reply {
+        self.ducks += 1;
+        let id = self.ducks;
+        let base = self.duck.take().and_then(|duck| duck.base);
+        self.duck = Some(Duck {
+            id,
+            level: percent / 100.0,
+            base,
+        });
+        info!("Ducking the volume to {}% for {:?}", percent, time);
+        self.apply_duck();
+        corona::spawn(move || {
+            let _ = Delay::new(Instant::now() + time).coro_wait();
+            let step = UNDUCK_TIME / FADE_STEPS;
+            for done in 1..=FADE_STEPS {
+                send(Cmd::Unduck(id, done));
+                let _ = Delay::new(Instant::now() + step).coro_wait();
+            }
+        });
+        Ok(Response::new())
+    }
+
+    /// Sets the volume of the current mpv to the ducked level (if ducking).
+    fn apply_duck(&mut self) {
+        let (duck, mpv) = match (self.duck.as_mut(), self.control_pipe.as_mut()) {
+            (Some(duck), Some(mpv)) => (duck, mpv),
+            _ => return,
+        };
+        let base = match duck.base {
+            Some(base) => base,
+            None => {
+                let base = mpv.get_property("volume")
+                    .ok()
+                    .and_then(|volume| volume.as_f64())
+                    .unwrap_or(100.0);
+                duck.base = Some(base);
+                base
+            }
+        };
+        if let Err(e) = mpv.set_property("volume", base * duck.level) {
+            warn!("Failed to duck the volume: {}", e);
+        }
+    }
+
+    /// Moves the volume a step back towards the original one, after the duck is over.
+    fn unduck(&mut self, id: u64, step: u32) {
+        let (level, base) = match &self.duck {
+            Some(duck) if duck.id == id => (duck.level, duck.base),
+            // Overridden by another duck
+            _ => return,
+        };
+        if step >= FADE_STEPS {
+            info!("Volume restored after ducking");
+            self.duck = None;
+        }
+        if let (Some(base), Some(mpv)) = (base, self.control_pipe.as_mut()) {
+            let restored = f64::from(step) / f64::from(FADE_STEPS);
+            let volume = base * (level + (1.0 - level) * restored);
+            if let Err(e) = mpv.set_property("volume", volume) {
+                warn!("Failed to restore the volume: {}", e);
+            }
+        }
+    }
+
+    /// Pauses a playing song, resumes a paused one or starts playing if there's none.
+    fn play_pause(&mut self) {
+        if self.control_pipe.is_some() && !self.paused {
+            self.should_play = true;
             self.pause();
         } else {
-            self.start();
+            self.resume();
         }
     }
 
     fn next(&mut self) {
+        // Already logged and there's nobody else to tell
+        let _ = self.try_next();
+    }
+
+    fn try_next(&mut self) -> Result<(), StartError> {
         self.should_play = true;
 
         if self.control_pipe.is_some() {
             self.stop_song();
+            Ok(())
         } else {
-            self.start();
+            self.try_start()
+        }
+    }
+
+    /// The `next` command, with the `--sequence-end` policy after the last song.
+    fn next_cmd(&mut self) -> Reply {
+        let at_end = self.mode == Mode::Sequence
+            && self.playlist.is_empty()
+            && self.position >= self.songs.len()
+            && !self.repeating();
+        if !at_end {
+            self.try_next()?;
+            return Ok(Response::new());
+        }
+        match config::get().sequence_end {
+            SequenceEnd::Stop => {
+                self.try_next()?;
+                Ok(Response::new().with("end", "stop"))
+            }
+            SequenceEnd::Wrap => {
+                self.position = 0;
+                self.rolled = false;
+                self.try_next()?;
+                Ok(Response::new().with("end", "wrap"))
+            }
+            SequenceEnd::Hold => Err(err_msg("at end")),
         }
     }
 
+    /// Moves by several songs forward or (with a negative count) back through the history.
+    ///
+    /// The songs skipped forward are never heard, so they don't go to the history. Going back
+    /// queues the songs from the history, so they play again in the original order (ending
+    /// with the current one).
+    fn skip(&mut self, count: i64) {
+        if count > 0 {
+            // More makes no sense and would take forever
+            let limit = (self.songs.len() + self.playlist.len()) as i64;
+            for _ in 1..count.min(limit) {
+                if self.playlist.pop().is_some() {
+                    continue;
+                }
+                // Stop at the last song instead of falling off the end
+                if self.mode == Mode::Sequence && self.position + 1 >= self.songs.len() {
+                    break;
+                }
+                self.choose_song();
+            }
+            self.next();
+        } else if count < 0 {
+            if let Some(current) = self.current.take() {
+                self.playlist.push(current);
+            }
+            // Clamped at the oldest song in the history
+            let back = count.unsigned_abs().min(self.history.len() as u64);
+            for _ in 0..back {
+                if let Some((song, _)) = self.history.pop_back() {
+                    self.playlist.push(song);
+                }
+            }
+            if !self.playlist.is_empty() {
+                self.next();
+            }
+        }
+    }
+
+    fn load(
+        &mut self,
+        songs: Vec<PathBuf>,
+        sources: Vec<PathBuf>,
+        order: PlaylistOrder,
+        how: LoadMode,
+        clear_history: bool,
+    ) -> Reply {
+        let sources = sources.into_iter().map(|source| (source, order));
+        match how {
+            LoadMode::Append => {
+                self.songs.extend(songs);
+                self.sources.extend(sources);
+            }
+            LoadMode::Initial if !self.songs.is_empty() => {
+                // Replacing everything by accident is too easy otherwise
+                bail!("specify append or replace");
+            }
+            LoadMode::Replace | LoadMode::Initial => {
+                self.songs = songs;
+                self.sources = sources.collect();
+                self.position = 0;
+                self.rolled = false;
+                if clear_history {
+                    self.history.clear();
+                }
+            }
+        }
+        self.total_duration = None;
+        self.loads += 1;
+        println!(
+            ">>> Playlist: {} songs, History: {}, Position: {}",
+            self.songs.len(),
+            self.history.len(),
+            self.position,
+        );
+        Ok(Response::new())
+    }
+
+    fn play_now(&mut self, songs: Vec<PathBuf>, sources: Vec<PathBuf>) -> Reply {
+        let order = config::get().playlist_order;
+        self.songs = songs;
+        self.sources = sources.into_iter().map(|source| (source, order)).collect();
+        self.total_duration = None;
+        self.loads += 1;
+        self.playlist.clear();
+        self.history.clear();
+        self.position = 0;
+        self.rolled = false;
+        let mut response = Response::new().with("songs", self.songs.len().to_string());
+        if let Some(song) = self.songs.first().cloned() {
+            response.add("song", song.as_os_str().as_bytes());
+            add_tags(&mut response, self.metadata.get(&song));
+            // The first one plays whatever the mode (even album-random), the sequence goes on
+            // after it
+            self.playlist.push(song);
+            self.position = 1;
+        }
+        self.should_play = true;
+        if self.control_pipe.is_some() {
+            self.stop_song();
+            // Not to get into the just cleared history once it stops
+            self.current = None;
+        } else {
+            self.try_start()?;
+        }
+        Ok(response)
+    }
+
+    /// How long the current song has been playing.
+    fn played(&self) -> Option<Duration> {
+        self.last_start.map(|start| start.elapsed())
+    }
+
     fn prev(&mut self) {
         if let Some(current) = self.current.take() {
             self.playlist.push(current);
@@ -218,12 +1501,13 @@ impl Player {
 
         // If the current song (we just moved above) played for long enough, restart it. If not,
         // place the previous song before it.
-        let restart = self.last_start.take()
-            .map(|last| Instant::now() - last > Duration::from_secs(2))
+        let restart = self.played()
+            .map(|played| played > PREV_RESTART_TIME)
             .unwrap_or(false);
+        self.last_start = None;
 
         if !restart {
-            if let Some(prev) = self.history.pop_back() {
+            if let Some((prev, _)) = self.history.pop_back() {
                 self.playlist.push(prev);
             }
         }
@@ -241,7 +1525,698 @@ impl Player {
     }
 
     fn stop_song(&mut self) {
-        self.send_mpv(b"quit\n");
+        if self.control_pipe.is_some() {
+            self.save_bookmark();
+            self.stopping = true;
+        }
+        self.send_mpv(json!(["quit"]));
+    }
+
+    fn save_bookmark(&mut self) {
+        let song = match self.current.as_ref() {
+            Some(song) => song.clone(),
+            None => return,
+        };
+        let mpv = match self.control_pipe.as_mut() {
+            Some(mpv) => mpv,
+            None => return,
+        };
+        let duration = mpv.get_property("duration").ok().and_then(|d| d.as_f64());
+        let long = duration
+            .map(|d| d >= bookmark::MIN_DURATION.as_secs() as f64)
+            .unwrap_or(false);
+        if !long {
+            return;
+        }
+        if let Some(pos) = mpv.get_property("time-pos").ok().and_then(|p| p.as_f64()) {
+            debug!("Bookmarking {} at {}s", song.to_string_lossy(), pos);
+            self.bookmarks.set(song, pos);
+        }
+    }
+
+    fn mpv(&mut self) -> Result<&mut Mpv, Error> {
+        self.control_pipe.as_mut().ok_or_else(|| err_msg("no song"))
+    }
+
+    fn chapter_count(&mut self) -> Result<u64, Error> {
+        // The property is unavailable for files without chapters
+        let count = self.mpv()?
+            .get_property("chapters")
+            .ok()
+            .and_then(|count| count.as_u64())
+            .unwrap_or(0);
+        if count == 0 {
+            bail!("no chapters");
+        }
+        Ok(count)
+    }
+
+    fn chapter(&mut self, cmd: &ChapterCmd) -> Reply {
+        let count = self.chapter_count()?;
+        let mpv = self.mpv()?;
+        match *cmd {
+            ChapterCmd::Next => {
+                mpv.command(json!(["add", "chapter", 1]))?;
+            }
+            ChapterCmd::Prev => {
+                mpv.command(json!(["add", "chapter", -1]))?;
+            }
+            ChapterCmd::Jump(chapter) if chapter >= count => {
+                bail!("no chapter {} (there are {})", chapter, count);
+            }
+            ChapterCmd::Jump(chapter) => mpv.set_property("chapter", chapter)?,
+        }
+        Ok(Response::new())
+    }
+
+    fn chapters(&mut self) -> Reply {
+        self.chapter_count()?;
+        let list = self.mpv()?.get_property("chapter-list")?;
+        let list = list.as_array().ok_or_else(|| err_msg("no chapters"))?;
+        let mut response = Response::new();
+        for (idx, chapter) in list.iter().enumerate() {
+            let time = chapter["time"].as_f64().unwrap_or(0.0);
+            let title = chapter["title"].as_str().unwrap_or("");
+            response.push("chapter", format!("{} {:.3} {}", idx, time, title));
+        }
+        Ok(response)
+    }
+
+    fn speed(&mut self, speed: Option<f64>) -> Reply {
+        if let Some(speed) = speed {
+            let speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+            if let Some(mpv) = self.control_pipe.as_mut() {
+                mpv.set_property("speed", speed)?;
+            }
+            self.speed = speed;
+        }
+        Ok(Response::new().with("speed", self.speed.to_string()))
+    }
+
+    fn seek_percent(&mut self, percent: f64) -> Reply {
+        let percent = percent.clamp(0.0, 100.0);
+        self.mpv()?.command(json!(["seek", percent, "absolute-percent"]))?;
+        Ok(Response::new().with("percent", percent.to_string()))
+    }
+
+    fn seek(&mut self, secs: f64, relative: bool) -> Reply {
+        // A negative absolute position would count from the end in mpv
+        let (secs, how) = if relative {
+            (secs, "relative")
+        } else {
+            (secs.max(0.0), "absolute")
+        };
+        self.mpv()?.command(json!(["seek", secs, how]))?;
+        Ok(Response::new())
+    }
+
+    /// The seconds left of the current song, at the current speed.
+    fn remaining(&mut self) -> Reply {
+        let mpv = self.mpv()?;
+        let mut get = |name: &str| -> Result<f64, Error> {
+            let value = mpv.get_property(name)?;
+            value.as_f64().ok_or_else(|| format_err!("Invalid {} {}", name, value))
+        };
+        // Streams don't have any
+        let duration = get("duration").map_err(|_| err_msg("unknown duration"))?;
+        let position = get("time-pos")?;
+        let speed = get("speed")?.max(MIN_SPEED);
+        let remaining = (duration - position).max(0.0) / speed;
+        let remaining = (remaining * 1000.0).round() / 1000.0;
+        Ok(Response::new().with("remaining", remaining.to_string()))
+    }
+
+    fn mute(&mut self, mute: Option<bool>) -> Reply {
+        let muted = mute.unwrap_or(!self.muted);
+        if let Some(mpv) = self.control_pipe.as_mut() {
+            mpv.set_property("mute", muted)?;
+        }
+        self.muted = muted;
+        Ok(Response::new().with("mute", if self.muted { "yes" } else { "no" }))
+    }
+
+    /// The volume songs play at, not counting a duck.
+    fn base_volume(&mut self) -> f64 {
+        if let Some(volume) = self.volume {
+            return volume;
+        }
+        if let Some(base) = self.duck.as_ref().and_then(|duck| duck.base) {
+            return base;
+        }
+        self.control_pipe
+            .as_mut()
+            .and_then(|mpv| mpv.get_property("volume").ok())
+            .and_then(|volume| volume.as_f64())
+            .unwrap_or(100.0)
+    }
+
+    fn volume(&mut self, cmd: VolumeCmd) -> Reply {
+        let volume = match cmd {
+            VolumeCmd::Get => None,
+            VolumeCmd::Set(volume) => Some(volume),
+            VolumeCmd::Change(by) => Some(self.base_volume() + by),
+        };
+        if let Some(volume) = volume {
+            let volume = volume.clamp(0.0, MAX_VOLUME);
+            self.volume = Some(volume);
+            match self.duck.as_mut() {
+                // Stays ducked, but comes back to the new volume
+                Some(duck) => {
+                    duck.base = Some(volume);
+                    self.apply_duck();
+                }
+                None => {
+                    if let Some(mpv) = self.control_pipe.as_mut() {
+                        mpv.set_property("volume", volume)?;
+                    }
+                }
+            }
+        }
+        Ok(Response::new().with("volume", self.base_volume().to_string()))
+    }
+
+    /// Scans the loaded files and directories again, to pick up changes in them.
+    ///
+    /// The scan runs in a separate thread and the player goes on meanwhile, the songs found
+    /// come back as [`Cmd::Rescanned`].
+    fn reload(&mut self, sender: Sender<Reply>) {
+        let scan = library::scan_in_background(self.sources.clone());
+        let loads = self.loads;
+        corona::spawn(move || match scan.coro_wait() {
+            Ok(scan) => send(Cmd::Rescanned(loads, scan.songs, sender)),
+            Err(_) => {
+                let _ = sender.send(Err(err_msg("The scan failed")));
+            }
+        });
+    }
+
+    /// Uses the songs found by [`reload`](Player::reload).
+    ///
+    /// The position stays on the same song, if it's still there.
+    fn rescanned(&mut self, loads: u64, songs: Vec<PathBuf>) -> Reply {
+        // The scan is of the sources that are no longer loaded
+        if loads != self.loads {
+            bail!("Other songs were loaded during the reload");
+        }
+        let (added, removed) = {
+            let old = self.songs.iter().collect::<HashSet<_>>();
+            let new = songs.iter().collect::<HashSet<_>>();
+            self.playlist.retain(|song| new.contains(song));
+            (new.difference(&old).count(), old.difference(&new).count())
+        };
+
+        if let Some(next) = self.songs.get(self.position) {
+            if let Some(position) = songs.iter().position(|song| song == next) {
+                self.position = position;
+            }
+        }
+        self.position = self.position.min(songs.len());
+        self.rolled = false;
+        self.songs = songs;
+        self.total_duration = None;
+        self.loads += 1;
+        info!("Reloaded the songs, {} added, {} removed", added, removed);
+
+        Ok(Response::new()
+            .with("added", added.to_string())
+            .with("removed", removed.to_string()))
+    }
+
+    /// Answers with the sum of the durations of the loaded songs.
+    ///
+    /// The tags not read yet are read in a separate thread first (it takes a while for a large
+    /// collection), the answer comes once they are in the cache.
+    fn total_duration(&mut self, sender: Sender<Reply>) {
+        let (total, unknown) = match self.total_duration {
+            Some(cached) => cached,
+            None => {
+                let unread = self.songs
+                    .iter()
+                    .filter(|song| self.metadata.cached(song).is_none())
+                    .cloned()
+                    .collect::<Vec<_>>();
+                if !unread.is_empty() {
+                    let batch = metadata::read_in_background(unread);
+                    corona::spawn(move || match batch.coro_wait() {
+                        Ok(batch) => send(Cmd::TagsRead(batch, sender)),
+                        Err(_) => {
+                            let _ = sender.send(Err(err_msg("Reading the tags failed")));
+                        }
+                    });
+                    return;
+                }
+                let mut total = 0;
+                let mut unknown = 0;
+                for song in &self.songs {
+                    match self.metadata.cached(song).and_then(|meta| meta.duration) {
+                        Some(duration) => total += u64::from(duration),
+                        None => unknown += 1,
+                    }
+                }
+                self.total_duration = Some((total, unknown));
+                (total, unknown)
+            }
+        };
+        let secs = total / 1000;
+        let length = format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60);
+        let reply = Response::new()
+            .with("duration", secs.to_string())
+            .with("length", length)
+            .with("unknown", unknown.to_string());
+        let _ = sender.send(Ok(reply));
+    }
+
+    fn repeat(&mut self, repeat: Option<Repeat>) -> Reply {
+        if let Some(repeat) = repeat {
+            self.repeat = repeat;
+        }
+        Ok(Response::new().with("repeat", self.repeat.to_string()))
+    }
+
+    fn position(&mut self, position: Option<usize>) -> Reply {
+        if let Some(position) = position {
+            if position >= self.songs.len() {
+                bail!("Position {} out of {} songs", position, self.songs.len());
+            }
+            self.position = position;
+            // Even the random modes take this one next
+            self.rolled = true;
+        }
+        Ok(Response::new()
+            .with("position", self.position.to_string())
+            .with("songs", self.songs.len().to_string()))
+    }
+
+    /// The cover art of the song, the current one if no path is given.
+    fn artwork(&mut self, path: Option<PathBuf>) -> Reply {
+        let song = path.or_else(|| self.current.clone()).ok_or_else(|| err_msg("no song"))?;
+        let cover = self.metadata.cover(&song).ok_or_else(|| err_msg("no artwork"))?;
+        Ok(Response::new().with("cover", cover.as_os_str().as_bytes()))
+    }
+
+    /// The song being played, with the tags.
+    fn current(&mut self) -> Reply {
+        let song = self.current.clone().ok_or_else(|| err_msg("no song"))?;
+        let mut response = Response::new();
+        response.add("song", song.as_os_str().as_bytes());
+        let meta = self.metadata.get(&song);
+        let tagged = meta.duration.is_some();
+        add_tags(&mut response, meta);
+        if let Some(title) = &self.stream_title {
+            response.add("stream-title", title.as_str());
+        }
+        if !tagged {
+            // Not in the tags, but mpv knows once it opens the file
+            let duration = self.mpv()?.get_property("duration").ok().and_then(|d| d.as_f64());
+            if let Some(duration) = duration {
+                response.add("duration", duration.to_string());
+            }
+        }
+        if let Some(pid) = self.pid {
+            response.add("pid", pid.to_string());
+        }
+        Ok(response)
+    }
+
+    /// What the player does, for clients that want it all at once.
+    ///
+    /// Unlike `current`, this doesn't ask mpv anything, so it answers even if mpv hangs.
+    fn status(&mut self) -> Response {
+        let mut response = Response::new();
+        response.add("playing", self.should_play.to_string());
+        if self.control_pipe.is_some() {
+            response.add("paused", self.paused.to_string());
+        }
+        response.add("mode", self.mode.name());
+        response.add("repeat", self.repeat.to_string());
+        for filter in &self.filters {
+            response.push("filter", filter.to_string());
+        }
+        if let Some(song) = self.current.clone() {
+            response.add("song", song.as_os_str().as_bytes());
+            add_tags(&mut response, self.metadata.get(&song));
+        }
+        if let Some(title) = &self.stream_title {
+            response.add("stream-title", title.as_str());
+        }
+        response.add("songs", self.songs.len().to_string());
+        response.add("position", self.position.to_string());
+        response.add("queued", self.playlist.len().to_string());
+        response.add("history", self.history.len().to_string());
+        response
+    }
+
+    /// The elapsed time and the duration of the current song (in seconds), as far as known.
+    ///
+    /// The duration from the tags goes first, mpv knows it only once it opens the file. Both
+    /// come from the observed properties, without waiting for mpv.
+    fn times(&mut self) -> (Option<f64>, Option<f64>) {
+        let from_tags = match self.current.clone() {
+            Some(song) => self.metadata.get(&song).duration,
+            None => None,
+        };
+        let duration = from_tags.map(|duration| f64::from(duration) / 1000.0);
+        (self.elapsed, duration.or(self.mpv_duration))
+    }
+
+    fn time(&mut self) -> Reply {
+        self.mpv()?;
+        let (elapsed, duration) = self.times();
+        let mut response = Response::new();
+        // Right after the start, mpv may not know even where it is
+        if let Some(elapsed) = elapsed {
+            response.add("elapsed", ((elapsed * 1000.0).round() / 1000.0).to_string());
+        }
+        if let Some(duration) = duration {
+            response.add("duration", ((duration * 1000.0).round() / 1000.0).to_string());
+        }
+        Ok(response)
+    }
+
+    fn state(&mut self) -> State {
+        let (elapsed, duration) = self.times();
+        let current = self.current.clone().map(|song| {
+            let index = self.songs.iter().position(|loaded| *loaded == song);
+            let meta = self.metadata.get(&song).clone();
+            (song, index, meta)
+        });
+        State {
+            mode: self.mode,
+            current,
+            paused: self.paused,
+            elapsed,
+            duration,
+            songs: self.songs.len(),
+            loads: self.loads,
+            volume: self.base_volume(),
+        }
+    }
+
+    fn signal(&mut self, signal: Signal) -> Reply {
+        let pid = self.pid.ok_or_else(|| err_msg("no child"))?;
+        debug!("Sending {:?} to mpv {}", signal, pid);
+        signal::kill(pid, signal)?;
+        Ok(Response::new())
+    }
+
+    fn info(&mut self, path: &Path) -> Reply {
+        let mut response = Response::new();
+        add_tags(&mut response, self.metadata.get(path));
+        Ok(response)
+    }
+
+    fn peek(&mut self) -> Reply {
+        let mut response = Response::new();
+        if let Some(song) = self.peek_next() {
+            response.add("song", song.as_os_str().as_bytes());
+            add_tags(&mut response, self.metadata.get(&song));
+        }
+        Ok(response)
+    }
+
+    /// Lists the queued songs in the order they'll play, with their metadata.
+    ///
+    /// Only the metadata already in the cache is used. Reading the tags of many songs would
+    /// block the player for a long time, so songs not seen yet come with just the path.
+    fn queue_detail(&self) -> Reply {
+        let mut response = Response::new();
+        for song in self.playlist.iter().rev() {
+            let mut record = Response::new().with("song", song.as_os_str().as_bytes());
+            if let Some(meta) = self.metadata.cached(song) {
+                add_tags(&mut record, meta);
+            }
+            response.record("queued", record);
+        }
+        Ok(response)
+    }
+
+    /// Drops songs from the loaded ones (the current one plays on).
+    ///
+    /// The position stays on the same song, unless that's the removed one; then it's the one
+    /// after. A removed song that's not loaded any more is taken out of the queue too.
+    fn remove(&mut self, cmd: RemoveCmd) -> Reply {
+        let indices = match &cmd {
+            RemoveCmd::Index(idx) if *idx < self.songs.len() => vec![*idx],
+            RemoveCmd::Index(idx) => bail!("Position {} out of {} songs", idx, self.songs.len()),
+            RemoveCmd::Path(path) => {
+                let indices = self.songs
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, song)| song == &path)
+                    .map(|(idx, _)| idx)
+                    .collect::<Vec<_>>();
+                if indices.is_empty() {
+                    bail!("Not loaded: {}", path.to_string_lossy());
+                }
+                indices
+            }
+        };
+        let before = indices.iter().filter(|idx| **idx < self.position).count();
+        // The random choice is gone
+        if indices.contains(&self.position) {
+            self.rolled = false;
+        }
+        let mut removed = Vec::new();
+        for idx in indices.iter().rev() {
+            removed.push(self.songs.remove(*idx));
+        }
+        self.position -= before;
+        let songs = &self.songs;
+        self.playlist.retain(|song| !removed.contains(song) || songs.contains(song));
+        self.total_duration = None;
+        self.loads += 1;
+        info!("Removed {} songs", removed.len());
+        Ok(Response::new().with("removed", removed.len().to_string()))
+    }
+
+    /// Starts the loaded song with the index right away and goes on after it in the sequence and
+    /// circular modes. The random modes don't go on from anywhere, so the song is queued after
+    /// the already queued ones instead.
+    fn goto(&mut self, index: usize) -> Reply {
+        let song = self.songs.get(index).cloned().ok_or_else(|| {
+            format_err!("Position {} out of {} songs", index, self.songs.len())
+        })?;
+        match self.mode {
+            Mode::Sequence | Mode::Circular => {
+                let before = (self.playlist.clone(), self.position, self.should_play);
+                // Before the queued songs
+                self.playlist.push(song.clone());
+                self.position = index + 1;
+                if let Err(e) = self.try_next() {
+                    // Not to play it (or go on from it) later as a surprise
+                    let (playlist, position, should_play) = before;
+                    self.playlist = playlist;
+                    self.position = position;
+                    self.should_play = should_play;
+                    return Err(e.into());
+                }
+            }
+            Mode::Random | Mode::RandomAlbum => self.playlist.insert(0, song.clone()),
+        }
+        Ok(Response::new().with("song", song.as_os_str().as_bytes()))
+    }
+
+    fn history(&self, count: Option<usize>) -> Response {
+        let skip = count.map_or(0, |count| self.history.len().saturating_sub(count));
+        let mut response = Response::new();
+        for (song, started) in self.history.iter().skip(skip) {
+            let started = DateTime::<Local>::from(*started).format("%Y-%m-%dT%H:%M:%S%:z");
+            let mut entry = format!("{} ", started).into_bytes();
+            entry.extend_from_slice(song.as_os_str().as_bytes());
+            response.push("played", entry);
+        }
+        response
+    }
+
+    fn list(&mut self, upcoming: Option<usize>) -> Reply {
+        let mut response = Response::new();
+        if let Some(count) = upcoming {
+            for song in self.upcoming(count) {
+                response.push("song", song.as_os_str().as_bytes());
+            }
+            return Ok(response);
+        }
+        response.add("position", self.position.to_string());
+        for (idx, song) in self.songs.iter().enumerate() {
+            let mut entry = format!("{} ", idx).into_bytes();
+            entry.extend_from_slice(song.as_os_str().as_bytes());
+            response.push("song", entry);
+        }
+        for song in self.playlist.iter().rev() {
+            response.push("queued", song.as_os_str().as_bytes());
+        }
+        Ok(response)
+    }
+
+    /// The next songs to play, as far as they are known without making any random choices.
+    ///
+    /// That's the queue, followed by the loaded songs from the position in the sequence and
+    /// circular modes.
+    fn upcoming(&mut self, count: usize) -> Vec<PathBuf> {
+        let mut upcoming = self.playlist.iter().rev().take(count).cloned().collect::<Vec<_>>();
+        let total = self.songs.len();
+        let (mut idx, wrap) = match self.mode {
+            Mode::Sequence if self.position > total => (0, self.repeating()),
+            Mode::Sequence => (self.position, self.repeating()),
+            Mode::Circular if self.position >= total => (0, true),
+            Mode::Circular => (self.position, true),
+            _ => return upcoming,
+        };
+        // Each song at most once, even if wrapping around
+        for _ in 0..total {
+            if upcoming.len() >= count {
+                break;
+            }
+            if idx >= total {
+                if !wrap {
+                    break;
+                }
+                idx = 0;
+            }
+            let song = self.songs[idx].clone();
+            if self.length_allowed(&song) && self.filters_allow(&song) {
+                upcoming.push(song);
+            }
+            idx += 1;
+        }
+        upcoming
+    }
+
+    /// Queues the next songs the current mode would choose, after the already queued ones.
+    ///
+    /// The choices are made now, so what's queued is what plays (unless the queue changes).
+    fn plan(&mut self, count: usize) -> Reply {
+        let queued = mem::take(&mut self.playlist);
+        let mut planned = Vec::new();
+        while planned.len() < count {
+            match self.choose_song() {
+                Some(song) => planned.push(song),
+                None => break,
+            }
+        }
+        let mut response = Response::new();
+        for song in &planned {
+            response.push("song", song.as_os_str().as_bytes());
+        }
+        // It's a stack; the rest of an album being played in the album-random mode goes last
+        self.playlist.extend(planned.into_iter().rev());
+        self.playlist.extend(queued);
+        Ok(response)
+    }
+
+    fn reindex(&mut self, start: bool) -> Reply {
+        let running = self.reindex
+            .as_ref()
+            .filter(|cancel| Rc::strong_count(cancel) > 1 && !cancel.get());
+        match (start, running) {
+            (true, Some(_)) => bail!("already reindexing"),
+            (false, None) => bail!("not reindexing"),
+            (false, Some(cancel)) => {
+                cancel.set(true);
+                return Ok(Response::new());
+            }
+            (true, None) => (),
+        }
+
+        let cancel = Rc::new(Cell::new(false));
+        self.reindex = Some(Rc::clone(&cancel));
+        self.total_duration = None;
+        let songs = self.songs.clone();
+        let count = songs.len();
+        // One song at a time, so other commands get their turn in between
+        corona::spawn(move || {
+            for (idx, song) in songs.into_iter().enumerate() {
+                if cancel.get() {
+                    info!("Reindexing cancelled after {} of {} songs", idx, count);
+                    return;
+                }
+                if idx > 0 && idx % 1000 == 0 {
+                    info!("Reindexed {} of {} songs", idx, count);
+                }
+                let (sender, receiver) = oneshot::channel();
+                send(Cmd::Refresh(song, sender));
+                if receiver.coro_wait().is_err() {
+                    return;
+                }
+            }
+            info!("Reindexed all {} songs", count);
+        });
+        Ok(Response::new().with("songs", count.to_string()))
+    }
+
+    /// Writes the loaded songs (or the queue, in the order it'll play) as an m3u playlist.
+    ///
+    /// The paths are absolute, or relative to the playlist if asked to and they are inside its
+    /// directory. Songs with already read tags get an `#EXTINF` line.
+    fn export(&self, path: &Path, queue: bool, relative: bool) -> Reply {
+        let songs = if queue {
+            self.playlist.iter().rev().collect::<Vec<_>>()
+        } else {
+            self.songs.iter().collect()
+        };
+        let cwd = env::current_dir()?;
+        let dir = cwd.join(path.parent().unwrap_or_else(|| Path::new("")));
+        let mut data = b"#EXTM3U\n".to_vec();
+        for song in &songs {
+            if let Some(meta) = self.metadata.cached(song) {
+                let secs = meta.duration.map_or(-1, |duration| i64::from(duration / 1000));
+                let title = match (&meta.artist, &meta.title) {
+                    (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+                    (None, Some(title)) => title.clone(),
+                    (_, None) => song.file_stem().unwrap_or_default().to_string_lossy().into(),
+                };
+                // Tags may contain anything, but it must stay on a single line
+                let title = title.replace(|c: char| c.is_control(), " ");
+                data.extend_from_slice(format!("#EXTINF:{},{}\n", secs, title).as_bytes());
+            }
+            let written = if library::is_url(song) {
+                song.to_path_buf()
+            } else {
+                let absolute = cwd.join(song);
+                match absolute.strip_prefix(&dir) {
+                    Ok(inside) if relative => inside.to_owned(),
+                    _ => absolute,
+                }
+            };
+            data.extend_from_slice(written.as_os_str().as_bytes());
+            data.push(b'\n');
+        }
+        fs::write(path, data)
+            .map_err(|e| format_err!("Can't write {}: {}", path.to_string_lossy(), e))?;
+        info!("Exported {} songs into {}", songs.len(), path.to_string_lossy());
+        Ok(Response::new().with("songs", songs.len().to_string()))
+    }
+
+    /// Looks for songs in the pool by a (lowercase) part of the path or tags.
+    ///
+    /// Only already cached tags are considered, reading tags of the whole pool would take ages.
+    fn find(&self, needle: &str) -> Reply {
+        let limit = config::get().find_limit;
+        let matches = self.songs
+            .iter()
+            .enumerate()
+            .filter(|(_, song)| {
+                let in_path = song.to_string_lossy().to_lowercase().contains(needle);
+                in_path || self.metadata
+                    .cached(song)
+                    .map(|meta| meta.texts().any(|t| t.to_lowercase().contains(needle)))
+                    .unwrap_or(false)
+            });
+
+        let mut response = Response::new();
+        let mut total = 0;
+        for (idx, song) in matches {
+            if total < limit {
+                let mut entry = format!("{} ", idx).into_bytes();
+                entry.extend_from_slice(song.as_os_str().as_bytes());
+                response.push("song", entry);
+            }
+            total += 1;
+        }
+        if total > limit {
+            response.add("truncated", total.to_string());
+        }
+        Ok(response)
     }
 
     fn cmd(&mut self, cmd: Cmd) {
@@ -249,45 +2224,312 @@ impl Player {
 
         debug!("Executing command {:#?}", cmd);
 
+        if self.draining.is_some() && cmd.mutates() {
+            info!("Refusing a command, shutting down");
+            cmd.refuse("shutting down");
+            return;
+        }
+
         match cmd {
             Play => self.play_pause(),
+            Pause => self.pause(),
+            Resume => self.resume(),
             Stop => self.stop(),
-            Next => self.next(),
+            Next(sender) => {
+                let _ = sender.send(self.next_cmd());
+            }
             Prev => self.prev(),
-            Load { songs, append } => {
-                if append {
-                    self.songs.extend(songs);
-                } else {
-                    self.songs = songs;
-                    self.position = 0;
+            Skip(count) => self.skip(count),
+            Repeat(repeat, sender) => {
+                let _ = sender.send(self.repeat(repeat));
+            }
+            Load {
+                songs,
+                sources,
+                order,
+                how,
+                clear_history,
+                sender,
+            } => {
+                let reply = self.load(songs, sources, order, how, clear_history);
+                if let Some(sender) = sender {
+                    let _ = sender.send(reply);
+                }
+            }
+            PlayNow {
+                songs,
+                sources,
+                sender,
+            } => {
+                let _ = sender.send(self.play_now(songs, sources));
+            }
+            Reload(sender) => self.reload(sender),
+            Rescanned(loads, songs, sender) => {
+                let _ = sender.send(self.rescanned(loads, songs));
+            }
+            Mode(mode) => self.set_mode(mode),
+            GetMode(sender) => {
+                let _ = sender.send(Ok(Response::new().with("mode", self.mode.name())));
+            }
+            Speed(speed, sender) => {
+                let _ = sender.send(self.speed(speed));
+            }
+            Remaining(sender) => {
+                let _ = sender.send(self.remaining());
+            }
+            Time(sender) => {
+                let _ = sender.send(self.time());
+            }
+            Seek(secs, relative, sender) => {
+                let _ = sender.send(self.seek(secs, relative));
+            }
+            SeekPercent(percent, sender) => {
+                let _ = sender.send(self.seek_percent(percent));
+            }
+            Mute(mute, sender) => {
+                let _ = sender.send(self.mute(mute));
+            }
+            Volume(cmd, sender) => {
+                let _ = sender.send(self.volume(cmd));
+            }
+            Filter(cmd, sender) => {
+                let _ = sender.send(self.filter(cmd));
+            }
+            Find(needle, sender) => {
+                let _ = sender.send(self.find(&needle));
+            }
+            Info(path, sender) => {
+                let _ = sender.send(self.info(&path));
+            }
+            Peek(sender) => {
+                let _ = sender.send(self.peek());
+            }
+            Current(sender) => {
+                let _ = sender.send(self.current());
+            }
+            Status(sender) => {
+                let _ = sender.send(Ok(self.status()));
+            }
+            GetState(sender) => {
+                let _ = sender.send(self.state());
+            }
+            GetChanges(sender) => {
+                let _ = sender.send(Changes {
+                    mode: self.mode,
+                    current: self.current.clone(),
+                    paused: self.paused,
+                    loads: self.loads,
+                    starts: self.starts,
+                });
+            }
+            Songs(sender) => {
+                let songs = self.songs
+                    .iter()
+                    .map(|song| (song.clone(), self.metadata.cached(song).cloned()))
+                    .collect();
+                let _ = sender.send(songs);
+            }
+            Artwork(path, sender) => {
+                let _ = sender.send(self.artwork(path));
+            }
+            TotalDuration(sender) => self.total_duration(sender),
+            TagsRead(batch, sender) => {
+                self.metadata.add(batch);
+                self.total_duration(sender);
+            }
+            PickTagsRead(batch) => {
+                self.metadata.add(batch);
+                self.reading_tags = false;
+            }
+            Signal(signal, sender) => {
+                let _ = sender.send(self.signal(signal));
+            }
+            History(count, sender) => {
+                let _ = sender.send(Ok(self.history(count)));
+            }
+            TrimHistory(keep, sender) => {
+                let removed = self.history.len().saturating_sub(keep);
+                self.history.drain(..removed);
+                let _ = sender.send(Ok(Response::new().with("removed", removed.to_string())));
+            }
+            QueueDetail(sender) => {
+                let _ = sender.send(self.queue_detail());
+            }
+            Remove(cmd, sender) => {
+                let _ = sender.send(self.remove(cmd));
+            }
+            Goto(index, sender) => {
+                let _ = sender.send(self.goto(index));
+            }
+            List(upcoming, sender) => {
+                let _ = sender.send(self.list(upcoming));
+            }
+            ShuffleQueue(seed, sender) => {
+                match seed {
+                    Some(seed) => {
+                        let mut bytes = [0; 32];
+                        bytes[..8].copy_from_slice(&seed.to_le_bytes());
+                        ChaChaRng::from_seed(bytes).shuffle(&mut self.playlist);
+                    }
+                    None => rand::thread_rng().shuffle(&mut self.playlist),
                 }
-                println!(
-                    ">>> Playlist: {} songs, History: {}, Position: {}",
-                    self.songs.len(),
-                    self.history.len(),
-                    self.position,
-                );
-            }
-            Mode(mode) => self.mode = mode,
-            Confirm(sender) => {
+                let reply = Response::new().with("queue", self.playlist.len().to_string());
+                let _ = sender.send(Ok(reply));
+            }
+            Plan(count, sender) => {
+                let _ = sender.send(self.plan(count));
+            }
+            Export {
+                path,
+                queue,
+                relative,
+                sender,
+            } => {
+                let _ = sender.send(self.export(&path, queue, relative));
+            }
+            Position(position, sender) => {
+                let _ = sender.send(self.position(position));
+            }
+            Chapter(cmd, sender) => {
+                let _ = sender.send(self.chapter(&cmd));
+            }
+            Chapters(sender) => {
+                let _ = sender.send(self.chapters());
+            }
+            ClearBookmark(path, sender) => {
+                let reply = if self.bookmarks.clear(&path) {
+                    Ok(Response::new())
+                } else {
+                    Err(err_msg("no bookmark"))
+                };
+                let _ = sender.send(reply);
+            }
+            Reindex(start, sender) => {
+                let _ = sender.send(self.reindex(start));
+            }
+            Probed(song, Ok(trim)) => {
+                debug!("Silence in {}: {:?}", song.to_string_lossy(), trim);
+                self.trims.set(song, trim);
+            }
+            Probed(song, Err(e)) => {
+                warn!("Can't find silence in {}: {}", song.to_string_lossy(), e);
+            }
+            Measured(song, Ok(loudness)) => {
+                debug!("Loudness of {}: {:?}", song.to_string_lossy(), loudness);
+                self.gains.set(song, loudness);
+            }
+            Measured(song, Err(e)) => {
+                warn!("Can't measure loudness of {}: {}", song.to_string_lossy(), e);
+            }
+            Refresh(song, sender) => {
+                self.metadata.get(&song);
                 let _ = sender.send(());
             }
+            StreamTitle(stream, title) => self.stream_title(stream, title),
+            // Late news from a song that's no longer playing don't count
+            Paused(start, paused) if start == self.starts && self.control_pipe.is_some() => {
+                self.paused = paused;
+            }
+            Paused(..) => (),
+            Elapsed(start, elapsed) if start == self.starts && self.control_pipe.is_some() => {
+                self.elapsed = elapsed;
+            }
+            Elapsed(..) => (),
+            MpvDuration(start, duration) if start == self.starts && self.control_pipe.is_some() => {
+                self.mpv_duration = duration;
+            }
+            MpvDuration(..) => (),
+            Snapshot => self.session().save_in_background(),
+            Duck(percent, time, sender) => {
+                let _ = sender.send(self.duck(percent, time));
+            }
+            Unduck(id, step) => self.unduck(id, step),
+            Terminate(now, sender) => self.terminate(now, sender),
+            Exit => {
+                warn!("Not waiting for mpv to exit any more");
+                self.exit();
+            }
             Done => self.done(),
+            Announce(start) => {
+                let song = self.current.clone().filter(|_| start == self.starts);
+                if let Some(song) = song {
+                    self.announce(&song);
+                }
+            }
+            Retry => {
+                // Unless something got started in the meantime
+                if self.should_play && self.control_pipe.is_none() {
+                    self.start();
+                }
+            }
         }
     }
 }
 
+/// Whether a song this long (in milliseconds) fits into `--min-song-length` and
+/// `--max-song-length`.
+fn length_fits(duration: Option<u32>) -> bool {
+    let config = config::get();
+    if config.min_song_length.is_none() && config.max_song_length.is_none() {
+        return true;
+    }
+    match duration {
+        Some(duration) => {
+            let secs = u64::from(duration) / 1000;
+            config.min_song_length.map(|min| secs >= min).unwrap_or(true)
+                && config.max_song_length.map(|max| secs <= max).unwrap_or(true)
+        }
+        None => !config.skip_unknown_length,
+    }
+}
+
+fn add_tags(response: &mut Response, meta: &Metadata) {
+    let fields = [("title", &meta.title), ("artist", &meta.artist), ("album", &meta.album)];
+    for (key, value) in &fields {
+        if let Some(value) = value {
+            response.add(key, value.as_str());
+        }
+    }
+    if let Some(track) = meta.track {
+        response.add("track", track.to_string());
+    }
+    if let Some(genre) = &meta.genre {
+        response.add("genre", genre.as_str());
+    }
+    if let Some(year) = meta.year {
+        response.add("year", year.to_string());
+    }
+    if let Some(duration) = meta.duration {
+        response.add("duration", (f64::from(duration) / 1000.0).to_string());
+    }
+}
+
 fn start_player() -> QueueSender<Cmd> {
     let (sender, receiver) = mpsc::unbounded();
 
-    corona::spawn(move || {
-        let mut player = Player::new();
+    Coroutine::new()
+        .stack_size(config::get().player_stack_size)
+        .spawn(move || {
+            let mut player = Player::new();
+            player.restore();
+            if let Some(interval) = config::get().save_interval {
+                let interval = Duration::from_secs(interval);
+                corona::spawn(move || loop {
+                    let _ = Delay::new(Instant::now() + interval).coro_wait();
+                    send(Cmd::Snapshot);
+                });
+            }
 
-        for cmd in receiver.iter_ok() {
-            player.cmd(cmd);
-        }
-        unreachable!();
-    });
+            for cmd in receiver.iter_ok() {
+                // A bug in one command must not leave the daemon without a player. Whoever waits
+                // for an answer gets an error once the sender is dropped during the unwinding.
+                // The state might be a bit off after that, but it's still better than nothing.
+                if panic::catch_unwind(AssertUnwindSafe(|| player.cmd(cmd))).is_err() {
+                    error!("The player panicked while handling a command, trying to go on");
+                }
+            }
+            unreachable!();
+        }).expect("Failed to start the player");
 
     sender
 }
@@ -0,0 +1,187 @@
+//! Songs analyzed by ffmpeg in the background, with the results kept in a state file.
+//!
+//! Used by `--trim-silence` and `--auto-gain`, both of which need to decode the whole song.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str;
+use std::time::UNIX_EPOCH;
+
+use corona::prelude::*;
+use failure::{bail, Error};
+use futures::unsync::oneshot::{self, Sender};
+use log::{debug, error, warn};
+use tokio_process::CommandExt;
+
+use crate::config;
+use crate::state;
+
+/// At most this many ffmpeg processes run at once, the other probes wait for their turn.
+const MAX_RUNNING: usize = 2;
+
+thread_local! {
+    static RUNNING: Cell<usize> = const { Cell::new(0) };
+    static WAITING: RefCell<VecDeque<Sender<()>>> = const { RefCell::new(VecDeque::new()) };
+}
+
+/// A permission to run ffmpeg, passed to the next waiting probe when dropped.
+struct Turn;
+
+impl Turn {
+    fn wait() -> Self {
+        if RUNNING.with(Cell::get) < MAX_RUNNING {
+            RUNNING.with(|running| running.set(running.get() + 1));
+        } else {
+            let (sender, receiver) = oneshot::channel();
+            WAITING.with(|waiting| waiting.borrow_mut().push_back(sender));
+            // The turn is handed over by the one finishing, the count stays the same
+            let _ = receiver.coro_wait();
+        }
+        Turn
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        loop {
+            match WAITING.with(|waiting| waiting.borrow_mut().pop_front()) {
+                // Skip the ones that are no longer waiting
+                Some(sender) => {
+                    if sender.send(()).is_ok() {
+                        return;
+                    }
+                }
+                None => {
+                    RUNNING.with(|running| running.set(running.get() - 1));
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Runs ffmpeg with the audio filter over the whole song and returns what it logged.
+///
+/// Only this coroutine waits for it, the others (including the player) go on.
+pub(crate) fn ffmpeg(path: &Path, filter: &str) -> Result<String, Error> {
+    let _turn = Turn::wait();
+    let output = Command::new(&config::get().ffmpeg)
+        .args(["-hide_banner", "-nostats", "-i"])
+        .arg(path)
+        .args(["-af", filter, "-f", "null", "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .output_async()
+        .coro_wait()?;
+    if !output.status.success() {
+        bail!("ffmpeg failed: {}", output.status);
+    }
+    Ok(String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+fn mtime(path: &Path) -> Option<u64> {
+    let modified = fs::metadata(path).and_then(|meta| meta.modified()).ok()?;
+    modified.duration_since(UNIX_EPOCH).ok().map(|since| since.as_secs())
+}
+
+/// What a `ProbeCache` probes and how it stores the results.
+pub(crate) struct Hooks<T> {
+    /// What is found, for the name of the state file and the log messages.
+    pub(crate) name: &'static str,
+    /// Analyzes the song (in a coroutine of its own).
+    pub(crate) probe: fn(&Path) -> Result<T, Error>,
+    /// Reads the result from the two words it's stored as.
+    pub(crate) parse: fn([&str; 2]) -> Option<T>,
+    /// Turns the result into the two words to store.
+    pub(crate) format: fn(&T) -> [String; 2],
+}
+
+/// The results of probing the songs so far.
+///
+/// Stored in a state file, one `<mtime> <word> <word> <path>` per line. An entry is used only
+/// while the file has the same modification time.
+pub(crate) struct ProbeCache<T> {
+    hooks: Hooks<T>,
+    file: PathBuf,
+    found: HashMap<PathBuf, (u64, T)>,
+    /// Songs probed (or being probed) since starting, so failing ones aren't tried over and over.
+    probed: HashSet<PathBuf>,
+}
+
+impl<T: Copy + 'static> ProbeCache<T> {
+    pub(crate) fn load(hooks: Hooks<T>) -> Self {
+        let file = state::path(hooks.name);
+        let data = state::read(&file).unwrap_or_else(|e| {
+            error!("Failed to read {} from {}: {}", hooks.name, file.display(), e);
+            Vec::new()
+        });
+        let found = data
+            .split(|c| *c == b'\n')
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| {
+                let mut parts = line.splitn(4, |c| *c == b' ');
+                let mut word = || str::from_utf8(parts.next()?).ok();
+                let mtime = word()?.parse().ok()?;
+                let value = (hooks.parse)([word()?, word()?])?;
+                let path = state::remap(OsStr::from_bytes(parts.next()?).into());
+                Some((path, (mtime, value)))
+            }).collect::<HashMap<_, _>>();
+        debug!("Loaded {} of {} songs", hooks.name, found.len());
+        ProbeCache {
+            hooks,
+            file,
+            found,
+            probed: HashSet::new(),
+        }
+    }
+
+    fn save(&self) {
+        let mut data = Vec::new();
+        for (path, (mtime, value)) in &self.found {
+            let path = path.as_os_str().as_bytes();
+            if path.contains(&b'\n') {
+                warn!("Can't store {} of {}", self.hooks.name, String::from_utf8_lossy(path));
+                continue;
+            }
+            let [first, second] = (self.hooks.format)(value);
+            data.extend_from_slice(format!("{} {} {} ", mtime, first, second).as_bytes());
+            data.extend_from_slice(path);
+            data.push(b'\n');
+        }
+        if let Err(e) = state::write(&self.file, &data) {
+            error!("Failed to store {} to {}: {}", self.hooks.name, self.file.display(), e);
+        }
+    }
+
+    /// The result for the song, if it's known.
+    ///
+    /// If not, the song is probed in the background (only the first time it's asked for) and
+    /// the result is passed to `done`.
+    pub(crate) fn get(&mut self, path: &Path, done: fn(PathBuf, Result<T, Error>)) -> Option<T> {
+        match self.found.get(path) {
+            Some((stored, value)) if mtime(path) == Some(*stored) => return Some(*value),
+            _ => (),
+        }
+        if self.probed.insert(path.to_owned()) {
+            let path = path.to_owned();
+            let probe = self.hooks.probe;
+            corona::spawn(move || {
+                let result = probe(&path);
+                done(path, result);
+            });
+        }
+        None
+    }
+
+    pub(crate) fn set(&mut self, path: PathBuf, value: T) {
+        if let Some(mtime) = mtime(&path) {
+            self.found.insert(path, (mtime, value));
+            self.save();
+        }
+    }
+}
@@ -0,0 +1,207 @@
+use std::io::{Error as IoError, Write};
+
+use failure::Error;
+use log::error;
+use serde_json::{json, Map, Value};
+
+#[derive(Debug)]
+enum Field {
+    Single(Vec<u8>),
+    List(Vec<Vec<u8>>),
+    /// Items with their own fields.
+    Records(Vec<Response>),
+}
+
+/// Data sent back to a client as an answer to a command.
+#[derive(Debug, Default)]
+pub(crate) struct Response {
+    fields: Vec<(&'static str, Field)>,
+}
+
+impl Response {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn add(&mut self, key: &'static str, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.fields.push((key, Field::Single(value.into())));
+        self
+    }
+
+    pub(crate) fn with(mut self, key: &'static str, value: impl Into<Vec<u8>>) -> Self {
+        self.add(key, value);
+        self
+    }
+
+    /// Adds another item to a list under the given key.
+    pub(crate) fn push(&mut self, key: &'static str, value: impl Into<Vec<u8>>) -> &mut Self {
+        let existing = self.fields
+            .iter_mut()
+            .filter(|(k, _)| *k == key)
+            .filter_map(|(_, field)| match field {
+                Field::List(items) => Some(items),
+                _ => None,
+            }).next();
+        match existing {
+            Some(items) => items.push(value.into()),
+            None => self.fields.push((key, Field::List(vec![value.into()]))),
+        }
+        self
+    }
+
+    /// Adds another item with its own fields to a list under the given key.
+    ///
+    /// In the text format the fields of the items just follow each other, so the first field of
+    /// each item should be one every item has.
+    pub(crate) fn record(&mut self, key: &'static str, record: Response) -> &mut Self {
+        let existing = self.fields
+            .iter_mut()
+            .filter(|(k, _)| *k == key)
+            .filter_map(|(_, field)| match field {
+                Field::Records(records) => Some(records),
+                _ => None,
+            }).next();
+        match existing {
+            Some(records) => records.push(record),
+            None => self.fields.push((key, Field::Records(vec![record]))),
+        }
+        self
+    }
+}
+
+fn json_data(fields: &[(&'static str, Field)]) -> Map<String, Value> {
+    let text = |value: &[u8]| Value::String(String::from_utf8_lossy(value).into_owned());
+    fields
+        .iter()
+        .map(|(key, field)| {
+            let value = match field {
+                Field::Single(value) => text(value),
+                Field::List(items) => items.iter().map(|i| text(i)).collect(),
+                Field::Records(records) => {
+                    records.iter().map(|r| Value::Object(json_data(&r.fields))).collect()
+                }
+            };
+            (key.to_string(), value)
+        }).collect()
+}
+
+pub(crate) type Reply = Result<Response, Error>;
+
+/// How the responses are encoded on a connection.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Format {
+    /// Each field is a `key: value` line (list items get a line each), followed by a terminating
+    /// `OK` line. Failed commands get a single `ERR <message>` line instead.
+    Text,
+    /// Each response is a single line with a JSON object, with `status` (`ok` or `error`),
+    /// `data` (an object with the fields) and `error` (the message) keys. Paths that aren't
+    /// valid UTF-8 are mangled.
+    Json,
+}
+
+/// Encodes responses to a client.
+pub(crate) struct Writer<W> {
+    out: W,
+    format: Format,
+    /// The original commands don't answer in the text format (see `quiet`).
+    quiet: bool,
+}
+
+impl<W: Write> Writer<W> {
+    pub(crate) fn new(out: W) -> Self {
+        Writer {
+            out,
+            format: Format::Text,
+            quiet: false,
+        }
+    }
+
+    pub(crate) fn format(&self) -> Format {
+        self.format
+    }
+
+    pub(crate) fn set_format(&mut self, format: Format) {
+        self.format = format;
+    }
+
+    pub(crate) fn quiet(&self) -> bool {
+        self.quiet
+    }
+
+    pub(crate) fn set_quiet(&mut self, quiet: bool) {
+        self.quiet = quiet;
+    }
+
+    /// Sends the answer to a command.
+    pub(crate) fn send(&mut self, reply: &Reply) -> Result<(), IoError> {
+        match self.format {
+            Format::Text => self.send_text(reply),
+            Format::Json => self.send_json(reply),
+        }
+    }
+
+    /// Sends the answer to one of the commands from before the protocol had responses.
+    ///
+    /// They answer like any other command, unless the client asked for the old behaviour with
+    /// `quiet`. Then not even errors are sent in the text format: an old client sending several
+    /// of them in a row and then a command that answers would take the `ERR` line for the
+    /// answer of the last one and stay one answer behind from then on. Errors are at least
+    /// logged.
+    pub(crate) fn send_quiet(&mut self, reply: &Reply) -> Result<(), IoError> {
+        match (self.format, reply) {
+            (Format::Text, _) if !self.quiet => self.send_text(reply),
+            (Format::Text, Ok(_)) => Ok(()),
+            (Format::Text, Err(e)) => {
+                error!("{}", e);
+                Ok(())
+            }
+            (Format::Json, _) => self.send_json(reply),
+        }
+    }
+
+    fn send_text(&mut self, reply: &Reply) -> Result<(), IoError> {
+        match reply {
+            Ok(response) => {
+                self.write_fields(&response.fields)?;
+                self.out.write_all(b"OK\n")
+            }
+            // The message must stay on a single line, or the client would lose track of responses
+            Err(e) => writeln!(self.out, "ERR {}", e.to_string().replace('\n', " ")),
+        }
+    }
+
+    fn write_fields(&mut self, fields: &[(&'static str, Field)]) -> Result<(), IoError> {
+        for (key, field) in fields {
+            let values = match field {
+                Field::Single(value) => std::slice::from_ref(value),
+                Field::List(items) => &items[..],
+                Field::Records(records) => {
+                    for record in records {
+                        self.write_fields(&record.fields)?;
+                    }
+                    continue;
+                }
+            };
+            for value in values {
+                self.out.write_all(key.as_bytes())?;
+                self.out.write_all(b": ")?;
+                self.out.write_all(value)?;
+                self.out.write_all(b"\n")?;
+            }
+        }
+        Ok(())
+    }
+
+    fn send_json(&mut self, reply: &Reply) -> Result<(), IoError> {
+        let msg = match reply {
+            Ok(response) => {
+                let data = json_data(&response.fields);
+                json!({ "status": "ok", "data": data, "error": null })
+            }
+            Err(e) => json!({ "status": "error", "data": null, "error": e.to_string() }),
+        };
+        // The compact form has no newlines inside
+        serde_json::to_writer(&mut self.out, &msg)?;
+        self.out.write_all(b"\n")
+    }
+}
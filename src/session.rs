@@ -0,0 +1,166 @@
+//! Snapshots of what the player is doing, to survive crashes and restarts.
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use log::{debug, error, warn};
+
+use crate::config;
+use crate::library;
+use crate::player::Mode;
+use crate::state;
+
+/// Checks the song can still be played.
+fn found(song: &Path) -> bool {
+    library::is_url(song) || song.is_file()
+}
+
+/// Parses the `<unix time> <path>` of a `history` line.
+///
+/// Sessions from before the times were stored have just the `<path>`. The time those songs
+/// played is lost, so they get the time of loading.
+fn history_entry(value: &[u8]) -> Option<(PathBuf, SystemTime)> {
+    let mut parts = value.splitn(2, |c| *c == b' ');
+    let secs = str::from_utf8(parts.next()?).ok().and_then(|secs| secs.parse().ok());
+    let (path, started) = match secs {
+        Some(secs) => (parts.next()?, UNIX_EPOCH + Duration::from_secs(secs)),
+        None => (value, SystemTime::now()),
+    };
+    Some((state::remap(OsStr::from_bytes(path).into()), started))
+}
+
+fn write(data: &[u8]) {
+    let file = state::path("session");
+    if let Err(e) = state::write(&file, data) {
+        error!("Failed to store session to {}: {}", file.display(), e);
+    }
+}
+
+/// The loaded songs, where the player is in them and what it played.
+///
+/// Stored in a state file, one `<key> <value>` per line. The paths are stored as they are, so
+/// ones containing a newline are skipped.
+pub(crate) struct Session {
+    pub(crate) mode: Mode,
+    pub(crate) position: usize,
+    pub(crate) sources: Vec<PathBuf>,
+    pub(crate) songs: Vec<PathBuf>,
+    /// With the time each song started, the most recent song last.
+    pub(crate) history: Vec<(PathBuf, SystemTime)>,
+}
+
+impl Session {
+    /// Reads the last snapshot, if there's any.
+    pub(crate) fn load() -> Option<Self> {
+        let file = state::path("session");
+        let data = match state::read(&file) {
+            Ok(data) => data,
+            Err(e) => {
+                error!("Failed to read session from {}: {}", file.display(), e);
+                return None;
+            }
+        };
+        if data.is_empty() {
+            return None;
+        }
+        let mut session = Session {
+            mode: config::get().default_mode,
+            position: 0,
+            sources: Vec::new(),
+            songs: Vec::new(),
+            history: Vec::new(),
+        };
+        for line in data.split(|c| *c == b'\n').filter(|line| !line.is_empty()) {
+            let mut parts = line.splitn(2, |c| *c == b' ');
+            let key = parts.next().unwrap_or_default();
+            let value = parts.next().unwrap_or_default();
+            match key {
+                b"mode" => match Mode::from_name(value) {
+                    Some(mode) => session.mode = mode,
+                    None => warn!("Unknown mode {} in session", String::from_utf8_lossy(value)),
+                },
+                b"position" => {
+                    session.position = str::from_utf8(value)
+                        .ok()
+                        .and_then(|pos| pos.parse().ok())
+                        .unwrap_or(0);
+                }
+                b"source" => session.sources.push(state::remap(OsStr::from_bytes(value).into())),
+                b"song" => session.songs.push(state::remap(OsStr::from_bytes(value).into())),
+                b"history" => match history_entry(value) {
+                    Some(entry) => session.history.push(entry),
+                    None => warn!("Invalid history {}", String::from_utf8_lossy(value)),
+                },
+                _ => warn!("Unknown session entry {}", String::from_utf8_lossy(key)),
+            }
+        }
+        // The songs might have been changed by hand or moved since
+        let total = session.songs.len();
+        let before = session.position.min(total);
+        let missing_before = session.songs[..before]
+            .iter()
+            .filter(|song| !found(song))
+            .count();
+        session.songs.retain(|song| found(song));
+        session.position = before - missing_before;
+        if session.songs.len() < total {
+            let missing = total - session.songs.len();
+            warn!("Dropped {} songs not found any more from the session", missing);
+        }
+        session.history.retain(|(song, _)| found(song));
+        let sources = session.sources.len();
+        session.sources.retain(|source| library::is_url(source) || source.exists());
+        if session.sources.len() < sources {
+            let missing = sources - session.sources.len();
+            warn!("Dropped {} loaded paths not found any more from the session", missing);
+        }
+        debug!("Loaded session with {} songs", session.songs.len());
+        Some(session)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut data = format!("mode {}\nposition {}\n", self.mode.name(), self.position)
+            .into_bytes();
+        let paths = self.sources
+            .iter()
+            .map(|path| (&b"source"[..], None, path))
+            .chain(self.songs.iter().map(|path| (&b"song"[..], None, path)))
+            .chain(self.history.iter().map(|(path, started)| {
+                let secs = started
+                    .duration_since(UNIX_EPOCH)
+                    .map(|since| since.as_secs())
+                    .unwrap_or(0);
+                (&b"history"[..], Some(secs), path)
+            }));
+        for (key, secs, path) in paths {
+            let path = path.as_os_str().as_bytes();
+            if path.contains(&b'\n') {
+                warn!("Can't store {} in session", String::from_utf8_lossy(path));
+                continue;
+            }
+            data.extend_from_slice(key);
+            data.push(b' ');
+            if let Some(secs) = secs {
+                data.extend_from_slice(format!("{} ", secs).as_bytes());
+            }
+            data.extend_from_slice(path);
+            data.push(b'\n');
+        }
+        data
+    }
+
+    /// Writes the snapshot right away, for when the daemon is about to exit.
+    pub(crate) fn save(&self) {
+        write(&self.encode());
+    }
+
+    /// Writes the snapshot in a separate thread, so the player doesn't have to wait for the disk.
+    pub(crate) fn save_in_background(&self) {
+        let data = self.encode();
+        thread::spawn(move || write(&data));
+    }
+}
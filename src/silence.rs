@@ -0,0 +1,101 @@
+//! Silence at the start and the end of songs, skipped with `--trim-silence`.
+//!
+//! Finding it means decoding the whole song, so ffmpeg does it in the background the first time
+//! the song plays and the results are kept in a state file for the next times.
+
+use std::path::Path;
+
+use failure::Error;
+use log::debug;
+
+use crate::probe::{self, Hooks};
+
+/// Quieter than this for at least half a second is silence.
+const FILTER: &str = "silencedetect=noise=-50dB:duration=0.5";
+/// Silence closer than this (in seconds) to the edge of the song counts as touching it.
+const EDGE: f64 = 0.1;
+
+/// Where the sound of a song is.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Trim {
+    /// Where the leading silence ends (0 without any).
+    pub(crate) start: f64,
+    /// Where the trailing silence starts, if there's any.
+    pub(crate) end: Option<f64>,
+}
+
+/// The first number in the text.
+fn number(text: &str) -> Option<f64> {
+    text.split(|c: char| c.is_whitespace() || c == ',').next()?.parse().ok()
+}
+
+/// Parses the `hh:mm:ss.xx` duration ffmpeg prints.
+fn duration(text: &str) -> Option<f64> {
+    let text = text.split(',').next()?;
+    let mut parts = text.trim().split(':');
+    let hours = parts.next()?.parse::<f64>().ok()?;
+    let minutes = parts.next()?.parse::<f64>().ok()?;
+    let seconds = parts.next()?.parse::<f64>().ok()?;
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+/// Finds the silence at the edges from the output of ffmpeg's silencedetect.
+fn parse(log: &str) -> Trim {
+    let mut total = None;
+    let mut silences: Vec<(f64, Option<f64>)> = Vec::new();
+    for line in log.lines() {
+        let value = |key: &str| line.find(key).map(|pos| &line[pos + key.len()..]);
+        if let Some(value) = value("Duration: ") {
+            total = duration(value);
+        } else if let Some(start) = value("silence_start: ").and_then(number) {
+            silences.push((start, None));
+        } else if let Some(end) = value("silence_end: ").and_then(number) {
+            if let Some(last) = silences.last_mut() {
+                last.1 = Some(end);
+            }
+        }
+    }
+    let start = match silences.first() {
+        Some((start, Some(end))) if *start < EDGE => *end,
+        _ => 0.0,
+    };
+    // Nothing but silence, better play it than skip it all
+    if total.is_some_and(|total| start > total - EDGE) {
+        return Trim::default();
+    }
+    // Newer ffmpeg ends the trailing silence at the end of the file, older ones don't
+    let end = silences
+        .last()
+        .filter(|(_, end)| match (end, total) {
+            (None, _) => true,
+            (Some(end), Some(total)) => *end > total - EDGE,
+            (Some(_), None) => false,
+        }).map(|(start, _)| *start)
+        .filter(|end| *end > start);
+    Trim { start, end }
+}
+
+/// Finds the silence in the whole song with ffmpeg.
+fn probe(path: &Path) -> Result<Trim, Error> {
+    debug!("Looking for silence in {}", path.display());
+    Ok(parse(&probe::ffmpeg(path, FILTER)?))
+}
+
+/// The silence is stored as `<start> <end>` (`-` for no end).
+pub(crate) const HOOKS: Hooks<Trim> = Hooks {
+    name: "silence",
+    probe,
+    parse: |[start, end]| {
+        Some(Trim {
+            start: start.parse().ok()?,
+            end: match end {
+                "-" => None,
+                end => Some(end.parse().ok()?),
+            },
+        })
+    },
+    format: |trim| {
+        let end = trim.end.map_or_else(|| "-".to_owned(), |end| end.to_string());
+        [trim.start.to_string(), end]
+    },
+};
@@ -0,0 +1,86 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use failure::Error;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::config;
+
+/// The first bytes of a gzip file.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Makes the temporary files unique, even when writing the same file from more threads.
+static TMP_NUM: AtomicUsize = AtomicUsize::new(0);
+
+/// Where a state file of the given name lives.
+pub(crate) fn path(name: &str) -> PathBuf {
+    let home = env::var_os("HOME").unwrap_or_else(|| "/".into());
+    PathBuf::from(home).join(format!(".clue_play_{}", name))
+}
+
+/// A path read from a state file, with the first matching `--path-remap` applied.
+pub(crate) fn remap(path: PathBuf) -> PathBuf {
+    config::get()
+        .path_remaps
+        .iter()
+        .filter_map(|remap| remap.apply(&path))
+        .next()
+        .unwrap_or(path)
+}
+
+/// Reads the whole file, a missing one is considered empty.
+///
+/// Gzipped files are recognized and decompressed.
+pub(crate) fn read(path: &Path) -> Result<Vec<u8>, Error> {
+    let mut data = Vec::new();
+    match File::open(path) {
+        Ok(mut f) => {
+            f.read_to_end(&mut data)?;
+        }
+        Err(ref e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    if data.starts_with(GZIP_MAGIC) {
+        let mut plain = Vec::new();
+        GzDecoder::new(&data[..]).read_to_end(&mut plain)?;
+        data = plain;
+    }
+    Ok(data)
+}
+
+/// Replaces the file with new content.
+///
+/// The data is written into a temporary file first and renamed over the original, so a crash
+/// in the middle leaves the previous version intact. Each write has its own temporary file, so
+/// a background one doesn't mix with another. With `--compress-state`, the file is gzipped.
+pub(crate) fn write(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let compressed;
+    let data = if config::get().compress_state {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data)?;
+        compressed = encoder.finish()?;
+        &compressed[..]
+    } else {
+        data
+    };
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".tmp.{}.{}", process::id(), TMP_NUM.fetch_add(1, Ordering::Relaxed)));
+    let tmp = PathBuf::from(tmp);
+    let result = File::create(&tmp)
+        .and_then(|mut f| {
+            f.write_all(data)?;
+            f.sync_all()
+        })
+        .and_then(|()| fs::rename(&tmp, path));
+    if result.is_err() {
+        // Not to leave them lying around
+        let _ = fs::remove_file(&tmp);
+    }
+    Ok(result?)
+}
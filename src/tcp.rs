@@ -0,0 +1,63 @@
+//! Control connections over TCP, for clients on other machines.
+//!
+//! The commands are the same as on the socket, except that no file descriptors can be passed.
+//! The MPD clients connect over TCP too, only speaking a different protocol. There's no
+//! authentication, so this should be bound only to addresses trusted clients use.
+
+use std::net::SocketAddr;
+
+use corona::prelude::*;
+use log::{error, info};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::config;
+
+/// Serves a single connection.
+pub(crate) type Handler = fn(TcpStream);
+
+/// Accepts the connections on one of the addresses.
+fn accept(listener: TcpListener, addr: SocketAddr, handler: Handler) {
+    for conn in listener.incoming().iter_result() {
+        match conn {
+            Ok(conn) => {
+                let spawned = Coroutine::new()
+                    .stack_size(config::get().stack_size)
+                    .spawn(move || handler(conn));
+                if let Err(e) = spawned {
+                    error!("Failed to start connection handler: {}", e);
+                }
+            }
+            Err(e) => error!("Failed to accept connection on {}: {}", addr, e),
+        }
+    }
+}
+
+/// Binds the address (retrying if asked to) and accepts the connections.
+fn serve(addr: SocketAddr, handler: Handler) {
+    let bound = crate::bind_with_retry(&addr.to_string(), || Ok(TcpListener::bind(&addr)?));
+    let listener = match bound {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Can't listen on {}: {}", addr, e);
+            return;
+        }
+    };
+    // The system picks the port if it's 0
+    let addr = listener.local_addr().unwrap_or(addr);
+    info!("Listening on {}", addr);
+    accept(listener, addr, handler);
+}
+
+/// Starts listening on all the addresses, each with its own coroutine.
+///
+/// An address that can't be used is only logged, so the others (and the socket) still work.
+pub(crate) fn listen(addrs: &[SocketAddr], handler: Handler) {
+    for addr in addrs.iter().cloned() {
+        let spawned = Coroutine::new()
+            .stack_size(config::get().stack_size)
+            .spawn(move || serve(addr, handler));
+        if let Err(e) = spawned {
+            error!("Failed to start listening on {}: {}", addr, e);
+        }
+    }
+}
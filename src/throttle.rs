@@ -0,0 +1,50 @@
+//! Limiting how fast a single client may send commands.
+
+use std::time::{Duration, Instant};
+
+use corona::prelude::*;
+use log::debug;
+use tokio::timer::Delay;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Slows down a client sending more than the allowed number of commands per second.
+///
+/// The client is not refused, its commands just wait for the next second. That keeps the
+/// protocol intact while the player isn't flooded.
+pub(crate) struct Throttle {
+    limit: Option<u32>,
+    window: Instant,
+    count: u32,
+}
+
+impl Throttle {
+    pub(crate) fn new(limit: Option<u32>) -> Self {
+        Throttle {
+            limit,
+            window: Instant::now(),
+            count: 0,
+        }
+    }
+
+    /// Accounts for one more command, waiting first if there were too many already.
+    pub(crate) fn wait(&mut self) {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        let now = Instant::now();
+        if now.duration_since(self.window) >= WINDOW {
+            self.window = now;
+            self.count = 0;
+        }
+        if self.count >= limit {
+            let next = self.window + WINDOW;
+            debug!("Too many commands, waiting {:?}", next.duration_since(now));
+            let _ = Delay::new(next).coro_wait();
+            self.window = next;
+            self.count = 0;
+        }
+        self.count += 1;
+    }
+}
@@ -0,0 +1,3043 @@
+//! Drives the daemon over its control socket.
+//!
+//! Each test starts its own daemon with a temporary socket, home directory and a fake mpv, so
+//! nothing is actually played and the tests don't interfere with each other.
+
+use std::env;
+use std::fs::{self, Permissions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command};
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use nix::sys::signal::{self, Signal};
+use nix::sys::socket::{self, ControlMessage, MsgFlags};
+use nix::sys::uio::IoVec;
+use nix::unistd::Pid;
+use serde_json::Value;
+
+static DAEMON_NUM: AtomicUsize = AtomicUsize::new(0);
+
+/// Pretends to be mpv: ignores all commands and lives until the daemon closes the control
+/// channel.
+const FAKE_MPV: &str = "#!/bin/sh\nexec cat <&4 >/dev/null\n";
+
+/// Ending of a fake mpv that quits when asked to and answers all JSON requests with an error
+/// (like a player without any properties), so the daemon isn't left waiting.
+const MPV_LOOP: &str = r#"while read -r line <&4; do
+    case "$line" in
+        *'["quit"]'*) exit 0 ;;
+        *request_id*)
+            id=$(echo "$line" | sed 's/.*"request_id":\([0-9]*\).*/\1/')
+            echo "{\"request_id\":$id,\"error\":\"property unavailable\"}" >&4 ;;
+    esac
+done
+"#;
+
+/// Beginning of a fake mpv recording the played songs into the `played` file.
+const RECORDING_MPV: &str =
+    "#!/bin/sh\nfor song; do :; done\necho \"$song\" >> \"$(dirname \"$0\")/played\"\n";
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+struct Daemon {
+    dir: PathBuf,
+    socket: PathBuf,
+    child: Child,
+}
+
+impl Daemon {
+    fn start() -> Self {
+        Self::with_args(&[])
+    }
+
+    fn with_args(args: &[&str]) -> Self {
+        Self::with_mpv(FAKE_MPV, args)
+    }
+
+    fn with_mpv(mpv_script: &str, args: &[&str]) -> Self {
+        Self::with_setup(mpv_script, args, |_| ())
+    }
+
+    /// Lets the test prepare the (temporary) home directory before the daemon starts.
+    fn with_setup(mpv_script: &str, args: &[&str], setup: impl FnOnce(&Path)) -> Self {
+        Self::with_env(mpv_script, args, &[], setup)
+    }
+
+    /// Like [`with_setup`], with additional environment variables for the daemon.
+    fn with_env(
+        mpv_script: &str,
+        args: &[&str],
+        vars: &[(&str, &str)],
+        setup: impl FnOnce(&Path),
+    ) -> Self {
+        Self::launch(mpv_script, args, vars, None, setup)
+    }
+
+    /// Like [`with_mpv`], allowing the daemon only this many open files.
+    fn with_fd_limit(limit: usize, mpv_script: &str, args: &[&str]) -> Self {
+        Self::launch(mpv_script, args, &[], Some(limit), |_| ())
+    }
+
+    fn launch(
+        mpv_script: &str,
+        args: &[&str],
+        vars: &[(&str, &str)],
+        fd_limit: Option<usize>,
+        setup: impl FnOnce(&Path),
+    ) -> Self {
+        let num = DAEMON_NUM.fetch_add(1, Ordering::Relaxed);
+        let dir = env::temp_dir().join(format!("playlist_mgr-test-{}-{}", process::id(), num));
+        // Exists from the start, so it can be passed to --root
+        fs::create_dir_all(dir.join("music")).unwrap();
+        let mpv = dir.join("mpv");
+        fs::write(&mpv, mpv_script).unwrap();
+        fs::set_permissions(&mpv, Permissions::from_mode(0o755)).unwrap();
+        let socket = dir.join("socket");
+        setup(&dir);
+
+        let mut command = match fd_limit {
+            Some(limit) => {
+                // The shell keeps its PID through the exec
+                let mut shell = Command::new("sh");
+                shell.arg("-c").arg(format!("ulimit -n {} && exec \"$0\" \"$@\"", limit));
+                shell.arg(env!("CARGO_BIN_EXE_playlist_mgr"));
+                shell
+            }
+            None => Command::new(env!("CARGO_BIN_EXE_playlist_mgr")),
+        };
+        let child = command
+            .arg("--socket")
+            .arg(&socket)
+            .arg("--mpv")
+            .arg(&mpv)
+            .args(args)
+            .env("HOME", &dir)
+            // Not to pick up the config of whoever runs the tests
+            .env_remove("XDG_CONFIG_HOME")
+            .envs(vars.iter().cloned())
+            // Relative paths in the arguments land in the temporary directory
+            .current_dir(&dir)
+            .spawn()
+            .unwrap();
+        let daemon = Daemon { dir, socket, child };
+
+        let deadline = Instant::now() + TIMEOUT;
+        while !daemon.socket.exists() {
+            assert!(Instant::now() < deadline, "The daemon didn't create its socket");
+            thread::sleep(Duration::from_millis(10));
+        }
+        daemon
+    }
+
+    /// Creates a (fake) song file and returns its path.
+    fn song(&self, name: &str) -> PathBuf {
+        let path = self.dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, b"").unwrap();
+        path
+    }
+
+    /// Waits for at least this many songs to be played (by the recording mpv) and returns them.
+    fn played(&self, count: usize) -> Vec<PathBuf> {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let played = fs::read_to_string(self.dir.join("played")).unwrap_or_default();
+            let played = played.lines().map(PathBuf::from).collect::<Vec<_>>();
+            if played.len() >= count {
+                return played;
+            }
+            assert!(Instant::now() < deadline, "Not enough songs played");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Waits for the file in the daemon's directory to have the right content and returns it.
+    fn wait_file(&self, name: &str, what: impl Fn(&str) -> bool) -> String {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let content = fs::read_to_string(self.dir.join(name)).unwrap_or_default();
+            if what(&content) {
+                return content;
+            }
+            assert!(Instant::now() < deadline, "Waiting for {}: {}", name, content);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Waits for the daemon to listen on a localhost TCP port and returns it.
+    ///
+    /// Needs `--log-file log` with the `tcp` module logging at the info level.
+    fn tcp_port(&self) -> u16 {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let log = fs::read_to_string(self.dir.join("log")).unwrap_or_default();
+            let port = log
+                .lines()
+                .filter_map(|line| line.split("Listening on 127.0.0.1:").nth(1))
+                .next()
+                .map(|port| port.parse::<u16>().unwrap());
+            if let Some(port) = port {
+                return port;
+            }
+            assert!(Instant::now() < deadline, "Not listening: {}", log);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Connects as a client of the original protocol (see `quiet`).
+    ///
+    /// Most of the tests send the original commands by `send` and don't read their answers.
+    fn connect(&self) -> Client {
+        let mut client = self.connect_answering();
+        client.cmd("quiet").unwrap();
+        client
+    }
+
+    /// Connects without `quiet`, every command answers.
+    fn connect_answering(&self) -> Client {
+        let stream = UnixStream::connect(&self.socket).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        Client {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        }
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+struct Client {
+    reader: BufReader<UnixStream>,
+    writer: UnixStream,
+}
+
+impl Client {
+    fn send_raw(&mut self, data: &[u8]) {
+        self.writer.write_all(data).unwrap();
+    }
+
+    fn send(&mut self, line: &str) {
+        self.send_raw(format!("{}\n", line).as_bytes());
+    }
+
+    /// Sends the command line together with the file descriptor.
+    fn send_fd(&mut self, line: &str, file: &impl AsRawFd) {
+        let line = format!("{}\n", line);
+        let iov = [IoVec::from_slice(line.as_bytes())];
+        let fds = [file.as_raw_fd()];
+        let rights = [ControlMessage::ScmRights(&fds)];
+        socket::sendmsg(self.writer.as_raw_fd(), &iov, &rights, MsgFlags::empty(), None).unwrap();
+    }
+
+    fn load(&mut self, flags: &str, songs: &[PathBuf]) {
+        self.send(&format!("load {}", flags));
+        for song in songs {
+            self.send(song.to_str().unwrap());
+        }
+        self.send("");
+    }
+
+    fn line(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        assert!(line.ends_with('\n'), "Incomplete line {:?}", line);
+        line.pop();
+        line
+    }
+
+    /// Reads a text response, the field lines or the error message.
+    fn response(&mut self) -> Result<Vec<String>, String> {
+        let mut fields = Vec::new();
+        loop {
+            let line = self.line();
+            if line == "OK" {
+                return Ok(fields);
+            } else if let Some(err) = line.strip_prefix("ERR ") {
+                return Err(err.to_owned());
+            }
+            fields.push(line);
+        }
+    }
+
+    fn cmd(&mut self, line: &str) -> Result<Vec<String>, String> {
+        self.send(line);
+        self.response()
+    }
+
+    fn json(&mut self, line: &str) -> Value {
+        self.send(line);
+        serde_json::from_str(&self.line()).unwrap()
+    }
+
+    fn closed(&mut self) -> bool {
+        let mut buf = [0; 1];
+        self.reader.read(&mut buf).unwrap() == 0
+    }
+}
+
+#[test]
+fn version() {
+    let daemon = Daemon::start();
+    let mut client = daemon.connect();
+    let fields = client.cmd("version").unwrap();
+    assert_eq!(fields[0], format!("version: {}", env!("CARGO_PKG_VERSION")));
+}
+
+#[test]
+fn conn_info() {
+    let daemon = Daemon::start();
+    let first = daemon.connect().cmd("conn-info").unwrap();
+    let second = daemon.connect().cmd("conn-info").unwrap();
+    assert_eq!(first, vec!["connection: 0", "transport: unix", "access: control"]);
+    assert_eq!(second[0], "connection: 1");
+}
+
+#[test]
+fn capabilities() {
+    let daemon = Daemon::start();
+    let mut client = daemon.connect();
+    let fields = client.cmd("capabilities").unwrap();
+    for expected in &["command: load", "command: play", "command: capabilities", "mode: random"] {
+        assert!(fields.iter().any(|field| field == expected), "Missing {}", expected);
+    }
+    assert_eq!(client.json("json")["status"], "ok");
+    let found = client.json("capabilities");
+    assert!(found["data"]["mode"].as_array().unwrap().contains(&"album-random".into()));
+}
+
+#[test]
+fn ping() {
+    let daemon = Daemon::start();
+    let mut client = daemon.connect();
+    assert_eq!(client.cmd("ping foo").unwrap(), vec!["pong: foo"]);
+    assert!(client.cmd("ping").unwrap().is_empty());
+}
+
+#[test]
+fn already_running() {
+    let daemon = Daemon::start();
+    let second = Command::new(env!("CARGO_BIN_EXE_playlist_mgr"))
+        .arg("--socket")
+        .arg(&daemon.socket)
+        .env("HOME", &daemon.dir)
+        .output()
+        .unwrap();
+    assert_eq!(second.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&second.stderr);
+    assert!(stderr.contains("Another instance is already running"), "{}", stderr);
+    // The first one is still fine
+    assert!(daemon.connect().cmd("version").is_ok());
+}
+
+#[test]
+fn socket_from_env() {
+    let daemon = Daemon::start();
+    // Waits for the socket to appear and checks the daemon answers there
+    let serve = |vars: &[(&str, &Path)], socket: &Path| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_playlist_mgr"))
+            .env("HOME", &daemon.dir)
+            .env_remove("CLUE_PLAY_SOCKET")
+            .env_remove("XDG_RUNTIME_DIR")
+            .env_remove("XDG_CONFIG_HOME")
+            .envs(vars.iter().cloned())
+            .spawn()
+            .unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+        while !socket.exists() {
+            assert!(Instant::now() < deadline, "No socket at {}", socket.display());
+            thread::sleep(Duration::from_millis(10));
+        }
+        let stream = UnixStream::connect(socket).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut client = Client {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        };
+        assert!(client.cmd("version").is_ok());
+        client.send("terminate");
+        assert!(child.wait().unwrap().success());
+    };
+    let explicit = daemon.dir.join("env-socket");
+    serve(&[("CLUE_PLAY_SOCKET", &explicit)], &explicit);
+    let runtime = daemon.dir.join("runtime");
+    fs::create_dir(&runtime).unwrap();
+    serve(&[("XDG_RUNTIME_DIR", &runtime)], &runtime.join("clue_play_socket"));
+    serve(&[], &daemon.dir.join(".clue_play_socket"));
+}
+
+#[test]
+fn single_instance_lock() {
+    let daemon = Daemon::with_args(&["--single-instance-lock", "lock"]);
+    let lock = daemon.dir.join("lock");
+    let pid = fs::read_to_string(&lock).unwrap();
+    // A different socket doesn't help, the lock is held
+    let start = Instant::now();
+    let second = Command::new(env!("CARGO_BIN_EXE_playlist_mgr"))
+        .arg("--socket")
+        .arg(daemon.dir.join("other-socket"))
+        .arg("--single-instance-lock")
+        .arg(&lock)
+        .env("HOME", &daemon.dir)
+        .output()
+        .unwrap();
+    assert!(start.elapsed() < TIMEOUT);
+    assert_eq!(second.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&second.stderr);
+    assert!(stderr.contains(&format!("PID {}", pid.trim())), "{}", stderr);
+    assert!(!daemon.dir.join("other-socket").exists());
+    // The first one still holds it
+    assert_eq!(fs::read_to_string(&lock).unwrap(), pid);
+    assert!(daemon.connect().cmd("version").is_ok());
+}
+
+#[test]
+fn bind_retry() {
+    let args = ["--bind-retry", "20", "--bind-retry-interval", "50"];
+    // Something else is in the way at first
+    let daemon = Daemon::with_setup(FAKE_MPV, &args, |dir| {
+        fs::write(dir.join("socket"), b"").unwrap();
+    });
+    thread::sleep(Duration::from_millis(200));
+    fs::remove_file(&daemon.socket).unwrap();
+    let deadline = Instant::now() + TIMEOUT;
+    while UnixStream::connect(&daemon.socket).is_err() {
+        assert!(Instant::now() < deadline, "The daemon didn't bind the socket");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(daemon.connect().cmd("version").is_ok());
+}
+
+#[test]
+fn listen_tcp() {
+    // The first one isn't on this machine, the second one still works
+    let args = ["--listen-tcp", "192.0.2.1:0", "--listen-tcp", "127.0.0.1:0", "--log-file", "log"];
+    let vars = [("RUST_LOG", "playlist_mgr::tcp=info")];
+    let daemon = Daemon::with_env(FAKE_MPV, &args, &vars, |_| ());
+    let port = daemon.tcp_port();
+    let log = fs::read_to_string(daemon.dir.join("log")).unwrap();
+    assert!(log.contains("Can't listen on 192.0.2.1:0"), "{}", log);
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.write_all(b"conn-info\n").unwrap();
+    let lines = BufReader::new(stream)
+        .lines()
+        .map(Result::unwrap)
+        .take_while(|line| line != "OK")
+        .collect::<Vec<_>>();
+    assert!(lines.contains(&"transport: tcp".to_owned()), "{:?}", lines);
+}
+
+/// A client speaking the MPD protocol.
+struct Mpd {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+impl Mpd {
+    fn connect(port: u16) -> Self {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        let mut mpd = Mpd {
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+        };
+        assert_eq!(mpd.answer(), vec!["OK MPD 0.19.0"]);
+        mpd
+    }
+
+    /// Reads the lines up to the `OK` or `ACK` one (included).
+    fn answer(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            assert!(line.ends_with('\n'), "Incomplete line {:?}", line);
+            line.pop();
+            let done = line.starts_with("OK") || line.starts_with("ACK ");
+            lines.push(line);
+            if done {
+                return lines;
+            }
+        }
+    }
+
+    fn cmd(&mut self, line: &str) -> Vec<String> {
+        self.writer.write_all(format!("{}\n", line).as_bytes()).unwrap();
+        self.answer()
+    }
+}
+
+#[test]
+fn mpd() {
+    let args = ["--listen-mpd", "127.0.0.1:0", "--mpd-music-dir", "music", "--log-file", "log"];
+    let vars = [("RUST_LOG", "playlist_mgr::tcp=info")];
+    let daemon = Daemon::with_env(&format!("#!/bin/sh\n{}", MPV_LOOP), &args, &vars, |_| ());
+    daemon.song("music/a.mp3");
+    fs::write(daemon.song("music/b c.mp3"), id3_tag(&[("TIT2", "Title")])).unwrap();
+    let port = daemon.tcp_port();
+    let mut mpd = Mpd::connect(port);
+
+    assert_eq!(mpd.cmd("add a.mp3"), vec!["OK"]);
+    assert_eq!(mpd.cmd("add \"b c.mp3\""), vec!["OK"]);
+    assert_eq!(mpd.cmd("add missing.mp3"), vec!["ACK [50@0] {add} No such song"]);
+    assert_eq!(mpd.cmd("bogus"), vec!["ACK [5@0] {bogus} unknown command \"bogus\""]);
+    let status = mpd.cmd("status");
+    for field in &["state: stop", "playlistlength: 2", "playlist: 2", "random: 1", "volume: 100"] {
+        assert!(status.contains(&field.to_string()), "{:?}", status);
+    }
+    assert_eq!(mpd.cmd("setvol 40"), vec!["OK"]);
+    assert!(mpd.cmd("status").contains(&"volume: 40".to_owned()));
+    assert_eq!(mpd.cmd("playlistinfo 1:"), vec![
+        "file: b c.mp3",
+        "Pos: 1",
+        "Id: 1",
+        "OK",
+    ]);
+    let max = usize::MAX.to_string();
+    assert_eq!(mpd.cmd(&format!("playlistinfo {}", max)), vec![format!(
+        "ACK [2@0] {{playlistinfo}} Bad range: {}",
+        max
+    )]);
+
+    // Another client starts playing, the idle one notices
+    mpd.writer.write_all(b"idle player\n").unwrap();
+    // Give it the time to look at the state before the change
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(Mpd::connect(port).cmd("play 1"), vec!["OK"]);
+    assert_eq!(mpd.answer(), vec!["changed: player", "OK"]);
+    let status = mpd.cmd("status");
+    for field in &["state: play", "song: 1", "songid: 1"] {
+        assert!(status.contains(&field.to_string()), "{:?}", status);
+    }
+    assert_eq!(mpd.cmd("currentsong"), vec![
+        "file: b c.mp3",
+        "Title: Title",
+        "Pos: 1",
+        "Id: 1",
+        "OK",
+    ]);
+    // Now the tags are known
+    assert_eq!(mpd.cmd("playlistinfo 1")[1], "Title: Title");
+
+    // Nothing changes, so only noidle ends it
+    mpd.writer.write_all(b"idle playlist\n").unwrap();
+    assert_eq!(mpd.cmd("noidle"), vec!["OK"]);
+
+    mpd.writer.write_all(b"command_list_ok_begin\nping\nstatus\nbogus\nping\n").unwrap();
+    let list = mpd.cmd("command_list_end");
+    assert_eq!(list[0], "list_OK");
+    assert_eq!(list.last().unwrap(), "ACK [5@2] {bogus} unknown command \"bogus\"");
+    assert_eq!(list.iter().filter(|line| *line == "list_OK").count(), 2);
+
+    assert_eq!(mpd.cmd("clear"), vec!["OK"]);
+    assert!(mpd.cmd("status").contains(&"playlistlength: 0".to_owned()));
+}
+
+#[test]
+fn stale_socket() {
+    let daemon = Daemon::with_setup(FAKE_MPV, &[], |dir| {
+        // Nobody listens on it once the listener is dropped
+        drop(UnixListener::bind(dir.join("socket")).unwrap());
+    });
+    let deadline = Instant::now() + TIMEOUT;
+    let mut stream = loop {
+        if let Ok(stream) = UnixStream::connect(&daemon.socket) {
+            break stream;
+        }
+        assert!(Instant::now() < deadline, "The daemon didn't replace the socket");
+        thread::sleep(Duration::from_millis(10));
+    };
+    stream.write_all(b"version\n").unwrap();
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer).unwrap();
+    assert!(answer.starts_with("version: "));
+}
+
+#[test]
+fn socket_taken() {
+    let dir = env::temp_dir().join(format!("playlist_mgr-test-{}-taken", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("socket");
+    // Listens, but doesn't answer
+    let _listener = UnixListener::bind(&socket).unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_playlist_mgr"))
+        .arg("--socket")
+        .arg(&socket)
+        .env("HOME", &dir)
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr);
+    assert!(stderr.contains("Something else listens on"), "{}", stderr);
+    // Left alone
+    assert!(UnixStream::connect(&socket).is_ok());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn load_and_find() {
+    let daemon = Daemon::start();
+    let songs = vec![
+        daemon.song("first.mp3"),
+        daemon.song("second.ogg"),
+        daemon.song("cover.jpg"),
+        daemon.dir.join("missing.mp3"),
+    ];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+
+    let found = client.cmd("find first").unwrap();
+    assert_eq!(found, vec![format!("song: 0 {}", songs[0].display())]);
+    let found = client.cmd("find SECOND").unwrap();
+    assert_eq!(found, vec![format!("song: 1 {}", songs[1].display())]);
+    // Forbidden extension and non-existent files are not loaded
+    assert!(client.cmd("find cover").unwrap().is_empty());
+    assert!(client.cmd("find missing").unwrap().is_empty());
+
+    let third = daemon.song("third.flac");
+    client.load("append", slice::from_ref(&third));
+    let found = client.cmd("find third").unwrap();
+    assert_eq!(found, vec![format!("song: 2 {}", third.display())]);
+}
+
+#[test]
+fn load_nothing_valid() {
+    let daemon = Daemon::start();
+    let song = daemon.song("song.mp3");
+    let cover = daemon.song("cover.jpg");
+    let mut client = daemon.connect();
+    client.json("json");
+    client.load("", slice::from_ref(&song));
+    let loaded: Value = serde_json::from_str(&client.line()).unwrap();
+    assert_eq!(loaded["status"], "ok");
+
+    client.load("", &[cover.clone(), daemon.dir.join("missing.mp3")]);
+    let loaded: Value = serde_json::from_str(&client.line()).unwrap();
+    assert_eq!(loaded["error"], "no valid songs");
+    client.load("append", &[cover]);
+    let loaded: Value = serde_json::from_str(&client.line()).unwrap();
+    assert_eq!(loaded["status"], "ok");
+
+    // The original song survived both
+    let found = client.json("find song");
+    assert_eq!(found["data"]["song"], serde_json::json!([format!("0 {}", song.display())]));
+
+    // But an empty block clears the songs
+    client.load("replace", &[]);
+    let loaded: Value = serde_json::from_str(&client.line()).unwrap();
+    assert_eq!(loaded["status"], "ok");
+    let found = client.json("find song");
+    assert_eq!(found["status"], "ok");
+    assert!(found["data"].get("song").is_none());
+}
+
+#[test]
+fn load_append_or_replace() {
+    let daemon = Daemon::start();
+    let first = daemon.song("first.mp3");
+    let second = daemon.song("second.mp3");
+    let mut client = daemon.connect();
+    client.json("json");
+    // Nothing loaded yet, no need to choose
+    client.load("", slice::from_ref(&first));
+    let loaded: Value = serde_json::from_str(&client.line()).unwrap();
+    assert_eq!(loaded["status"], "ok");
+
+    for flags in &["", "append replace"] {
+        client.load(flags, slice::from_ref(&second));
+        let loaded: Value = serde_json::from_str(&client.line()).unwrap();
+        assert_eq!(loaded["error"], "specify append or replace");
+    }
+    let found = client.json("find mp3");
+    assert_eq!(found["data"]["song"], serde_json::json!([format!("0 {}", first.display())]));
+
+    client.load("append", slice::from_ref(&second));
+    let loaded: Value = serde_json::from_str(&client.line()).unwrap();
+    assert_eq!(loaded["status"], "ok");
+    let found = client.json("find mp3");
+    assert_eq!(found["data"]["song"].as_array().unwrap().len(), 2);
+
+    client.load("replace", slice::from_ref(&second));
+    let loaded: Value = serde_json::from_str(&client.line()).unwrap();
+    assert_eq!(loaded["status"], "ok");
+    let found = client.json("find mp3");
+    assert_eq!(found["data"]["song"], serde_json::json!([format!("0 {}", second.display())]));
+}
+
+#[test]
+fn legacy_load() {
+    let daemon = Daemon::with_args(&["--legacy-load"]);
+    let first = daemon.song("first.mp3");
+    let second = daemon.song("second.mp3");
+    let mut client = daemon.connect();
+    client.load("", &[first]);
+    client.load("", slice::from_ref(&second));
+    let found = client.cmd("find mp3").unwrap();
+    assert_eq!(found, vec![format!("song: 0 {}", second.display())]);
+}
+
+#[test]
+fn playlist_order() {
+    let daemon = Daemon::start();
+    let dir = daemon.song("album/track2.mp3").parent().unwrap().to_owned();
+    daemon.song("album/track10.mp3");
+    let mut client = daemon.connect();
+    client.load("order=name", slice::from_ref(&dir));
+    let found = client.cmd("find track").unwrap();
+    assert_eq!(
+        found,
+        vec![
+            format!("song: 0 {}", dir.join("track10.mp3").display()),
+            format!("song: 1 {}", dir.join("track2.mp3").display()),
+        ]
+    );
+    // The reload keeps the order of the load
+    client.cmd("reload").unwrap();
+    assert_eq!(client.cmd("find track").unwrap(), found);
+
+    // Natural by default
+    client.load("replace", slice::from_ref(&dir));
+    let found = client.cmd("find track").unwrap();
+    assert_eq!(
+        found,
+        vec![
+            format!("song: 0 {}", dir.join("track2.mp3").display()),
+            format!("song: 1 {}", dir.join("track10.mp3").display()),
+        ]
+    );
+
+    client.json("json");
+    client.load("replace order=shuffled", &[dir]);
+    let loaded: Value = serde_json::from_str(&client.line()).unwrap();
+    assert_eq!(loaded["error"], "Unknown order shuffled");
+}
+
+#[test]
+fn load_fd() {
+    let daemon = Daemon::start();
+    let first = daemon.song("first.mp3");
+    let second = daemon.song("second.mp3");
+    let list = daemon.dir.join("list");
+    fs::write(&list, format!("{}\n\n{}\n", first.display(), second.display())).unwrap();
+    let list = fs::File::open(&list).unwrap();
+    let mut client = daemon.connect();
+
+    client.send_fd("load-fd", &list);
+    assert!(client.response().unwrap().is_empty());
+    let found = client.cmd("find mp3").unwrap();
+    assert_eq!(
+        found,
+        vec![
+            format!("song: 0 {}", first.display()),
+            format!("song: 1 {}", second.display()),
+        ]
+    );
+
+    // Each descriptor is used once
+    assert_eq!(client.cmd("load-fd append").unwrap_err(), "no file descriptor received");
+}
+
+#[test]
+fn export() {
+    let daemon = Daemon::start();
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3"), daemon.song("c.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    let before = client.cmd("find mp3").unwrap();
+    let exported = daemon.dir.join("exported.m3u");
+    let cmd = format!("export {}", exported.display());
+    assert_eq!(client.cmd(&cmd).unwrap(), vec!["songs: 3"]);
+    let content = fs::read_to_string(&exported).unwrap();
+    assert!(content.starts_with("#EXTM3U\n"), "{}", content);
+
+    client.load("replace", &[daemon.song("other.mp3")]);
+    let exported = fs::File::open(&exported).unwrap();
+    client.send_fd("load-fd replace", &exported);
+    assert!(client.response().unwrap().is_empty());
+    assert_eq!(client.cmd("find mp3").unwrap(), before);
+
+    let unwritable = daemon.dir.join("missing/exported.m3u");
+    assert!(client.cmd(&format!("export {}", unwritable.display())).is_err());
+}
+
+#[test]
+fn export_over_tcp() {
+    let args = ["--listen-tcp", "127.0.0.1:0", "--log-file", "log"];
+    let vars = [("RUST_LOG", "playlist_mgr::tcp=info")];
+    let daemon = Daemon::with_env(FAKE_MPV, &args, &vars, |_| ());
+    let port = daemon.tcp_port();
+    let exported = daemon.dir.join("exported.m3u");
+
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+    stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+    stream.write_all(format!("export {}\n", exported.display()).as_bytes()).unwrap();
+    let mut answer = String::new();
+    BufReader::new(stream).read_line(&mut answer).unwrap();
+    assert_eq!(answer, "ERR export is not allowed over TCP, use save\n");
+    assert!(!exported.exists());
+}
+
+#[test]
+fn saved_playlists() {
+    let daemon = Daemon::with_args(&["--playlist-dir", "lists"]);
+    let songs = vec![daemon.song("a.mp3"), daemon.song("lists/inside.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    let before = client.cmd("find mp3").unwrap();
+    assert_eq!(client.cmd("save my list").unwrap(), vec!["songs: 2"]);
+    let content = fs::read_to_string(daemon.dir.join("lists/my list.m3u8")).unwrap();
+    assert!(content.ends_with("\ninside.mp3\n"), "{}", content);
+
+    client.load("replace", &[daemon.song("other.mp3")]);
+    assert!(client.cmd("loadlist replace my list").unwrap().is_empty());
+    assert_eq!(client.cmd("find mp3").unwrap(), before);
+
+    assert_eq!(client.cmd("loadlist missing").unwrap_err(), "No playlist missing");
+    assert_eq!(client.cmd("save ../escape").unwrap_err(), "Invalid playlist name ../escape");
+    assert_eq!(client.cmd("save").unwrap_err(), "Missing playlist name");
+}
+
+#[test]
+fn urls() {
+    let url = "https://radio.example.com/stream.mp3";
+    let daemon = Daemon::with_args(&["--allow-urls"]);
+    let mut client = daemon.connect();
+    client.load("", &[PathBuf::from(url)]);
+    assert_eq!(client.cmd("find radio").unwrap(), vec![format!("song: 0 {}", url)]);
+
+    // Only with the option
+    let daemon = Daemon::start();
+    let mut client = daemon.connect();
+    client.json("json");
+    client.load("", &[PathBuf::from(url)]);
+    let loaded: Value = serde_json::from_str(&client.line()).unwrap();
+    assert_eq!(loaded["error"], "no valid songs");
+}
+
+#[test]
+fn stream_title() {
+    let event = r#"{"event":"property-change","id":0,"name":"media-title","data":"Live Song"}"#;
+    let mpv = format!("{}echo '{}' >&4\n{}", RECORDING_MPV, event, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &["--allow-urls"]);
+    let url = "http://radio.example.com/live";
+    let mut client = daemon.connect();
+    client.load("", &[PathBuf::from(url)]);
+    client.send("play");
+    assert_eq!(daemon.played(1), vec![PathBuf::from(url)]);
+
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        if let Ok(current) = client.cmd("current") {
+            if current.contains(&"stream-title: Live Song".to_owned()) {
+                assert_eq!(current[0], format!("song: {}", url));
+                break;
+            }
+        }
+        assert!(Instant::now() < deadline, "The stream title didn't arrive");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let status = client.cmd("status").unwrap();
+    assert!(status.contains(&"stream-title: Live Song".to_owned()), "{:?}", status);
+}
+
+#[test]
+fn forbidden() {
+    let daemon = Daemon::start();
+    let songs = vec![daemon.song("song.mp3"), daemon.song("song.flac"), daemon.song("notes.txt")];
+    let mut client = daemon.connect();
+    let exts = client.cmd("forbidden list").unwrap();
+    assert!(exts.contains(&"ext: txt".to_owned()));
+    assert!(!exts.contains(&"ext: flac".to_owned()));
+
+    assert!(client.cmd("forbidden add .FLAC").unwrap().contains(&"ext: flac".to_owned()));
+    assert!(!client.cmd("forbidden remove txt").unwrap().contains(&"ext: txt".to_owned()));
+    assert_eq!(client.cmd("forbidden add").unwrap_err(), "Missing extension");
+    client.load("", &songs);
+    assert_eq!(client.cmd("find song").unwrap(), vec![format!("song: 0 {}", songs[0].display())]);
+    assert_eq!(client.cmd("find notes").unwrap(), vec![format!("song: 1 {}", songs[2].display())]);
+}
+
+#[test]
+fn directories_and_reload() {
+    let daemon = Daemon::start();
+    let first = daemon.song("music/b/first.mp3");
+    let second = daemon.song("music/a/second.mp3");
+    let mut client = daemon.connect();
+    client.load("", &[daemon.dir.join("music")]);
+    let found = client.cmd("find mp3").unwrap();
+    assert_eq!(found, vec![
+        format!("song: 0 {}", second.display()),
+        format!("song: 1 {}", first.display()),
+    ]);
+
+    fs::remove_file(&first).unwrap();
+    let third = daemon.song("music/c/third.mp3");
+    assert_eq!(client.cmd("reload").unwrap(), vec!["added: 1", "removed: 1"]);
+    let found = client.cmd("find mp3").unwrap();
+    assert_eq!(found, vec![
+        format!("song: 0 {}", second.display()),
+        format!("song: 1 {}", third.display()),
+    ]);
+}
+
+#[test]
+fn expansion() {
+    let daemon = Daemon::start();
+    let tilde = daemon.song("tilde.mp3");
+    let var = daemon.song("var.mp3");
+    let braces = daemon.song("braces.mp3");
+    let mut client = daemon.connect();
+    client.send("load");
+    client.send("~/tilde.mp3");
+    client.send("$HOME/var.mp3");
+    client.send("${HOME}/braces.mp3");
+    client.send("");
+    let found = client.cmd("find mp3").unwrap();
+    assert_eq!(found, vec![
+        format!("song: 0 {}", tilde.display()),
+        format!("song: 1 {}", var.display()),
+        format!("song: 2 {}", braces.display()),
+    ]);
+}
+
+#[test]
+fn crlf() {
+    let daemon = Daemon::start();
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    client.send_raw(format!("load\r\n{}\r\n\r\n", song.display()).as_bytes());
+    client.send_raw(b"find song\r\n");
+    assert_eq!(client.response().unwrap(), vec![format!("song: 0 {}", song.display())]);
+}
+
+#[test]
+fn trailing_space_in_path() {
+    let daemon = Daemon::start();
+    let song = daemon.song("song.mp3 ");
+    fs::write(&song, id3_tag(&[("TIT2", "Spaced")])).unwrap();
+    daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    let info = client.cmd(&format!("info {}", song.display())).unwrap();
+    assert_eq!(info, vec!["title: Spaced"]);
+    // Even with CRLF, only the line ending goes away
+    client.send_raw(format!("info {}\r\n", song.display()).as_bytes());
+    assert_eq!(client.response().unwrap(), vec!["title: Spaced"]);
+}
+
+#[test]
+fn unknown_command_is_quiet() {
+    let daemon = Daemon::start();
+    let mut client = daemon.connect();
+    client.send("hello");
+    // The first answer belongs to the version, not to the unknown command
+    assert!(client.cmd("version").unwrap()[0].starts_with("version: "));
+}
+
+#[test]
+fn dispatch() {
+    let daemon = Daemon::start();
+    let mut client = daemon.connect();
+    // Empty lines are skipped and the words can be separated by more spaces
+    client.send("");
+    assert_eq!(client.cmd("  ping   foo").unwrap(), vec!["pong: foo"]);
+    // Only whole names match
+    client.send("pin");
+    client.send("pingpong");
+    assert!(client.cmd("ping").unwrap().is_empty());
+    client.send("quit");
+    assert!(client.closed());
+}
+
+#[test]
+fn errors() {
+    let daemon = Daemon::start();
+    let mut client = daemon.connect();
+    assert_eq!(client.cmd("chapters").unwrap_err(), "no song");
+    assert_eq!(client.cmd("speed fast").unwrap_err(), "Invalid speed fast");
+}
+
+#[test]
+fn speed_refused() {
+    // Refuses to set any property
+    let daemon = Daemon::with_mpv(&format!("{}{}", RECORDING_MPV, MPV_LOOP), &[]);
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    client.load("", &[song]);
+    client.send("play");
+    daemon.played(1);
+
+    assert_eq!(client.cmd("speed 1.5").unwrap_err(), "property unavailable");
+    assert_eq!(client.cmd("speed").unwrap(), vec!["speed: 1"]);
+}
+
+#[test]
+fn player_survives_errors() {
+    let daemon = Daemon::start();
+    let mut client = daemon.connect();
+    for cmd in &["chapters", "chapter next", "chapter 42", "bookmark clear /nothing"] {
+        assert!(client.cmd(cmd).is_err(), "{} should fail", cmd);
+    }
+    // The player still answers after all that
+    assert_eq!(client.cmd("mute").unwrap(), vec!["mute: yes"]);
+}
+
+#[test]
+fn mute() {
+    let daemon = Daemon::start();
+    let mut client = daemon.connect();
+    assert_eq!(client.cmd("mute").unwrap(), vec!["mute: yes"]);
+    assert_eq!(client.cmd("mute toggle").unwrap(), vec!["mute: no"]);
+    assert_eq!(client.cmd("mute toggle").unwrap(), vec!["mute: yes"]);
+    assert_eq!(client.cmd("unmute").unwrap(), vec!["mute: no"]);
+}
+
+#[test]
+fn duck() {
+    // Logs what it's asked to do
+    let mpv = format!(
+        "{}{}",
+        RECORDING_MPV,
+        MPV_LOOP.replace("do\n", "do\n    echo \"$line\" >> \"$(dirname \"$0\")/requests\"\n"),
+    );
+    let daemon = Daemon::with_mpv(&mpv, &[]);
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    client.load("", &[song]);
+    client.send("play");
+    daemon.played(1);
+
+    assert!(client.cmd("duck 50 0.2").unwrap().is_empty());
+    let volumes = || {
+        fs::read_to_string(daemon.dir.join("requests"))
+            .unwrap_or_default()
+            .lines()
+            .filter(|line| line.contains(r#""set_property","volume""#))
+            .map(str::to_owned)
+            .collect::<Vec<_>>()
+    };
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let volumes = volumes();
+        // The volume isn't known (the fake mpv doesn't have it), so it's taken as 100
+        if volumes.iter().any(|line| line.contains("100.0")) {
+            assert!(volumes[0].contains("50.0"), "Not ducked first: {:?}", volumes);
+            assert!(volumes.last().unwrap().contains("100.0"), "Not restored: {:?}", volumes);
+            break;
+        }
+        assert!(Instant::now() < deadline, "Volume not restored: {:?}", volumes);
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(client.cmd("duck loud 1").is_err());
+}
+
+#[test]
+fn volume() {
+    // Logs its arguments and what it's asked to do, agrees with everything
+    let mpv_loop = MPV_LOOP
+        .replace("do\n", "do\n    echo \"$line\" >> \"$(dirname \"$0\")/requests\"\n")
+        .replace("property unavailable", "success");
+    let mpv = format!("#!/bin/sh\necho \"$@\" >> \"$(dirname \"$0\")/args\"\n{}", mpv_loop);
+    let daemon = Daemon::with_mpv(&mpv, &[]);
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    // Nothing plays, so mpv's default is assumed
+    assert_eq!(client.cmd("volume").unwrap(), vec!["volume: 100"]);
+    assert_eq!(client.cmd("volume 40").unwrap(), vec!["volume: 40"]);
+    assert_eq!(client.cmd("volume +5").unwrap(), vec!["volume: 45"]);
+    assert_eq!(client.cmd("volume -50").unwrap(), vec!["volume: 0"]);
+    assert_eq!(client.cmd("volume 150").unwrap(), vec!["volume: 100"]);
+    assert_eq!(client.cmd("volume loud").unwrap_err(), "Invalid volume loud");
+    assert_eq!(client.cmd("volume 60").unwrap(), vec!["volume: 60"]);
+
+    client.load("", &[song]);
+    client.send("play");
+    let deadline = Instant::now() + TIMEOUT;
+    while !fs::read_to_string(daemon.dir.join("args")).unwrap_or_default().contains("--volume=60") {
+        assert!(Instant::now() < deadline, "Not started with the volume");
+        thread::sleep(Duration::from_millis(10));
+    }
+    while client.cmd("current").is_err() {
+        assert!(Instant::now() < deadline, "The song didn't start");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(client.cmd("volume -10").unwrap(), vec!["volume: 50"]);
+    let requests = fs::read_to_string(daemon.dir.join("requests")).unwrap();
+    assert!(requests.contains(r#""set_property","volume",50.0"#), "{}", requests);
+}
+
+#[test]
+fn seek_percent() {
+    // Logs what it's asked to do and agrees with everything
+    let mpv_loop = MPV_LOOP
+        .replace("do\n", "do\n    echo \"$line\" >> \"$(dirname \"$0\")/requests\"\n")
+        .replace("property unavailable", "success");
+    let daemon = Daemon::with_mpv(&format!("{}{}", RECORDING_MPV, mpv_loop), &[]);
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    assert_eq!(client.cmd("seek-percent 50").unwrap_err(), "no song");
+    assert_eq!(client.cmd("seek-percent half").unwrap_err(), "Invalid percent half");
+    client.load("", &[song]);
+    client.send("play");
+    daemon.played(1);
+
+    assert_eq!(client.cmd("seek-percent 50").unwrap(), vec!["percent: 50"]);
+    assert_eq!(client.cmd("seek-percent 120").unwrap(), vec!["percent: 100"]);
+    let requests = fs::read_to_string(daemon.dir.join("requests")).unwrap();
+    let seeks = requests.lines().filter(|line| line.contains(r#""seek""#)).collect::<Vec<_>>();
+    assert_eq!(seeks.len(), 2, "{}", requests);
+    assert!(seeks[0].contains(r#""command":["seek",50.0,"absolute-percent"]"#), "{}", seeks[0]);
+    assert!(seeks[1].contains(r#"["seek",100.0,"absolute-percent"]"#), "{}", seeks[1]);
+}
+
+#[test]
+fn json_control() {
+    // Logs what it's asked to do
+    let mpv_loop =
+        MPV_LOOP.replace("do\n", "do\n    echo \"$line\" >> \"$(dirname \"$0\")/requests\"\n");
+    let daemon = Daemon::with_mpv(&format!("{}{}", RECORDING_MPV, mpv_loop), &[]);
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    client.load("", &[song]);
+    client.send("play");
+    daemon.played(1);
+    // Pauses the playing song
+    client.send("play");
+    client.send("stop");
+
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let requests = fs::read_to_string(daemon.dir.join("requests")).unwrap_or_default();
+        if requests.contains(r#"{"command":["quit"],"request_id":"#) {
+            assert!(requests.contains(r#"["set_property","pause",true]"#), "{}", requests);
+            break;
+        }
+        assert!(Instant::now() < deadline, "Not stopped: {}", requests);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn pause_state() {
+    // Agrees with everything and says it started paused
+    let mpv_loop = r#"answer() {
+    id=$(echo "$line" | sed 's/.*"request_id":\([0-9]*\).*/\1/')
+    echo "{\"request_id\":$id,\"error\":\"success\",\"data\":null}" >&4
+}
+while read -r line <&4; do
+    case "$line" in
+        *'["quit"]'*) exit 0 ;;
+        *'"observe_property"'*'"pause"'*)
+            answer
+            echo '{"event":"property-change","id":0,"name":"pause","data":true}' >&4 ;;
+        *request_id*) answer ;;
+    esac
+done
+"#;
+    let daemon = Daemon::with_mpv(&format!("{}{}", RECORDING_MPV, mpv_loop), &[]);
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    client.load("", &[song]);
+    client.send("play");
+    daemon.played(1);
+
+    let mut paused = || {
+        let status = client.cmd("status").unwrap();
+        status.iter().find(|line| line.starts_with("paused: ")).cloned()
+    };
+    let deadline = Instant::now() + TIMEOUT;
+    while paused().as_deref() != Some("paused: true") {
+        assert!(Instant::now() < deadline, "Pause not noticed");
+        thread::sleep(Duration::from_millis(10));
+    }
+    for &(cmd, expected) in &[
+        ("resume", "false"),
+        ("resume", "false"),
+        ("pause", "true"),
+        ("pause", "true"),
+        ("toggle", "false"),
+        ("toggle", "true"),
+    ] {
+        assert!(client.cmd(cmd).unwrap().is_empty());
+        let status = client.cmd("status").unwrap();
+        assert!(status.contains(&format!("paused: {}", expected)), "{}: {:?}", cmd, status);
+    }
+}
+
+#[test]
+fn seek() {
+    // Logs what it's asked to do and agrees with everything
+    let mpv_loop = MPV_LOOP
+        .replace("do\n", "do\n    echo \"$line\" >> \"$(dirname \"$0\")/requests\"\n")
+        .replace("property unavailable", "success");
+    let daemon = Daemon::with_mpv(&format!("{}{}", RECORDING_MPV, mpv_loop), &[]);
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    assert_eq!(client.cmd("seek 10").unwrap_err(), "no song");
+    assert_eq!(client.cmd("seek later").unwrap_err(), "Invalid time later");
+    client.load("", &[song]);
+    client.send("play");
+    daemon.played(1);
+
+    for cmd in &["seek +10", "seek -5", "seek 42", "seek -0.5"] {
+        assert!(client.cmd(cmd).unwrap().is_empty());
+    }
+    let requests = fs::read_to_string(daemon.dir.join("requests")).unwrap();
+    let seeks = requests.lines().filter(|line| line.contains(r#""seek""#)).collect::<Vec<_>>();
+    assert_eq!(seeks.len(), 4, "{}", requests);
+    assert!(seeks[0].contains(r#"["seek",10.0,"relative"]"#), "{}", seeks[0]);
+    assert!(seeks[1].contains(r#"["seek",-5.0,"relative"]"#), "{}", seeks[1]);
+    assert!(seeks[2].contains(r#"["seek",42.0,"absolute"]"#), "{}", seeks[2]);
+    assert!(seeks[3].contains(r#"["seek",-0.5,"relative"]"#), "{}", seeks[3]);
+}
+
+#[test]
+fn remaining() {
+    // Knows the few properties asked for
+    let mpv_loop = r#"answer() {
+    id=$(echo "$line" | sed 's/.*"request_id":\([0-9]*\).*/\1/')
+    echo "{\"request_id\":$id,\"error\":\"success\",\"data\":$1}" >&4
+}
+while read -r line <&4; do
+    case "$line" in
+        *'["quit"]'*) exit 0 ;;
+        *'"duration"'*) answer 100 ;;
+        *'"time-pos"'*) answer 40 ;;
+        *'"speed"'*) answer 2.0 ;;
+        *request_id*) answer null ;;
+    esac
+done
+"#;
+    let daemon = Daemon::with_mpv(&format!("{}{}", RECORDING_MPV, mpv_loop), &[]);
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    assert_eq!(client.cmd("remaining").unwrap_err(), "no song");
+    client.load("", &[song]);
+    client.send("play");
+    daemon.played(1);
+
+    assert_eq!(client.cmd("remaining").unwrap(), vec!["remaining: 30"]);
+}
+
+#[test]
+fn time() {
+    // Tells where it is and how long the song is once they are observed, never when asked
+    let mpv_loop = r#"answer() {
+    id=$(echo "$line" | sed 's/.*"request_id":\([0-9]*\).*/\1/')
+    echo "{\"request_id\":$id,\"error\":\"success\",\"data\":$1}" >&4
+}
+change() {
+    echo "{\"event\":\"property-change\",\"name\":\"$1\",\"data\":$2}" >&4
+}
+while read -r line <&4; do
+    case "$line" in
+        *'["quit"]'*) exit 0 ;;
+        *observe_property*'"duration"'*) change duration 100.25 ;;
+        *observe_property*'"time-pos"'*) change time-pos 40.1234 ;;
+        *request_id*) answer null ;;
+    esac
+done
+"#;
+    let mpv = format!("{}{}", RECORDING_MPV, mpv_loop);
+    let daemon = Daemon::with_mpv(&mpv, &["--default-mode", "sequence"]);
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3")];
+    // The tags win over mpv
+    fs::write(&songs[1], id3_tag(&[("TLEN", "185500")])).unwrap();
+    let mut client = daemon.connect();
+    assert_eq!(client.cmd("time").unwrap_err(), "no song");
+    client.load("", &songs);
+    client.send("play");
+    let wait_time = |client: &mut Client, expected: &[&str]| {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            let time = client.cmd("time").unwrap_or_default();
+            if time == expected {
+                break;
+            }
+            assert!(Instant::now() < deadline, "Time not known: {:?}", time);
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    daemon.played(1);
+    wait_time(&mut client, &["elapsed: 40.123", "duration: 100.25"]);
+
+    client.send("next");
+    daemon.played(2);
+    wait_time(&mut client, &["elapsed: 40.123", "duration: 185.5"]);
+}
+
+#[test]
+fn trim_silence() {
+    // Finds 2.5 seconds of silence at the start and the last 4.75 seconds
+    let ffmpeg = "#!/bin/sh\ncat >&2 <<EOF
+  Duration: 00:03:00.00, start: 0.000000, bitrate: 128 kb/s
+[silencedetect @ 0x1] silence_start: 0
+[silencedetect @ 0x1] silence_end: 2.5 | silence_duration: 2.5
+[silencedetect @ 0x1] silence_start: 175.25
+[silencedetect @ 0x1] silence_end: 180 | silence_duration: 4.75
+EOF
+";
+    let mpv = format!("#!/bin/sh\necho \"$@\" >> \"$(dirname \"$0\")/args\"\n{}", MPV_LOOP);
+    let args = ["--trim-silence", "--ffmpeg", "./ffmpeg"];
+    let daemon = Daemon::with_setup(&mpv, &args, |dir| {
+        fs::write(dir.join("ffmpeg"), ffmpeg).unwrap();
+        fs::set_permissions(dir.join("ffmpeg"), Permissions::from_mode(0o755)).unwrap();
+    });
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    client.load("", &[song]);
+    client.send("mode circular");
+    client.send("play");
+    // Not known the first time, only probed
+    let args = daemon.wait_file("args", |args| args.lines().count() == 1);
+    assert!(!args.contains("--start"), "{}", args);
+    assert!(!args.contains("--end"), "{}", args);
+    daemon.wait_file(".clue_play_silence", |stored| stored.contains("song.mp3"));
+
+    client.send("next");
+    let args = daemon.wait_file("args", |args| args.lines().count() == 2);
+    let second = args.lines().nth(1).unwrap();
+    assert!(second.contains("--start=2.5 "), "{}", second);
+    assert!(second.contains("--end=175.25 "), "{}", second);
+}
+
+#[test]
+fn auto_gain() {
+    // The per-frame lines before the summary must not be mistaken for it
+    let ffmpeg = "#!/bin/sh\ncat >&2 <<EOF
+[Parsed_ebur128_0 @ 0x1] t: 0.1 TARGET:-23 LUFS M: -120.7 S:-120.7 I: -70.0 LUFS LRA: 0.0 LU
+[Parsed_ebur128_0 @ 0x1] Summary:
+
+  Integrated loudness:
+    I:         -14.0 LUFS
+    Threshold: -24.6 LUFS
+
+  True peak:
+    Peak:       -1.0 dBFS
+EOF
+";
+    let mpv = format!("#!/bin/sh\necho \"$@\" >> \"$(dirname \"$0\")/args\"\n{}", MPV_LOOP);
+    let args = ["--auto-gain", "--ffmpeg", "./ffmpeg"];
+    let daemon = Daemon::with_setup(&mpv, &args, |dir| {
+        fs::write(dir.join("ffmpeg"), ffmpeg).unwrap();
+        fs::set_permissions(dir.join("ffmpeg"), Permissions::from_mode(0o755)).unwrap();
+    });
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    client.load("", &[song]);
+    client.send("mode circular");
+    client.send("play");
+    // Plays at the base volume until measured
+    let args = daemon.wait_file("args", |args| args.lines().count() == 1);
+    assert!(!args.contains("volume="), "{}", args);
+    daemon.wait_file(".clue_play_loudness", |stored| stored.contains("song.mp3"));
+
+    client.send("next");
+    let args = daemon.wait_file("args", |args| args.lines().count() == 2);
+    let second = args.lines().nth(1).unwrap();
+    // Brought from -14 to -18 LUFS
+    assert!(second.contains("--af-add=lavfi=[volume=-4dB] "), "{}", second);
+}
+
+#[test]
+fn mpv_profile_and_mode_args() {
+    let mpv = format!("#!/bin/sh\necho \"$@\" >> \"$(dirname \"$0\")/args\"\n{}", MPV_LOOP);
+    let daemon = Daemon::with_mpv(
+        &mpv,
+        &[
+            "--mpv-profile",
+            "audio",
+            "--mode-args",
+            "sequence:--no-video",
+            "--mode-args",
+            "random:--shuffle",
+        ],
+    );
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    client.load("", &[song]);
+    client.send("mode sequence");
+    client.send("play");
+    let deadline = Instant::now() + TIMEOUT;
+    let args = loop {
+        let args = fs::read_to_string(daemon.dir.join("args")).unwrap_or_default();
+        if !args.is_empty() {
+            break args;
+        }
+        assert!(Instant::now() < deadline, "mpv not started");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(args.contains("--input-ipc-client=fd://4 --profile=audio --no-video"), "{}", args);
+    assert!(!args.contains("--shuffle"), "{}", args);
+
+    // Taking over the control channel is refused
+    let refused = Command::new(env!("CARGO_BIN_EXE_playlist_mgr"))
+        .arg("--mode-args")
+        .arg("random:--input-ipc-client=fd://5")
+        .output()
+        .unwrap();
+    assert_eq!(refused.status.code(), Some(1));
+}
+
+#[test]
+fn out_of_descriptors() {
+    const LIMIT: usize = 64;
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_fd_limit(LIMIT, &mpv, &["--failure-backoff", "100"]);
+    let song = daemon.song("song.mp3");
+    // The error of next is what we are after
+    let mut client = daemon.connect_answering();
+    client.load("", slice::from_ref(&song));
+    // Done with the loading, which opens files too
+    client.response().unwrap();
+    client.cmd("mode circular").unwrap();
+
+    // Use up all the descriptors of the daemon by sending it some to keep
+    let open = || fs::read_dir(format!("/proc/{}/fd", daemon.child.id())).unwrap().count();
+    let spare = fs::File::open(&song).unwrap();
+    let mut fillers = Vec::new();
+    while open() < LIMIT {
+        let mut filler = daemon.connect();
+        filler.cmd("ping").unwrap();
+        // At most 16 are kept for each connection
+        for _ in 0..(LIMIT - open()).min(16) {
+            filler.send_fd("ping", &spare);
+            filler.response().unwrap();
+        }
+        fillers.push(filler);
+    }
+
+    let err = client.cmd("next").unwrap_err();
+    assert!(err.starts_with("Can't create the control channel for mpv"), "{}", err);
+    // Once there are some again, the song gets played
+    drop(fillers);
+    assert_eq!(daemon.played(1), vec![song]);
+}
+
+#[test]
+fn failing_songs_back_off() {
+    let daemon = Daemon::with_mpv(
+        "#!/bin/sh\necho started >> \"$(dirname \"$0\")/starts\"\nexit 1\n",
+        &["--failure-backoff", "500"],
+    );
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3"), daemon.song("c.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode circular");
+    client.send("play");
+    thread::sleep(Duration::from_secs(2));
+    let starts = fs::read_to_string(daemon.dir.join("starts")).unwrap_or_default();
+    let starts = starts.lines().count();
+    assert!(starts >= 1, "Nothing started");
+    assert!(starts <= 6, "Too many attempts: {}", starts);
+}
+
+#[test]
+fn random_album() {
+    let mpv = format!("{}sleep 0.05\n", RECORDING_MPV);
+    let daemon = Daemon::with_mpv(&mpv, &["--min-play-time", "0"]);
+    let albums = ["a", "b", "c"]
+        .iter()
+        .map(|album| {
+            (1..=3)
+                .map(|track| daemon.song(&format!("{}/{:02}.mp3", album, track)))
+                .collect::<Vec<_>>()
+        }).collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    client.load("", &albums.concat());
+    client.send("mode album-random");
+    client.send("play");
+
+    let played = daemon.played(12);
+    for chunk in played[..12].chunks(3) {
+        assert!(albums.iter().any(|album| &album[..] == chunk), "Album mixed up: {:?}", chunk);
+    }
+}
+
+#[test]
+fn random_album_by_tags() {
+    let mpv = format!("{}sleep 0.05\n", RECORDING_MPV);
+    let daemon = Daemon::with_mpv(&mpv, &["--min-play-time", "0", "--group-by", "album"]);
+    let songs = (1..=4).map(|i| daemon.song(&format!("mix/{}.mp3", i))).collect::<Vec<_>>();
+    for (song, album) in songs.iter().zip(&["First", "Second", "First", "Second"]) {
+        fs::write(song, id3_tag(&[("TALB", album)])).unwrap();
+    }
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode album-random");
+    client.send("play");
+
+    let played = daemon.played(4);
+    let first = [songs[0].clone(), songs[2].clone()];
+    let second = [songs[1].clone(), songs[3].clone()];
+    for chunk in played[..4].chunks(2) {
+        assert!(chunk == first || chunk == second, "Album mixed up: {:?}", chunk);
+    }
+}
+
+#[test]
+fn filter() {
+    let mpv = format!("{}sleep 0.05\n", RECORDING_MPV);
+    let daemon = Daemon::with_mpv(&mpv, &["--min-play-time", "0", "--on-empty", "loop"]);
+    let songs = (1..=4).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    for (song, genre) in songs.iter().zip(&["Jazz", "Rock", "Acid Jazz", "Pop"]) {
+        fs::write(song, id3_tag(&[("TCON", genre)])).unwrap();
+    }
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    assert_eq!(client.cmd("filter genre=jazz").unwrap(), vec!["filter: genre=jazz"]);
+    assert_eq!(client.cmd("filter mood=calm").unwrap_err(), "Invalid filter mood=calm");
+    client.send("play");
+
+    let played = daemon.played(6);
+    assert!(
+        played.iter().all(|song| *song == songs[0] || *song == songs[2]),
+        "Not filtered: {:?}",
+        played
+    );
+    assert!(client.cmd("filter clear").unwrap().is_empty());
+}
+
+#[test]
+fn filter_rare_song() {
+    let daemon = Daemon::start();
+    let songs = (0..100).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    fs::write(&songs[42], id3_tag(&[("TCON", "Jazz")])).unwrap();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.cmd("filter genre=jazz").unwrap();
+    // Waits for the tags, the random pick uses only the ones read already
+    client.cmd("total-duration").unwrap();
+    // The random pick is out of the allowed songs, however few of them there are
+    let peek = client.cmd("peek").unwrap();
+    assert_eq!(peek, vec![format!("song: {}", songs[42].display()), "genre: Jazz".to_owned()]);
+}
+
+/// An ID3v2.3 tag with just a picture (APIC frame) in it.
+fn tag_with_picture(mime: &str, picture: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0];
+    frame.extend_from_slice(mime.as_bytes());
+    // Terminated mime type, front cover, empty description
+    frame.extend_from_slice(&[0, 3, 0]);
+    frame.extend_from_slice(picture);
+    let mut tag = b"ID3\x03\x00\x00".to_vec();
+    let size = frame.len() + 10;
+    // Sync-safe integer, 7 bits per byte
+    tag.extend((0..4).rev().map(|i| (size >> (7 * i)) as u8 & 0x7f));
+    tag.extend_from_slice(b"APIC");
+    tag.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    tag.extend_from_slice(&[0, 0]);
+    tag.extend(frame);
+    tag
+}
+
+#[test]
+fn artwork() {
+    let daemon = Daemon::start();
+    let song = daemon.song("album/a.mp3");
+    let picture = b"\x89PNG not really";
+    fs::write(&song, tag_with_picture("image/png", picture)).unwrap();
+    let plain = daemon.song("other/b.mp3");
+    let folder = daemon.dir.join("other/folder.jpg");
+    fs::write(&folder, b"jpeg").unwrap();
+    let lonely = daemon.song("lonely/c.mp3");
+    let mut client = daemon.connect();
+
+    let fields = client.cmd(&format!("artwork {}", song.display())).unwrap();
+    let cover = fields[0].trim_start_matches("cover: ");
+    assert!(cover.starts_with(daemon.dir.join(".clue_play_covers").to_str().unwrap()));
+    assert!(cover.ends_with(".png"), "{}", cover);
+    assert_eq!(fs::read(cover).unwrap(), picture);
+
+    // The folder image as a fallback
+    let cmd = format!("artwork {}", plain.display());
+    assert_eq!(client.cmd(&cmd).unwrap(), vec![format!("cover: {}", folder.display())]);
+    let cmd = format!("artwork {}", lonely.display());
+    assert_eq!(client.cmd(&cmd).unwrap_err(), "no artwork");
+    assert_eq!(client.cmd("artwork").unwrap_err(), "no song");
+}
+
+#[test]
+fn peek() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &[]);
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3"), daemon.song("c.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    let song = |path: &PathBuf| vec![format!("song: {}", path.display())];
+
+    assert_eq!(client.cmd("peek").unwrap(), song(&songs[0]));
+    // Peeking doesn't move anywhere
+    assert_eq!(client.cmd("peek").unwrap(), song(&songs[0]));
+    client.send("play");
+    assert_eq!(daemon.played(1), &songs[..1]);
+    assert_eq!(client.cmd("peek").unwrap(), song(&songs[1]));
+    client.send("next");
+    assert_eq!(daemon.played(2), &songs[..2]);
+    assert_eq!(client.cmd("peek").unwrap(), song(&songs[2]));
+}
+
+#[test]
+fn sequence_after_random() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &[]);
+    let songs = (0..5).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode random");
+    client.send("play");
+    let playing = &daemon.played(1)[0];
+    let idx = songs.iter().position(|song| song == playing).unwrap();
+
+    client.send("mode sequence");
+    let expected = songs
+        .get(idx + 1)
+        .map(|song| vec![format!("song: {}", song.display())])
+        .unwrap_or_default();
+    assert_eq!(client.cmd("peek").unwrap(), expected);
+}
+
+#[test]
+fn position() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &[]);
+    let songs = (0..4).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    assert_eq!(client.cmd("position").unwrap(), vec!["position: 0", "songs: 4"]);
+    assert_eq!(client.cmd("position 2").unwrap(), vec!["position: 2", "songs: 4"]);
+    assert_eq!(client.cmd("position 4").unwrap_err(), "Position 4 out of 4 songs");
+    assert_eq!(client.cmd("position x").unwrap_err(), "Invalid position x");
+
+    client.send("next");
+    assert_eq!(daemon.played(1), &songs[2..3]);
+    assert_eq!(client.cmd("position").unwrap(), vec!["position: 3", "songs: 4"]);
+}
+
+#[test]
+fn startup_playlist() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let args = ["--startup-playlist", "list", "--autoplay"];
+    let daemon = Daemon::with_setup(&mpv, &args, |dir| {
+        let songs = ["a.mp3", "b.mp3", "notes.txt"];
+        for song in &songs {
+            fs::write(dir.join(song), b"").unwrap();
+        }
+        let list = format!("a.mp3\n\n~/b.mp3\n{}/notes.txt\n", dir.display());
+        fs::write(dir.join("list"), list).unwrap();
+    });
+    let played = &daemon.played(1)[0];
+    assert!(played.ends_with("a.mp3") || played.ends_with("b.mp3"));
+    let found = daemon.connect().cmd("find .").unwrap();
+    assert_eq!(found.len(), 2);
+}
+
+#[test]
+fn config_file() {
+    let write_config = |dir: &Path| {
+        let config = "# Comments are fine\nfind-limit = 1\n\nunknown-key = 42\nautoplay = false\n";
+        fs::write(dir.join("settings"), config).unwrap();
+    };
+    let daemon = Daemon::with_setup(FAKE_MPV, &["--config", "settings"], write_config);
+    let songs = (0..3).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    assert_eq!(client.cmd("find mp3").unwrap(), vec![
+        format!("song: 0 {}", songs[0].display()),
+        "truncated: 3".to_owned(),
+    ]);
+
+    // The command line wins
+    let args = ["--config", "settings", "--find-limit", "5"];
+    let daemon = Daemon::with_setup(FAKE_MPV, &args, write_config);
+    let songs = (0..3).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    assert_eq!(client.cmd("find mp3").unwrap().len(), 3);
+}
+
+#[test]
+fn config_toml() {
+    let config = r#"
+# The default place, read without --config
+default-mode = "sequence"
+history-size = 1
+forbidden-ext = ["ogg", ".FLAC"]
+allowed-ext = ["txt"]
+autoplay = false
+"#;
+    let daemon = Daemon::with_setup(FAKE_MPV, &[], |dir| {
+        fs::create_dir_all(dir.join(".config/playlist_mgr")).unwrap();
+        fs::write(dir.join(".config/playlist_mgr/config.toml"), config).unwrap();
+    });
+    let songs = ["a.mp3", "b.ogg", "c.flac", "d.txt"]
+        .iter()
+        .map(|name| daemon.song(name))
+        .collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    assert_eq!(client.cmd("mode").unwrap(), vec!["mode: sequence"]);
+    client.load("", &songs);
+    let found = client.cmd("find .").unwrap();
+    assert_eq!(found, vec![
+        format!("song: 0 {}", songs[0].display()),
+        format!("song: 1 {}", songs[3].display()),
+    ]);
+
+    // Only the types TOML shares with the command line make sense
+    fs::write(daemon.dir.join("bad.toml"), "[mpv]\npath = \"mpv\"\n").unwrap();
+    let bad = Command::new(env!("CARGO_BIN_EXE_playlist_mgr"))
+        .arg("--socket")
+        .arg(daemon.dir.join("bad-socket"))
+        .args(["--config", "bad.toml"])
+        .current_dir(&daemon.dir)
+        .env("HOME", &daemon.dir)
+        .output()
+        .unwrap();
+    assert_eq!(bad.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&bad.stderr);
+    assert!(stderr.contains("mpv must be a string"), "{}", stderr);
+}
+
+#[test]
+fn startup_directory() {
+    let daemon = Daemon::with_setup(FAKE_MPV, &["--startup-playlist", "music"], |dir| {
+        fs::write(dir.join("music/song.mp3"), b"").unwrap();
+    });
+    let mut client = daemon.connect();
+    let found = client.cmd("find song").unwrap();
+    // Relative to the working directory of the daemon, which is the temporary one
+    assert_eq!(found, vec!["song: 0 music/song.mp3"]);
+    // Not playing without --autoplay
+    assert_eq!(client.cmd("current").unwrap_err(), "no song");
+}
+
+#[test]
+fn history_clear() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &[]);
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3"), daemon.song("c.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    client.send("play");
+    daemon.played(1);
+    client.send("next");
+    daemon.played(2);
+
+    assert_eq!(client.cmd("history-trim 5").unwrap(), vec!["removed: 0"]);
+    assert_eq!(client.cmd("history-clear").unwrap(), vec!["removed: 1"]);
+    assert_eq!(client.cmd("history-trim x").unwrap_err(), "Invalid count x");
+    // Nothing to go back to, so the current song starts again
+    client.send("prev");
+    assert_eq!(daemon.played(3), vec![songs[0].clone(), songs[1].clone(), songs[1].clone()]);
+}
+
+#[test]
+fn clear_history_on_load() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &[]);
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    client.send("play");
+    daemon.played(1);
+    client.send("next");
+    daemon.played(2);
+
+    // Appending keeps the history
+    client.load("append clear-history", &[daemon.song("c.mp3")]);
+    client.send("prev");
+    assert_eq!(daemon.played(3)[2], songs[0]);
+    client.send("next");
+    assert_eq!(daemon.played(4)[3], songs[1]);
+
+    client.load("replace clear-history", &songs);
+    // Nothing to go back to, so the current song starts again
+    client.send("prev");
+    assert_eq!(daemon.played(5)[4], songs[1]);
+}
+
+#[test]
+fn min_history_time() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &["--min-history-time", "2000"]);
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3"), daemon.song("c.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    client.send("play");
+    daemon.played(1);
+    thread::sleep(Duration::from_millis(500));
+    client.send("next");
+    daemon.played(2);
+
+    // The first one played for too short a time
+    assert_eq!(client.cmd("history-clear").unwrap(), vec!["removed: 0"]);
+}
+
+#[test]
+fn skip() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &[]);
+    let songs = (0..5).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    client.send("play");
+    daemon.played(1);
+    client.send("next");
+    daemon.played(2);
+
+    client.cmd("skip 2").unwrap();
+    assert_eq!(daemon.played(3)[2], songs[3]);
+    // The skipped song is not in the history
+    client.cmd("skip -2").unwrap();
+    assert_eq!(daemon.played(4)[3], songs[0]);
+    assert_eq!(client.cmd("skip x").unwrap_err(), "Invalid count x");
+}
+
+#[test]
+fn skip_past_ends() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &[]);
+    let songs = (0..3).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    client.send("play");
+    daemon.played(1);
+    client.send("next");
+    daemon.played(2);
+
+    // Stops at the last song
+    client.cmd(&format!("skip {}", i64::MAX)).unwrap();
+    assert_eq!(daemon.played(3)[2], songs[2]);
+    // And at the oldest one in the history
+    client.cmd(&format!("skip {}", i64::MIN)).unwrap();
+    assert_eq!(daemon.played(4)[3], songs[0]);
+}
+
+#[test]
+fn play_now() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &[]);
+    let old = daemon.song("old.mp3");
+    let songs = (0..3).map(|i| daemon.song(&format!("album/{}.mp3", i))).collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    client.load("", slice::from_ref(&old));
+    client.send("play");
+    daemon.played(1);
+
+    let block = songs.iter().map(|song| format!("{}\n", song.display())).collect::<String>();
+    let fields = client.cmd(&format!("play-now\n{}", block)).unwrap();
+    assert_eq!(fields, vec!["songs: 3".to_owned(), format!("song: {}", songs[0].display())]);
+    assert_eq!(daemon.played(2), vec![old, songs[0].clone()]);
+    assert_eq!(client.cmd("position").unwrap(), vec!["position: 1", "songs: 3"]);
+    // The old song is forgotten, there's nothing to go back to
+    assert_eq!(client.cmd("history-clear").unwrap(), vec!["removed: 0"]);
+    assert_eq!(client.cmd("play-now\n/nothing\n").unwrap_err(), "no valid songs");
+}
+
+#[test]
+fn play_now_album_random() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &["--default-mode", "album-random"]);
+    let songs = ["c", "a", "b"]
+        .iter()
+        .map(|album| daemon.song(&format!("{}/song.mp3", album)))
+        .collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    let block = songs.iter().map(|song| format!("{}\n", song.display())).collect::<String>();
+    client.cmd(&format!("play-now\n{}", block)).unwrap();
+    // Not a random album
+    assert_eq!(daemon.played(1), vec![songs[0].clone()]);
+}
+
+#[test]
+fn alarm() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &[]);
+    let song = daemon.song("wake-up/song.mp3");
+    let mut client = daemon.connect();
+
+    let at = (Local::now() + chrono::Duration::seconds(2)).format("%H:%M:%S").to_string();
+    let dir = daemon.dir.join("wake-up");
+    let set = client.cmd(&format!("alarm {} {}", at, dir.display())).unwrap();
+    assert_eq!(set, vec![format!("alarm: 1 {} once {}", at, dir.display())]);
+    let daily = client.cmd(&format!("alarm 07:30 daily {}", dir.display())).unwrap();
+    assert_eq!(daily, vec![format!("alarm: 2 07:30:00 daily {}", dir.display())]);
+    assert_eq!(client.cmd("alarm list").unwrap(), vec![set[0].clone(), daily[0].clone()]);
+    assert!(client.cmd("alarm cancel 2").unwrap().is_empty());
+    assert_eq!(client.cmd("alarm cancel 2").unwrap_err(), "No alarm 2");
+    assert_eq!(client.cmd("alarm 25:00 ~").unwrap_err(), "Invalid time 25:00");
+    assert_eq!(client.cmd("alarm 07:00 ~/missing").unwrap_err(), "Not found ~/missing");
+
+    assert_eq!(daemon.played(1), vec![song]);
+    // Rang once, it's gone
+    assert!(client.cmd("alarm").unwrap().is_empty());
+}
+
+#[test]
+fn plan() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &[]);
+    let songs = (0..5).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    client.send("play");
+    daemon.played(1);
+
+    let planned = songs[1..4]
+        .iter()
+        .map(|song| format!("song: {}", song.display()))
+        .collect::<Vec<_>>();
+    assert_eq!(client.cmd("plan 3").unwrap(), planned);
+    assert_eq!(client.cmd("plan x").unwrap_err(), "Invalid count x");
+    // The sequence goes on after the planned songs
+    for i in 2..5 {
+        client.send("next");
+        daemon.played(i);
+    }
+    client.send("next");
+    assert_eq!(daemon.played(5), songs);
+}
+
+#[test]
+fn queue_detail() {
+    let daemon = Daemon::start();
+    let songs = (0..3).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    let tag = id3_tag(&[("TIT2", "Title"), ("TPE1", "Artist"), ("TLEN", "185000")]);
+    fs::write(&songs[1], tag).unwrap();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    let planned = client.cmd("plan 3").unwrap();
+    assert_eq!(planned.len(), 3);
+    // Only the tags read before are there
+    client.cmd(&format!("info {}", songs[1].display())).unwrap();
+    let tagged = format!("song: {}", songs[1].display());
+    let mut expected = Vec::new();
+    for song in planned {
+        let has_tags = song == tagged;
+        expected.push(song);
+        if has_tags {
+            expected.extend(vec!["title: Title", "artist: Artist", "duration: 185"]
+                .into_iter()
+                .map(String::from));
+        }
+    }
+    assert_eq!(client.cmd("queue-detail").unwrap(), expected);
+
+    client.json("json");
+    let detail = client.json("queue-detail");
+    let queued = detail["data"]["queued"].as_array().unwrap();
+    assert_eq!(queued.len(), 3);
+    let tagged = queued.iter().find(|song| song["song"] == songs[1].to_str().unwrap()).unwrap();
+    assert_eq!(tagged["title"], "Title");
+    assert_eq!(tagged["artist"], "Artist");
+    let untagged = queued.iter().find(|song| song["song"] == songs[0].to_str().unwrap()).unwrap();
+    assert_eq!(untagged.as_object().unwrap().len(), 1);
+}
+
+#[test]
+fn queue_shuffle() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &[]);
+    let songs = (0..4).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    client.send("play");
+    for i in 1..4 {
+        daemon.played(i);
+        client.send("next");
+    }
+    daemon.played(4);
+    // Back to the first one, the rest gets queued
+    client.cmd("skip -3").unwrap();
+    daemon.played(5);
+
+    assert_eq!(client.cmd("queue-shuffle 42").unwrap(), vec!["queue: 3"]);
+    assert_eq!(client.cmd("queue-shuffle x").unwrap_err(), "Invalid seed x");
+    for i in 6..=8 {
+        client.send("next");
+        daemon.played(i);
+    }
+    let played = daemon.played(8);
+    assert_eq!(&played[5..], &[songs[2].clone(), songs[1].clone(), songs[3].clone()]);
+}
+
+#[test]
+fn repeat() {
+    let mpv = format!("{}sleep 0.05\n", RECORDING_MPV);
+    let daemon = Daemon::with_mpv(&mpv, &["--min-play-time", "0"]);
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    assert_eq!(client.cmd("repeat").unwrap(), vec!["repeat: 1"]);
+    assert_eq!(client.cmd("repeat 2").unwrap(), vec!["repeat: 2"]);
+    client.send("play");
+
+    assert_eq!(daemon.played(4), [&songs[..], &songs[..]].concat());
+    // And nothing more
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(daemon.played(4).len(), 4);
+    assert_eq!(client.cmd("repeat").unwrap(), vec!["repeat: 1"]);
+    assert_eq!(client.cmd("repeat inf").unwrap(), vec!["repeat: inf"]);
+}
+
+#[test]
+fn on_empty_loop() {
+    let mpv = format!("{}sleep 0.05\n", RECORDING_MPV);
+    let daemon = Daemon::with_mpv(&mpv, &["--min-play-time", "0", "--on-empty", "loop"]);
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    client.send("play");
+
+    assert_eq!(daemon.played(5)[..5], [&songs[..], &songs[..], &songs[..1]].concat()[..]);
+}
+
+/// Plays a sequence of 3 songs up to the last one, with the given `--sequence-end` policy.
+fn at_sequence_end(policy: &str) -> (Daemon, Client, Vec<PathBuf>) {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &["--sequence-end", policy]);
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3"), daemon.song("c.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    client.send("play");
+    daemon.played(1);
+    client.send("next");
+    daemon.played(2);
+    client.send("next");
+    assert_eq!(daemon.played(3), songs);
+    client.json("json");
+    (daemon, client, songs)
+}
+
+#[test]
+fn sequence_end_stop() {
+    let (_daemon, mut client, _) = at_sequence_end("stop");
+    assert_eq!(client.json("next")["data"]["end"], "stop");
+    let deadline = Instant::now() + TIMEOUT;
+    while client.json("current")["status"] == "ok" {
+        assert!(Instant::now() < deadline, "Still playing");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn sequence_end_wrap() {
+    let (daemon, mut client, songs) = at_sequence_end("wrap");
+    assert_eq!(client.json("next")["data"]["end"], "wrap");
+    assert_eq!(daemon.played(4)[3], songs[0]);
+}
+
+#[test]
+fn sequence_end_hold() {
+    let (_daemon, mut client, songs) = at_sequence_end("hold");
+    assert_eq!(client.json("next")["error"], "at end");
+    let current = client.json("current");
+    assert_eq!(current["data"]["song"], songs[2].display().to_string());
+}
+
+#[test]
+fn max_song_length() {
+    let mpv = format!("{}sleep 0.05\n", RECORDING_MPV);
+    let daemon = Daemon::with_mpv(&mpv, &["--min-play-time", "0", "--max-song-length", "600"]);
+    let long = daemon.song("long.mp3");
+    fs::write(&long, id3_tag(&[("TLEN", "4000000")])).unwrap();
+    let short = daemon.song("short.mp3");
+    fs::write(&short, id3_tag(&[("TLEN", "180000")])).unwrap();
+    let unknown = daemon.song("unknown.mp3");
+    let mut client = daemon.connect();
+    client.load("", &[long, short.clone(), unknown.clone()]);
+    client.send("play");
+
+    let played = daemon.played(10);
+    assert!(played.iter().all(|song| *song == short || *song == unknown), "{:?}", played);
+}
+
+#[test]
+fn roots() {
+    let daemon = Daemon::with_args(&["--root", "music"]);
+    let inside = daemon.song("music/inside.mp3");
+    let outside = daemon.song("outside.mp3");
+    let traversal = daemon.dir.join("music/../outside.mp3");
+    let mut client = daemon.connect();
+    client.json("json");
+    client.load("", &[inside.clone(), outside.clone(), traversal.clone()]);
+    let loaded: Value = serde_json::from_str(&client.line()).unwrap();
+    assert_eq!(loaded["status"], "ok");
+    assert_eq!(loaded["data"]["skip"], serde_json::json!([
+        format!("outside-root {}", outside.display()),
+        format!("outside-root {}", traversal.display()),
+    ]));
+    let found = client.json("find mp3");
+    assert_eq!(found["data"]["song"], serde_json::json!([
+        format!("0 {}", inside.canonicalize().unwrap().display()),
+    ]));
+}
+
+#[test]
+fn exclude_pattern() {
+    let daemon = Daemon::with_args(&[
+        "--exclude-pattern",
+        "*/samples/*",
+        "--exclude-pattern",
+        "*preview*",
+    ]);
+    let kept = daemon.song("music/album/song.mp3");
+    let sample = daemon.song("music/album/samples/sample.mp3");
+    let preview = daemon.song("music/album/song-preview.mp3");
+    let mut client = daemon.connect();
+    client.json("json");
+    client.load("", &[daemon.dir.join("music")]);
+    let loaded: Value = serde_json::from_str(&client.line()).unwrap();
+    assert_eq!(loaded["status"], "ok");
+    assert_eq!(loaded["data"]["skip"], serde_json::json!([
+        format!("excluded {}", sample.display()),
+        format!("excluded {}", preview.display()),
+    ]));
+    let found = client.json("find mp3");
+    assert_eq!(found["data"]["song"], serde_json::json!([format!("0 {}", kept.display())]));
+}
+
+#[test]
+fn declick() {
+    let mpv = "#!/bin/sh\necho \"$@\" >> \"$(dirname \"$0\")/args\"\nsleep 0.05\n";
+    let daemon = Daemon::with_mpv(
+        mpv,
+        &["--min-play-time", "0", "--declick", "--declick-time", "50"],
+    );
+    let known = daemon.song("known.mp3");
+    fs::write(&known, id3_tag(&[("TLEN", "180000")])).unwrap();
+    let unknown = daemon.song("unknown.mp3");
+    let mut client = daemon.connect();
+    client.load("", &[known, unknown]);
+    client.send("mode sequence");
+    client.send("play");
+
+    let deadline = Instant::now() + TIMEOUT;
+    let args = loop {
+        let args = fs::read_to_string(daemon.dir.join("args")).unwrap_or_default();
+        if args.lines().count() >= 2 {
+            break args;
+        }
+        assert!(Instant::now() < deadline, "Songs not played: {}", args);
+        thread::sleep(Duration::from_millis(10));
+    };
+    let args = args.lines().collect::<Vec<_>>();
+    let both = "--af-add=lavfi=[afade=t=in:st=0:d=0.05,afade=t=out:st=179.95:d=0.05]";
+    assert!(args[0].contains(both), "{}", args[0]);
+    // The length isn't known, so only the start
+    assert!(args[1].contains("--af-add=lavfi=[afade=t=in:st=0:d=0.05]"), "{}", args[1]);
+}
+
+/// A minimal ID3v2.3 tag with the given text frames.
+fn id3_tag(frames: &[(&str, &str)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (id, text) in frames {
+        body.extend_from_slice(id.as_bytes());
+        body.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+        // No flags, ISO-8859-1 encoding
+        body.extend_from_slice(&[0, 0, 0]);
+        body.extend_from_slice(text.as_bytes());
+    }
+    let size = body.len() as u32;
+    let mut tag = b"ID3\x03\x00\x00".to_vec();
+    // Synchsafe integer, 7 bits per byte
+    tag.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7f) as u8));
+    tag.extend(body);
+    tag
+}
+
+#[test]
+fn info() {
+    let daemon = Daemon::start();
+    let song = daemon.song("song.mp3");
+    let tag = id3_tag(&[
+        ("TIT2", "Title"),
+        ("TPE1", "Artist"),
+        ("TALB", "Album"),
+        ("TRCK", "3"),
+        ("TCON", "Rock"),
+        ("TLEN", "123456"),
+    ]);
+    fs::write(&song, tag).unwrap();
+    let untagged = daemon.song("untagged.mp3");
+    daemon.song("cover.jpg");
+    let mut client = daemon.connect();
+
+    assert_eq!(client.cmd(&format!("info {}", song.display())).unwrap(), vec![
+        "title: Title",
+        "artist: Artist",
+        "album: Album",
+        "track: 3",
+        "genre: Rock",
+        "duration: 123.456",
+    ]);
+    assert!(client.cmd(&format!("info {}", untagged.display())).unwrap().is_empty());
+    assert!(client.cmd("info ~/missing.mp3").unwrap_err().starts_with("Not a file"));
+    assert!(client.cmd("info ~/cover.jpg").unwrap_err().starts_with("Forbidden file"));
+}
+
+#[test]
+fn announce() {
+    let daemon = Daemon::with_args(&[
+        "--announce-command",
+        "echo \"$PM_PATH|${PM_TITLE-none}\" >> announced",
+    ]);
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    client.load("", slice::from_ref(&song));
+    client.send("play");
+
+    let announced = daemon.dir.join("announced");
+    let deadline = Instant::now() + TIMEOUT;
+    while fs::read_to_string(&announced).unwrap_or_default().is_empty() {
+        assert!(Instant::now() < deadline, "Nothing announced");
+        thread::sleep(Duration::from_millis(10));
+    }
+    let announced = fs::read_to_string(&announced).unwrap();
+    assert_eq!(announced, format!("{}|none\n", song.display()));
+}
+
+#[test]
+fn announce_delay() {
+    let args = [
+        "--announce-command",
+        "echo \"$PM_PATH\" >> announced",
+        // Plenty of time to skip the songs, even on a busy machine
+        "--announce-delay",
+        "3000",
+    ];
+    let daemon = Daemon::with_mpv(&format!("{}{}", RECORDING_MPV, MPV_LOOP), &args);
+    let songs = (0..4).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    client.send("play");
+    daemon.played(1);
+    let skipping = Instant::now();
+    for i in 2..=4 {
+        client.send("next");
+        daemon.played(i);
+    }
+    assert!(skipping.elapsed() < Duration::from_millis(3000), "Skipping took too long");
+
+    let announced = daemon.dir.join("announced");
+    let deadline = Instant::now() + TIMEOUT;
+    while fs::read_to_string(&announced).unwrap_or_default().is_empty() {
+        assert!(Instant::now() < deadline, "Nothing announced");
+        thread::sleep(Duration::from_millis(10));
+    }
+    // Any announcements of the skipped songs would have been due before the last one, give
+    // their commands a while to finish
+    thread::sleep(Duration::from_millis(1000));
+    let announced = fs::read_to_string(&announced).unwrap();
+    assert_eq!(announced, format!("{}\n", songs[3].display()));
+}
+
+#[test]
+fn current_duration() {
+    let daemon = Daemon::with_mpv(&format!("#!/bin/sh\n{}", MPV_LOOP), &[]);
+    let song = daemon.song("song.mp3");
+    fs::write(&song, id3_tag(&[("TIT2", "Title"), ("TLEN", "185000")])).unwrap();
+    let mut client = daemon.connect();
+    assert_eq!(client.cmd("current").unwrap_err(), "no song");
+    client.load("", slice::from_ref(&song));
+    client.send("play");
+
+    let deadline = Instant::now() + TIMEOUT;
+    let current = loop {
+        if let Ok(current) = client.cmd("current") {
+            break current;
+        }
+        assert!(Instant::now() < deadline, "The song didn't start");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(current[..3], [
+        format!("song: {}", song.display()),
+        "title: Title".to_owned(),
+        "duration: 185".to_owned(),
+    ]);
+    assert!(current[3].starts_with("pid: "));
+}
+
+#[test]
+fn status() {
+    let daemon = Daemon::with_mpv(&format!("#!/bin/sh\n{}", MPV_LOOP), &[]);
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3"), daemon.song("c.mp3")];
+    fs::write(&songs[0], id3_tag(&[("TIT2", "Title")])).unwrap();
+    let mut client = daemon.connect();
+    assert_eq!(client.cmd("status").unwrap(), vec![
+        "playing: false",
+        "mode: random",
+        "repeat: 1",
+        "songs: 0",
+        "position: 0",
+        "queued: 0",
+        "history: 0",
+    ]);
+    client.load("", &songs);
+    client.send("mode sequence");
+    assert_eq!(client.cmd("plan 2").unwrap().len(), 2);
+    client.send("play");
+
+    let deadline = Instant::now() + TIMEOUT;
+    while client.cmd("current").is_err() {
+        assert!(Instant::now() < deadline, "The song didn't start");
+        thread::sleep(Duration::from_millis(10));
+    }
+    client.cmd("repeat inf").unwrap();
+    client.cmd("filter title=title").unwrap();
+    client.cmd("filter year=1999").unwrap();
+    let position = client.cmd("position").unwrap().remove(0);
+    assert_eq!(client.cmd("status").unwrap(), vec![
+        "playing: true".to_owned(),
+        "paused: false".to_owned(),
+        "mode: sequence".to_owned(),
+        "repeat: inf".to_owned(),
+        "filter: title=title".to_owned(),
+        "filter: year=1999".to_owned(),
+        format!("song: {}", songs[0].display()),
+        "title: Title".to_owned(),
+        "songs: 3".to_owned(),
+        position,
+        "queued: 1".to_owned(),
+        "history: 0".to_owned(),
+    ]);
+}
+
+#[test]
+fn history() {
+    let args = ["--default-mode", "sequence"];
+    let daemon = Daemon::with_mpv(&format!("{}{}", RECORDING_MPV, MPV_LOOP), &args);
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3"), daemon.song("c.mp3")];
+    let mut client = daemon.connect();
+    assert!(client.cmd("history").unwrap().is_empty());
+    client.load("", &songs);
+    client.send("play");
+    daemon.played(1);
+    client.send("next");
+    daemon.played(2);
+    client.send("next");
+    daemon.played(3);
+
+    // Parts of the lines, the time and the path
+    let played = |client: &mut Client, cmd: &str| {
+        client
+            .cmd(cmd)
+            .unwrap()
+            .into_iter()
+            .map(|line| {
+                let mut parts = line["played: ".len()..].splitn(2, ' ');
+                let time = parts.next().unwrap().to_owned();
+                (time, PathBuf::from(parts.next().unwrap()))
+            }).collect::<Vec<_>>()
+    };
+    let history = played(&mut client, "history");
+    assert_eq!(history.len(), 2, "{:?}", history);
+    assert_eq!(history[0].1, songs[0]);
+    assert_eq!(history[1].1, songs[1]);
+    let time = &history[0].0;
+    assert!(time.len() == 25 && &time[10..11] == "T", "{}", time);
+    assert_eq!(played(&mut client, "history 1"), history[1..].to_vec());
+    assert_eq!(client.cmd("history last").unwrap_err(), "Invalid count last");
+}
+
+#[test]
+fn legacy_session_history() {
+    let daemon = Daemon::with_setup(FAKE_MPV, &[], |dir| {
+        let song = dir.join("a.mp3");
+        fs::write(&song, b"").unwrap();
+        // The old form without the time, then the current one
+        let session = format!("song {0}\nhistory {0}\nhistory 1500000000 {0}\n", song.display());
+        fs::write(dir.join(".clue_play_session"), session).unwrap();
+    });
+    let song = daemon.dir.join("a.mp3");
+    let mut client = daemon.connect();
+    let history = client.cmd("history").unwrap();
+    assert_eq!(history.len(), 2, "{:?}", history);
+    for line in &history {
+        assert!(line.ends_with(&format!(" {}", song.display())), "{}", line);
+    }
+}
+
+#[test]
+fn list() {
+    let daemon = Daemon::with_args(&["--default-mode", "sequence"]);
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3"), daemon.song("c.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    let song = |idx: usize| format!("song: {}", songs[idx].display());
+    let indexed = |idx: usize| format!("song: {} {}", idx, songs[idx].display());
+    assert_eq!(client.cmd("list").unwrap(), vec![
+        "position: 0".to_owned(),
+        indexed(0),
+        indexed(1),
+        indexed(2),
+    ]);
+
+    client.cmd("position 1").unwrap();
+    assert_eq!(client.cmd("plan 1").unwrap(), vec![song(1)]);
+    assert_eq!(client.cmd("list").unwrap(), vec![
+        "position: 2".to_owned(),
+        indexed(0),
+        indexed(1),
+        indexed(2),
+        format!("queued: {}", songs[1].display()),
+    ]);
+    assert_eq!(client.cmd("list upcoming 5").unwrap(), vec![song(1), song(2)]);
+    assert_eq!(client.cmd("list upcoming 1").unwrap(), vec![song(1)]);
+    client.send("mode circular");
+    let upcoming = vec![song(1), song(2), song(0), song(1)];
+    assert_eq!(client.cmd("list upcoming 5").unwrap(), upcoming);
+    client.send("mode random");
+    assert_eq!(client.cmd("list upcoming 5").unwrap(), vec![song(1)]);
+
+    assert_eq!(client.cmd("list upcoming").unwrap_err(), "Missing count");
+    assert_eq!(client.cmd("list upcoming many").unwrap_err(), "Invalid count many");
+    assert_eq!(client.cmd("list all").unwrap_err(), "Unknown list command all");
+}
+
+#[test]
+fn goto() {
+    let args = ["--default-mode", "circular"];
+    let daemon = Daemon::with_mpv(&format!("{}{}", RECORDING_MPV, MPV_LOOP), &args);
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3"), daemon.song("c.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    let song = |idx: usize| format!("song: {}", songs[idx].display());
+    assert_eq!(client.cmd("goto 1").unwrap(), vec![song(1)]);
+    assert_eq!(daemon.played(1), &songs[1..2]);
+    // Goes on after it
+    client.send("next");
+    assert_eq!(daemon.played(2), &songs[1..]);
+
+    // Only queued in the random modes
+    client.send("mode random");
+    assert_eq!(client.cmd("goto 0").unwrap(), vec![song(0)]);
+    assert_eq!(client.cmd("list upcoming 1").unwrap(), vec![song(0)]);
+
+    assert_eq!(client.cmd("goto 3").unwrap_err(), "Position 3 out of 3 songs");
+    assert_eq!(client.cmd("goto first").unwrap_err(), "Invalid index first");
+    assert_eq!(client.cmd("goto").unwrap_err(), "Missing index");
+}
+
+#[test]
+fn goto_failed() {
+    let args = ["--default-mode", "sequence"];
+    // Nothing to start
+    let daemon = Daemon::with_setup(FAKE_MPV, &args, |dir| {
+        fs::remove_file(dir.join("mpv")).unwrap();
+    });
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3"), daemon.song("c.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    let err = client.cmd("goto 1").unwrap_err();
+    assert!(err.starts_with("Failed to start mpv"), "{}", err);
+    // Nothing left behind to start later
+    assert_eq!(client.cmd("position").unwrap(), vec!["position: 0", "songs: 3"]);
+    let list = client.cmd("list").unwrap();
+    assert!(!list.iter().any(|line| line.starts_with("queued: ")), "{:?}", list);
+    assert_eq!(client.cmd("status").unwrap()[0], "playing: false");
+}
+
+#[test]
+fn remove() {
+    let daemon = Daemon::with_args(&["--default-mode", "sequence"]);
+    let songs = vec![
+        daemon.song("a.mp3"),
+        daemon.song("b.mp3"),
+        daemon.song("c.mp3"),
+        daemon.song("d.mp3"),
+    ];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    let indexed = |idx: usize, song: usize| format!("song: {} {}", idx, songs[song].display());
+    client.cmd("position 2").unwrap();
+    assert_eq!(client.cmd("remove 0").unwrap(), vec!["removed: 1"]);
+    // Still at the same song
+    assert_eq!(client.cmd("position").unwrap(), vec!["position: 1", "songs: 3"]);
+
+    assert_eq!(client.cmd("plan 1").unwrap(), vec![format!("song: {}", songs[2].display())]);
+    let cmd = format!("remove {}", songs[2].display());
+    assert_eq!(client.cmd(&cmd).unwrap(), vec!["removed: 1"]);
+    // Gone from the queue too
+    assert_eq!(client.cmd("list").unwrap(), vec![
+        "position: 1".to_owned(),
+        indexed(0, 1),
+        indexed(1, 3),
+    ]);
+
+    assert_eq!(client.cmd("remove 2").unwrap_err(), "Position 2 out of 2 songs");
+    assert_eq!(client.cmd(&cmd).unwrap_err(), format!("Not loaded: {}", songs[2].display()));
+    assert_eq!(client.cmd("remove").unwrap_err(), "Missing song");
+}
+
+#[test]
+fn total_duration() {
+    let daemon = Daemon::start();
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3"), daemon.song("c.mp3")];
+    fs::write(&songs[0], id3_tag(&[("TLEN", "3600000")])).unwrap();
+    fs::write(&songs[1], id3_tag(&[("TLEN", "185500")])).unwrap();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    assert_eq!(client.cmd("total-duration").unwrap(), vec![
+        "duration: 3785",
+        "length: 1:03:05",
+        "unknown: 1",
+    ]);
+
+    client.load("replace", &songs[1..]);
+    assert_eq!(client.cmd("total-duration").unwrap(), vec![
+        "duration: 185",
+        "length: 0:03:05",
+        "unknown: 1",
+    ]);
+}
+
+#[test]
+fn reindex() {
+    let daemon = Daemon::start();
+    let songs = vec![daemon.song("a.mp3"), daemon.song("b.mp3")];
+    fs::write(&songs[1], id3_tag(&[("TIT2", "Tagged")])).unwrap();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    // The tags were not read yet
+    assert!(client.cmd("find tagged").unwrap().is_empty());
+    assert_eq!(client.cmd("reindex cancel").unwrap_err(), "not reindexing");
+
+    assert_eq!(client.cmd("reindex").unwrap(), vec!["songs: 2"]);
+    let deadline = Instant::now() + TIMEOUT;
+    while client.cmd("find tagged").unwrap().is_empty() {
+        assert!(Instant::now() < deadline, "The tags were not read");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(client.cmd("find tagged").unwrap(), vec![format!("song: 1 {}", songs[1].display())]);
+}
+
+/// State of a process, as in the third field of `/proc/<pid>/stat`.
+fn process_state(pid: &str) -> char {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).unwrap();
+    // The command name in parentheses may contain spaces
+    let after_name = &stat[stat.rfind(')').unwrap() + 2..];
+    after_name.chars().next().unwrap()
+}
+
+#[test]
+fn signal() {
+    let daemon = Daemon::with_mpv(&format!("#!/bin/sh\n{}", MPV_LOOP), &[]);
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    assert_eq!(client.cmd("signal STOP").unwrap_err(), "no child");
+    assert_eq!(client.cmd("signal KILL").unwrap_err(), "Signal KILL not allowed");
+    client.load("", &[song]);
+    client.send("play");
+
+    let deadline = Instant::now() + TIMEOUT;
+    let current = loop {
+        if let Ok(current) = client.cmd("current") {
+            break current;
+        }
+        assert!(Instant::now() < deadline, "The song didn't start");
+        thread::sleep(Duration::from_millis(10));
+    };
+    let pid = current.iter().find(|f| f.starts_with("pid: ")).unwrap()[5..].to_owned();
+
+    // The signals are delivered asynchronously
+    let wait_for = |stopped: bool| {
+        let deadline = Instant::now() + TIMEOUT;
+        while (process_state(&pid) == 'T') != stopped {
+            assert!(Instant::now() < deadline, "Signal not delivered");
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    client.cmd("signal STOP").unwrap();
+    wait_for(true);
+    client.cmd("signal CONT").unwrap();
+    wait_for(false);
+}
+
+#[test]
+fn signal_stopped_mpv() {
+    let mpv = format!("{}{}", RECORDING_MPV, MPV_LOOP);
+    let daemon = Daemon::with_mpv(&mpv, &["--default-mode", "sequence"]);
+    let songs = [daemon.song("a.mp3"), daemon.song("b.mp3")];
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("play");
+    daemon.played(1);
+    let current = client.cmd("current").unwrap();
+    let pid = current.iter().find(|f| f.starts_with("pid: ")).unwrap()[5..].to_owned();
+
+    client.cmd("signal STOP").unwrap();
+    let deadline = Instant::now() + TIMEOUT;
+    while process_state(&pid) != 'T' {
+        assert!(Instant::now() < deadline, "Signal not delivered");
+        thread::sleep(Duration::from_millis(10));
+    }
+    // The stopped mpv doesn't answer anything, but the player doesn't wait for it forever
+    client.send("next");
+    client.cmd("signal CONT").unwrap();
+    assert_eq!(&daemon.played(2)[..2], &songs[..]);
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn gunzip(data: &[u8]) -> Vec<u8> {
+    let mut plain = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut plain).unwrap();
+    plain
+}
+
+#[test]
+fn session_snapshot() {
+    let daemon = Daemon::with_args(&["--save-interval", "1"]);
+    let songs = (0..3).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    client.cmd("position 2").unwrap();
+
+    let file = daemon.dir.join(".clue_play_session");
+    let deadline = Instant::now() + TIMEOUT;
+    let snapshot = loop {
+        let snapshot = fs::read_to_string(&file).unwrap_or_default();
+        if snapshot.contains("position 2") {
+            break snapshot;
+        }
+        assert!(Instant::now() < deadline, "No snapshot stored");
+        thread::sleep(Duration::from_millis(100));
+    };
+    assert!(snapshot.starts_with("mode sequence\n"), "{}", snapshot);
+
+    // As if the daemon crashed and started again
+    let args = ["--save-interval", "60"];
+    let restored = Daemon::with_setup(FAKE_MPV, &args, |dir| {
+        fs::write(dir.join(".clue_play_session"), &snapshot).unwrap();
+    });
+    let mut client = restored.connect();
+    assert_eq!(client.cmd("position").unwrap(), vec!["position: 2", "songs: 3"]);
+    assert_eq!(client.cmd("find 1.mp3").unwrap(), vec![format!("song: 1 {}", songs[1].display())]);
+}
+
+#[test]
+fn session_on_terminate() {
+    // Stored even without the periodic snapshots
+    let args = ["--terminate-fade", "0"];
+    let mut daemon = Daemon::with_mpv(&format!("#!/bin/sh\n{}", MPV_LOOP), &args);
+    let songs = (0..3).map(|i| daemon.song(&format!("{}.mp3", i))).collect::<Vec<_>>();
+    let mut client = daemon.connect();
+    client.load("", &songs);
+    client.send("mode sequence");
+    client.send("play");
+    let deadline = Instant::now() + TIMEOUT;
+    while client.cmd("current").is_err() {
+        assert!(Instant::now() < deadline, "The song didn't start");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    client.send("terminate");
+    let status = loop {
+        if let Some(status) = daemon.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "The daemon didn't terminate");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(status.success());
+    let snapshot = fs::read_to_string(daemon.dir.join(".clue_play_session")).unwrap();
+    let history = snapshot.lines().filter(|line| line.starts_with("history ")).collect::<Vec<_>>();
+    assert_eq!(history.len(), 1, "{}", snapshot);
+    assert!(history[0].ends_with(&format!(" {}", songs[0].display())), "{}", snapshot);
+
+    let restored = Daemon::with_setup(FAKE_MPV, &args, |dir| {
+        fs::write(dir.join(".clue_play_session"), &snapshot).unwrap();
+    });
+    let mut client = restored.connect();
+    assert_eq!(client.cmd("status").unwrap(), vec![
+        "playing: false",
+        "mode: sequence",
+        "repeat: 1",
+        "songs: 3",
+        "position: 1",
+        "queued: 0",
+        "history: 1",
+    ]);
+}
+
+#[test]
+fn default_mode() {
+    let daemon = Daemon::with_args(&["--default-mode", "sequence"]);
+    let mut client = daemon.connect();
+    // The query answers even in the text format, unlike setting the mode
+    assert_eq!(client.cmd("mode").unwrap(), vec!["mode: sequence"]);
+    client.json("json");
+    assert_eq!(client.json("mode")["data"]["mode"], "sequence");
+
+    // The restored session wins, unless forced
+    for &(force, expected) in &[(false, "circular"), (true, "sequence")] {
+        let mut args = vec!["--default-mode", "sequence", "--save-interval", "60"];
+        if force {
+            args.push("--force-default-mode");
+        }
+        let restored = Daemon::with_setup(FAKE_MPV, &args, |dir| {
+            fs::write(dir.join(".clue_play_session"), "mode circular\nposition 0\n").unwrap();
+        });
+        let mut client = restored.connect();
+        client.json("json");
+        assert_eq!(client.json("mode")["data"]["mode"], expected);
+    }
+}
+
+#[test]
+fn path_remap() {
+    let args = ["--save-interval", "60", "--path-remap", "/old/music=moved"];
+    let daemon = Daemon::with_setup(FAKE_MPV, &args, |dir| {
+        fs::create_dir_all(dir.join("moved")).unwrap();
+        for song in &["a.mp3", "c.mp3"] {
+            fs::write(dir.join("moved").join(song), b"").unwrap();
+        }
+        // b.mp3 got lost in the move
+        let snapshot = "mode sequence\nposition 2\nsource /old/music\n\
+                        song /old/music/a.mp3\nsong /old/music/b.mp3\nsong /old/music/c.mp3\n";
+        fs::write(dir.join(".clue_play_session"), snapshot).unwrap();
+    });
+    let mut client = daemon.connect();
+    assert_eq!(client.cmd("position").unwrap(), vec!["position: 1", "songs: 2"]);
+    assert_eq!(client.cmd("find c.mp3").unwrap(), vec!["song: 1 moved/c.mp3"]);
+}
+
+#[test]
+fn compressed_state() {
+    for &compress in &[false, true] {
+        let args: &[&str] = if compress { &["--compress-state"] } else { &[] };
+        let daemon = Daemon::with_args(args);
+        let bookmarks = daemon.dir.join(".clue_play_bookmarks");
+        // Written in the other format, it must be readable anyway. The player (and the
+        // bookmarks) get loaded with the first command.
+        let stored = b"10 /a.mp3\n20 /b.mp3\n";
+        let stored = if compress { stored.to_vec() } else { gzip(stored) };
+        fs::write(&bookmarks, stored).unwrap();
+
+        let mut client = daemon.connect();
+        client.cmd("bookmark clear /a.mp3").unwrap();
+        let data = fs::read(&bookmarks).unwrap();
+        assert_eq!(data.starts_with(&[0x1f, 0x8b]), compress);
+        let data = if compress { gunzip(&data) } else { data };
+        assert_eq!(data, b"20 /b.mp3\n");
+    }
+}
+
+#[test]
+fn bookmark_clear_expands() {
+    let daemon = Daemon::start();
+    let bookmarks = daemon.dir.join(".clue_play_bookmarks");
+    fs::write(&bookmarks, format!("10 {}/book.mp3\n20 /b.mp3\n", daemon.dir.display())).unwrap();
+    let mut client = daemon.connect();
+    // The same path as when loading it, the home is the temporary directory
+    client.cmd("bookmark clear ~/book.mp3").unwrap();
+    assert_eq!(fs::read(&bookmarks).unwrap(), b"20 /b.mp3\n");
+}
+
+#[test]
+fn throttling() {
+    let daemon = Daemon::with_args(&["--max-commands-per-sec", "5"]);
+    let mut client = daemon.connect();
+    let start = Instant::now();
+    for _ in 0..15 {
+        client.send("version");
+    }
+    for _ in 0..15 {
+        client.response().unwrap();
+    }
+    // The first 5 go right away, the others wait for the next two seconds
+    assert!(start.elapsed() >= Duration::from_millis(1500), "Not throttled");
+
+    // Another client is not affected
+    let start = Instant::now();
+    daemon.connect().cmd("version").unwrap();
+    assert!(start.elapsed() < Duration::from_millis(500));
+}
+
+#[test]
+fn log_rotation() {
+    let args = ["--log-file", "log", "--log-max-size", "300", "--log-keep", "2"];
+    let daemon = Daemon::with_args(&args);
+    let mut client = daemon.connect();
+    // Each unknown command gets logged as an error
+    for i in 0..50 {
+        client.send(&format!("unknown-{}", i));
+    }
+    client.cmd("version").unwrap();
+
+    let log = fs::read_to_string(daemon.dir.join("log")).unwrap();
+    assert!(log.len() <= 300, "Too big log file {}", log.len());
+    assert!(log.contains("Unknown command unknown-49"), "{}", log);
+    assert!(daemon.dir.join("log.1").exists());
+    assert!(daemon.dir.join("log.2").exists());
+    assert!(!daemon.dir.join("log.3").exists());
+}
+
+#[test]
+fn audit() {
+    let args = ["--audit", "--log-file", "log"];
+    let daemon = Daemon::with_env(FAKE_MPV, &args, &[("RUST_LOG", "audit=info")], |_| ());
+    let mut client = daemon.connect();
+    client.cmd("version").unwrap();
+    client.cmd("history-trim x").unwrap_err();
+
+    let log = fs::read_to_string(daemon.dir.join("log")).unwrap();
+    let lines = log.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2, "{}", log);
+    assert!(lines[0].ends_with(" INFO  audit: #0 unix: version -> ok"), "{}", log);
+    assert!(lines[1].ends_with(" history-trim x -> error: Invalid count x"), "{}", log);
+}
+
+#[test]
+fn json() {
+    let daemon = Daemon::start();
+    let mut client = daemon.connect();
+    assert_eq!(client.json("json")["status"], "ok");
+    let version = client.json("version");
+    assert_eq!(version["status"], "ok");
+    assert_eq!(version["data"]["version"], env!("CARGO_PKG_VERSION"));
+    // Even the originally quiet commands answer
+    let unknown = client.json("hello");
+    assert_eq!(unknown["status"], "error");
+    assert_eq!(unknown["error"], "Unknown command hello");
+    assert_eq!(client.json("stop")["status"], "ok");
+}
+
+#[test]
+fn every_command_answers() {
+    let daemon = Daemon::start();
+    let song = daemon.song("song.mp3");
+    let missing = daemon.dir.join("missing.mp3");
+    let mut client = daemon.connect_answering();
+    assert_eq!(client.cmd("hello").unwrap_err(), "Unknown command hello");
+    assert_eq!(client.cmd("mode bogus").unwrap_err(), "Unknown mode bogus");
+    client.load("", &[missing]);
+    assert_eq!(client.response().unwrap_err(), "no valid songs");
+    client.cmd("mode sequence").unwrap();
+    client.load("", &[song]);
+    client.response().unwrap();
+    client.cmd("stop").unwrap();
+    // Nothing left over from the answers above
+    assert!(client.cmd("version").unwrap()[0].starts_with("version: "));
+}
+
+#[test]
+fn quiet() {
+    let daemon = Daemon::start();
+    let missing = daemon.dir.join("missing.mp3");
+    let mut client = daemon.connect_answering();
+    client.cmd("quiet").unwrap();
+    // The errors of the original commands are not sent, the first answer is of the version
+    client.send("hello");
+    client.send("mode bogus");
+    client.load("", &[missing]);
+    assert!(client.cmd("version").unwrap()[0].starts_with("version: "));
+    // Still answers when asked for the mode
+    assert_eq!(client.cmd("mode").unwrap(), vec!["mode: random"]);
+}
+
+#[test]
+fn command_fifo() {
+    let daemon = Daemon::with_args(&["--pause-trigger-fifo", "fifo"]);
+    let song = daemon.song("song.mp3");
+    let fifo = daemon.dir.join("fifo");
+    let deadline = Instant::now() + TIMEOUT;
+    while !fifo.exists() {
+        assert!(Instant::now() < deadline, "The daemon didn't create the fifo");
+        thread::sleep(Duration::from_millis(10));
+    }
+    // Opening for write blocks until the daemon opens it for reading
+    fs::write(&fifo, format!("load\n{}\n\npause\n", song.display())).unwrap();
+    let mut client = daemon.connect();
+    let deadline = Instant::now() + TIMEOUT;
+    while client.cmd("find song").unwrap().is_empty() {
+        assert!(Instant::now() < deadline, "Commands from the fifo weren't executed");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn terminate_now() {
+    // Logs what it's asked to do
+    let mpv = format!(
+        "{}{}",
+        RECORDING_MPV,
+        MPV_LOOP.replace("do\n", "do\n    echo \"$line\" >> \"$(dirname \"$0\")/requests\"\n"),
+    );
+    let mut daemon = Daemon::with_mpv(&mpv, &["--terminate-fade", "60000"]);
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    client.load("", &[song]);
+    client.send("play");
+    daemon.played(1);
+
+    client.send("terminate now");
+    let deadline = Instant::now() + TIMEOUT;
+    let status = loop {
+        if let Some(status) = daemon.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "The daemon didn't terminate");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(status.success());
+    assert!(!daemon.socket.exists());
+    let requests = fs::read_to_string(daemon.dir.join("requests")).unwrap_or_default();
+    assert!(!requests.contains("volume"), "Faded out: {}", requests);
+}
+
+#[test]
+fn terminate_on_signals() {
+    // Answers, but ignores the quit command
+    let mpv_loop = MPV_LOOP.replace("        *'[\"quit\"]'*) exit 0 ;;\n", "");
+    let mut daemon = Daemon::with_mpv(&format!("{}{}", RECORDING_MPV, mpv_loop), &[]);
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    client.load("", &[song]);
+    client.send("play");
+    daemon.played(1);
+    let current = client.cmd("current").unwrap();
+    let pid = current.iter().find(|f| f.starts_with("pid: ")).unwrap()[5..].to_owned();
+
+    let daemon_pid = Pid::from_raw(daemon.child.id() as i32);
+    signal::kill(daemon_pid, Signal::SIGTERM).unwrap();
+    let deadline = Instant::now() + TIMEOUT;
+    // Waiting for mpv to exit, refusing commands meanwhile
+    while client.cmd("position 0").is_ok() {
+        assert!(Instant::now() < deadline, "Not terminating");
+        thread::sleep(Duration::from_millis(10));
+    }
+    // Doesn't want to wait any more
+    signal::kill(daemon_pid, Signal::SIGINT).unwrap();
+    let status = loop {
+        if let Some(status) = daemon.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "The daemon didn't terminate");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(status.success());
+    assert!(!daemon.socket.exists());
+    // Doesn't play on its own
+    assert!(!Path::new(&format!("/proc/{}", pid)).exists());
+}
+
+#[test]
+fn terminate_refuses_commands() {
+    // Ignores the quit command, so the daemon has to wait a while for it
+    let mut daemon = Daemon::start();
+    let song = daemon.song("song.mp3");
+    let mut client = daemon.connect();
+    client.load("", slice::from_ref(&song));
+    client.send("play");
+    let deadline = Instant::now() + TIMEOUT;
+    // The status doesn't ask the (silent) mpv anything, it has the paused state once it runs
+    let started = |client: &mut Client| {
+        let status = client.cmd("status").unwrap();
+        status.iter().any(|field| field.starts_with("paused: "))
+    };
+    while !started(&mut client) {
+        assert!(Instant::now() < deadline, "Not playing");
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    client.send("terminate");
+    let mut other = daemon.connect();
+    // Once the terminate gets to the player (there are no filters, so nothing changes)
+    while other.cmd("filter clear").is_ok() {
+        assert!(Instant::now() < deadline, "Not terminating");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(other.cmd("position 0").unwrap_err(), "shutting down");
+    let play_now = format!("play-now\n{}\n", song.display());
+    assert_eq!(other.cmd(&play_now).unwrap_err(), "shutting down");
+    assert_eq!(other.cmd("terminate").unwrap_err(), "shutting down");
+    // Nothing changed, only the playback is stopping
+    assert_eq!(other.cmd("position").unwrap(), vec!["position: 1", "songs: 1"]);
+
+    let status = loop {
+        if let Some(status) = daemon.child.try_wait().unwrap() {
+            break status;
+        }
+        assert!(Instant::now() < deadline, "The daemon didn't terminate");
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert!(status.success());
+}
+
+#[test]
+fn idle_timeout() {
+    let daemon = Daemon::with_args(&["--conn-idle-timeout", "1"]);
+    let mut idle = daemon.connect();
+    let mut active = daemon.connect();
+    let start = Instant::now();
+    for _ in 0..4 {
+        thread::sleep(Duration::from_millis(400));
+        assert!(active.cmd("version").is_ok());
+    }
+    assert!(idle.closed());
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    // Commands keep the connection alive
+    assert!(active.cmd("version").is_ok());
+}
+
+#[test]
+fn max_line_length() {
+    let daemon = Daemon::with_args(&["--max-line-length", "1000"]);
+    let mut client = daemon.connect();
+    assert!(client.cmd("version").is_ok());
+    // The daemon may close the connection before getting all of it
+    let _ = client.writer.write_all(&vec![b'x'; 100_000]);
+    assert_eq!(client.response().unwrap_err(), "line too long");
+    assert!(client.closed());
+    // Others are fine
+    assert!(daemon.connect().cmd("version").is_ok());
+}
+
+#[test]
+fn quit() {
+    let daemon = Daemon::start();
+    let mut client = daemon.connect();
+    client.send("quit");
+    assert!(client.closed());
+    // The daemon itself goes on
+    assert!(daemon.connect().cmd("version").is_ok());
+}